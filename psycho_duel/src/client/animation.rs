@@ -5,7 +5,13 @@ use bevy::{
 use leafwing_input_manager::prelude::ActionState;
 use lightyear::prelude::client::Predicted;
 
-use super::{load_assets::GltfCollection, protocol::PlayerActions, ClientAppState};
+use super::{
+    catalog::CoreItemCatalog,
+    combat::swing_weapon,
+    load_assets::GltfCollection,
+    protocol::{MeleeState, PlayerActions, PlayerVisuals},
+    ClientAppState, FIXED_TIMESTEP_HZ,
+};
 
 /// Plugin responsible for animation on client
 pub struct ClientAnimationPlugin;
@@ -79,45 +85,76 @@ fn add_animation_components(
     }
 }
 
-/// Queries predicted entities with the component player action as this occcurs plays the animation according to the received type
+/// Queries predicted entities with the component player action as this occcurs plays the animation according to the received type.
+/// Mid swing the swing clip wins, kept on the predicted melee tick as server samples hits from that same clip on that same tick
 fn movement_animations(
     mut action_state: Query<
         (
             &ActionState<PlayerActions>,
+            &MeleeState,
+            &PlayerVisuals,
             &mut AnimationTransitions,
             &mut AnimationPlayer,
         ),
         With<Predicted>,
     >,
     animations: Res<Animations>,
+    catalog: Res<CoreItemCatalog>,
 ) {
-    for (action, mut animation_transitions, mut animation_player) in action_state.iter_mut() {
-        let (new_animation, transition_duration) = if action.pressed(&PlayerActions::Forward) {
+    for (action, melee, visuals, mut animation_transitions, mut animation_player) in
+        action_state.iter_mut()
+    {
+        if let (Some(attack), Some(weapon)) = (melee.attack, swing_weapon(visuals, &catalog)) {
+            let clip = weapon.profile.clip(attack, weapon.off_hand);
+            let Some(swing_animation) = animations.named_node.get(&clip) else {
+                warn!("Skeleton has no swing clip named {}", clip);
+                continue;
+            };
+            if !animation_player.is_playing_animation(*swing_animation) {
+                animation_transitions.play(
+                    &mut animation_player,
+                    *swing_animation,
+                    Duration::from_millis(50),
+                );
+            }
+            // Frames run faster than ticks, only pull him back once he drifts a whole tick away
+            let tick_time = melee.tick as f32 / FIXED_TIMESTEP_HZ as f32;
+            if let Some(active) = animation_player.animation_mut(*swing_animation) {
+                if (active.seek_time() - tick_time).abs() > 1.0 / FIXED_TIMESTEP_HZ as f32 {
+                    active.seek_to(tick_time);
+                }
+            }
+            continue;
+        }
+
+        // Whichever axis is pushed the most picks the walk, forward and back win ties
+        let movement = action.axis_pair(&PlayerActions::Move);
+        let (new_animation, transition_duration) = if movement == Vec2::ZERO {
+            // Fallback to idle animation
+            (
+                animations.named_node.get("IDLE_BEGIN").unwrap(),
+                Duration::from_millis(200),
+            )
+        } else if movement.y.abs() >= movement.x.abs() && movement.y > 0.0 {
             (
                 animations.named_node.get("KNEELESS_FRONT_WALK").unwrap(),
                 Duration::from_millis(150),
             )
-        } else if action.pressed(&PlayerActions::Backward) {
+        } else if movement.y.abs() >= movement.x.abs() {
             (
                 animations.named_node.get("KNEELESS_BACK_WALK").unwrap(),
                 Duration::from_millis(150),
             )
-        } else if action.pressed(&PlayerActions::Left) {
+        } else if movement.x < 0.0 {
             (
                 animations.named_node.get("KNEELESS_LEFT_WALK").unwrap(),
                 Duration::from_millis(150),
             )
-        } else if action.pressed(&PlayerActions::Right) {
+        } else {
             (
                 animations.named_node.get("KNEELESS_RIGHT_WALK").unwrap(),
                 Duration::from_millis(150),
             )
-        } else {
-            // Fallback to idle animation
-            (
-                animations.named_node.get("IDLE_BEGIN").unwrap(),
-                Duration::from_millis(200),
-            )
         };
 
         // If the animation differs from the previous one
@@ -0,0 +1,135 @@
+use crate::shared::config::CoreConfig;
+use crate::shared::protocol::{login_codec, LoginRequest, LoginResponse, MAX_LOGIN_MESSAGE_BYTES};
+use bevy::prelude::*;
+use bevy::tasks::{block_on, poll_once, IoTaskPool, Task};
+use bevy::utils::Duration;
+use bincode::Options;
+use lightyear::connection::netcode::ConnectToken;
+use lightyear::prelude::client::*;
+use std::io::Read;
+use std::net::{SocketAddr, TcpStream};
+
+use super::ClientAppState;
+
+/// Plugin responsible for logging our client in, he asks our token service for a connect token and then connects to server with it
+pub struct ClientAuthPlugin;
+
+/// Username and password passed via cli, we need to keep them around as every reconnect requires a fresh token
+#[derive(Resource, Clone)]
+pub struct ClientCredentials {
+    pub username: String,
+    pub password: String,
+    /// Asked via cli, creates the account on our first login. Once it succeeds we go back to plain logins
+    pub register: bool,
+}
+
+/// Trigger it whenever you want to login and connect to server
+#[derive(Event)]
+pub struct LoginEvent;
+
+/// Login request currently running on the io task pool, polled every frame so our window never freezes waiting for the token service
+#[derive(Resource, Default)]
+pub struct PendingLogin {
+    task: Option<Task<Result<ConnectToken, String>>>,
+}
+
+impl Plugin for ClientAuthPlugin {
+    fn build(&self, app: &mut App) {
+        // Pre initialized, empty until someone asks for a login
+        app.init_resource::<PendingLogin>();
+
+        // We only connect to the server when we have our assets loaded
+        app.add_systems(OnEnter(ClientAppState::Game), trigger_login);
+
+        // Observer because login can be asked from anywhere, example: manage connections egui
+        app.add_observer(start_login);
+
+        // Update because we just check if our token arrived, once per frame is plenty
+        app.add_systems(Update, connect_with_token);
+    }
+}
+
+/// Simple system that asks for login as soon as we enter game
+fn trigger_login(mut commands: Commands) {
+    commands.trigger(LoginEvent);
+}
+
+/// Asks our token service for a connect token in a background task, ignored if a login is already happening
+fn start_login(
+    _trigger: Trigger<LoginEvent>,
+    credentials: Res<ClientCredentials>,
+    core_config: Res<CoreConfig>,
+    mut pending: ResMut<PendingLogin>,
+) {
+    if pending.task.is_some() {
+        warn!("Already logging in, wait for the token service to answer");
+        return;
+    }
+    let request = LoginRequest {
+        username: credentials.username.clone(),
+        password: credentials.password.clone(),
+        register: credentials.register,
+    };
+    let auth_addr = core_config.auth.auth_addr();
+    let task = IoTaskPool::get().spawn(async move { request_connect_token(auth_addr, &request) });
+    pending.task = Some(task);
+}
+
+/// Once our login task finishes, inserts the token into lightyear client config and then connects
+fn connect_with_token(
+    mut pending: ResMut<PendingLogin>,
+    mut credentials: ResMut<ClientCredentials>,
+    mut client_config: ResMut<ClientConfig>,
+    mut commands: Commands,
+) {
+    let Some(task) = pending.task.as_mut() else {
+        return;
+    };
+    let Some(result) = block_on(poll_once(task)) else {
+        return;
+    };
+    pending.task = None;
+
+    match result {
+        Ok(token) => {
+            info!("Received connect token for user {}", credentials.username);
+            // Account exists now, reconnects must login instead of registering him again
+            credentials.register = false;
+            if let NetConfig::Netcode { auth, .. } = &mut client_config.net {
+                *auth = Authentication::Token(token);
+            }
+            commands.connect_client();
+        }
+        Err(err) => {
+            error!("Login failed for user {}: {}", credentials.username, err);
+        }
+    }
+}
+
+/// Callable function - Blocking tcp request to our token service, only call it from a background task
+fn request_connect_token(
+    auth_addr: SocketAddr,
+    request: &LoginRequest,
+) -> Result<ConnectToken, String> {
+    let mut stream = TcpStream::connect_timeout(&auth_addr, Duration::from_secs(3))
+        .map_err(|err| format!("Couldnt reach token service at {} {}", auth_addr, err))?;
+    stream
+        .set_read_timeout(Some(Duration::from_secs(3)))
+        .and_then(|_| stream.set_write_timeout(Some(Duration::from_secs(3))))
+        .map_err(|err| err.to_string())?;
+
+    login_codec()
+        .serialize_into(&mut stream, request)
+        .map_err(|err| err.to_string())?;
+
+    // Same limit as our token service, a broken or hostile one cant make us allocate whatever it claims
+    let response = login_codec()
+        .deserialize_from::<_, LoginResponse>(stream.take(MAX_LOGIN_MESSAGE_BYTES))
+        .map_err(|err| err.to_string())?;
+    match response {
+        LoginResponse::Token(bytes) => {
+            ConnectToken::try_from_bytes(&bytes).map_err(|err| format!("{:?}", err))
+        }
+        LoginResponse::Refused(reason) => Err(reason),
+    }
+}
@@ -1,6 +1,8 @@
-use super::protocol::PlayerMarker;
+use super::protocol::{PlayerActions, PlayerMarker};
 use bevy::prelude::*;
 use bevy_panorbit_camera::{PanOrbitCamera, PanOrbitCameraPlugin};
+use leafwing_input_manager::plugin::InputManagerSystem;
+use leafwing_input_manager::prelude::*;
 use lightyear::prelude::client::Predicted;
 use lightyear::shared::replication::components::Controlled;
 
@@ -44,6 +46,15 @@ impl Plugin for ClientCameraPlugin {
                 .run_if(rc_follow_player),
         );
 
+        // Update like the rest of our camera controls
+        app.add_systems(Update, orbit_with_stick);
+
+        // Manual control runs after leafwing reads the input map and before lightyear buffers the input for this tick
+        app.add_systems(
+            PreUpdate,
+            write_camera_look.in_set(InputManagerSystem::ManualControl),
+        );
+
         // Debug register
         app.register_type::<CamFeatures>();
         app.register_type::<PanOrbitCamera>();
@@ -115,3 +126,50 @@ fn cam_follow_player(
         }
     }
 }
+
+/// How fast the right stick orbits our camera, in radians per second at full push
+const STICK_ORBIT_SPEED: f32 = 2.5;
+
+/// Right stick orbits our camera, pan orbit only listens to the mouse
+fn orbit_with_stick(
+    time: Res<Time>,
+    mut pan_q: Query<&mut PanOrbitCamera, With<MarkerPrimaryCamera>>,
+    player_q: Query<
+        &ActionState<PlayerActions>,
+        (With<PlayerMarker>, With<Predicted>, With<Controlled>),
+    >,
+) {
+    let Ok(mut pan_cam) = pan_q.get_single_mut() else {
+        return;
+    };
+    let Ok(action_state) = player_q.get_single() else {
+        return;
+    };
+    let stick = action_state.axis_pair(&PlayerActions::Look);
+    if stick != Vec2::ZERO {
+        let orbit = stick * STICK_ORBIT_SPEED * time.delta_secs();
+        pan_cam.target_yaw -= orbit.x;
+        pan_cam.target_pitch -= orbit.y;
+    }
+}
+
+/// Writes where our camera looks into the camera look action of our player, the only place yaw enters our input.
+/// That is what shared movement uses to know where forward is. Yaw of zero means looking down +z, which is where our camera spawns looking
+fn write_camera_look(
+    cam_q: Query<&Transform, With<MarkerPrimaryCamera>>,
+    mut player_q: Query<
+        &mut ActionState<PlayerActions>,
+        (With<PlayerMarker>, With<Predicted>, With<Controlled>),
+    >,
+) {
+    let Ok(cam_transform) = cam_q.get_single() else {
+        return;
+    };
+    let Ok(mut action_state) = player_q.get_single_mut() else {
+        return;
+    };
+    let forward = cam_transform.forward();
+    let yaw = forward.x.atan2(forward.z);
+    let pitch = forward.y.clamp(-1.0, 1.0).asin();
+    action_state.set_axis_pair(&PlayerActions::CameraLook, Vec2::new(yaw, pitch));
+}
@@ -1,11 +1,14 @@
+use std::collections::VecDeque;
 use std::ops::DerefMut;
 
-use super::load_assets::GltfCollection;
 use super::protocol::*;
 use super::CommonChannel;
 
-use crate::client::{ClientAppState, CoreEasyClient};
-use crate::shared::protocol::Currency;
+use crate::client::auth::LoginEvent;
+use crate::client::keybinds::{CoreKeybinds, KeybindCapture, KeybindSlot, BINDINGS_PER_SLOT};
+use crate::client::{ClientAppState, ClientCoreInformation, CoreEasyClient};
+use crate::shared::catalog::{CoreItemCatalog, ItemSlot};
+use bevy::utils::HashMap;
 use bevy::{diagnostic::DiagnosticsStore, prelude::*, window::PrimaryWindow};
 use bevy_egui::{egui, EguiContext};
 use client::ClientCommands;
@@ -13,31 +16,55 @@ use client::NetworkingState;
 use client::Predicted;
 use lightyear::client::prediction::diagnostics::PredictionDiagnosticsPlugin;
 use lightyear::prelude::*;
+use lightyear::shared::events::components::MessageEvent;
 use lightyear::shared::replication::components::Controlled;
+use uuid::Uuid;
 
 /// Client focused egui
 pub struct ClientEguiPlugin;
 
 /// Gives me the current parts in ui we are able to customize,utilized in combo box button
 /// Locals - Variables that are unique to a system, and only have a reference to that system.
-#[derive(PartialEq, Debug, Default, Serialize, Deserialize, Clone)]
+#[derive(PartialEq, Eq, Hash, Debug, Default, Serialize, Deserialize, Clone, Reflect)]
 pub enum Parts {
     #[default]
     Head,
     Torso,
     Leg,
     Arm,
+    /// Right hand weapon slot
+    MainHand,
+    /// Left hand weapon slot
+    OffHand,
 }
 
-/// Static string references =  dont expect this variable to change mid system running
-/// Technically this are the paths to all available items, just increase this guy to adjust ui button
-const HEAD_PATHS: [&'static str; 1] = ["characters/visual_parts/def_m_head.glb"];
-
-const TORSO_PATHS: [&'static str; 1] = ["characters/visual_parts/def_m_torso.glb"];
-
-const LEG_PATHS: [&'static str; 1] = ["characters/visual_parts/def_m_legs.glb"];
+impl Parts {
+    /// Every part in the order our customizer shows them
+    pub const ALL: [Parts; 6] = [
+        Parts::Head,
+        Parts::Torso,
+        Parts::Leg,
+        Parts::Arm,
+        Parts::MainHand,
+        Parts::OffHand,
+    ];
+
+    /// Weapon slots hold weapons and go in hand bones, the rest are body parts that go in the player root
+    pub fn is_weapon(&self) -> bool {
+        matches!(self, Parts::MainHand | Parts::OffHand)
+    }
 
-const ARM_PATHS: [&'static str; 1] = ["characters/visual_parts/def_m_arms.glb"];
+    /// Catalog slot of the items that fit in this part, both hands take any weapon
+    pub fn slot(&self) -> ItemSlot {
+        match self {
+            Parts::Head => ItemSlot::Head,
+            Parts::Torso => ItemSlot::Torso,
+            Parts::Leg => ItemSlot::Leg,
+            Parts::Arm => ItemSlot::Arm,
+            Parts::MainHand | Parts::OffHand => ItemSlot::Weapon,
+        }
+    }
+}
 
 /// Carrier of information usefull for our char customizer
 #[derive(Event, Serialize, Deserialize, Debug, Clone, PartialEq)]
@@ -62,7 +89,11 @@ impl Plugin for ClientEguiPlugin {
                 inspector_ui,
                 char_customizer_ui,
                 currency_ui,
+                combat_ui,
                 store_ui,
+                trade_ui,
+                loadout_ui,
+                keybind_ui,
                 manage_connection_ui,
                 client_specific_diagnostics_ui,
             ),
@@ -114,19 +145,24 @@ fn inspector_ui(world: &mut World) {
                 ui.allocate_space(ui.available_size());
             });
         });
-    } else {
-        return;
-    };
+    }
 }
 
 /// A developer egui utilized to limit test our game character customizer
+/// Lists every part and only the catalog items we own that fit in it, new cosmetics show up once they are in our catalog
 fn char_customizer_ui(
     mut contexts: bevy_egui::EguiContexts,
     local_player: Query<&PlayerId, (With<Predicted>, With<Controlled>, With<PlayerMarker>)>,
+    opt_core: Option<Res<ClientCoreInformation>>,
+    catalog: Res<CoreItemCatalog>,
     mut selected_button: Local<Parts>,
     mut commands: Commands,
 ) {
-    // Only should appear if replication ocurred
+    // Only should appear if replication ocurred and server sent our inventory
+    let Some(core) = opt_core else {
+        return;
+    };
+    let player_inv = &core.core.inventory;
     if let Ok(player_id) = local_player.get_single() {
         // Egui context
         if let Some(egui_context) = contexts.try_ctx_mut() {
@@ -142,25 +178,23 @@ fn char_customizer_ui(
                         egui::ComboBox::from_label("")
                             .selected_text(format!("{:?}", selected_button))
                             .show_ui(ui, |ui| {
-                                ui.selectable_value(selected_button, Parts::Head, "Head");
-                                ui.selectable_value(selected_button, Parts::Torso, "Torso");
-                                ui.selectable_value(selected_button, Parts::Leg, "Leg");
+                                for part in Parts::ALL {
+                                    let label = format!("{:?}", part);
+                                    ui.selectable_value(selected_button, part, label);
+                                }
                             });
 
-                        ui.label("Available parts");
-                        // Matching pattern to grab pre defined const paths
-                        let paths = match selected_button {
-                            Parts::Head => &HEAD_PATHS,
-                            Parts::Torso => &TORSO_PATHS,
-                            Parts::Leg => &LEG_PATHS,
-                            Parts::Arm => &ARM_PATHS,
-                        };
-
-                        // Here we intake ref because we dont wannt to consume file path const
-                        let items: Vec<Item> = paths
-                            .iter()
-                            .map(|&path| Item::new_from_filepath(path))
+                        ui.label("Owned parts");
+                        // Previews only, they have no instance id. Server equips the instance we own
+                        let items: Vec<Item> = catalog
+                            .of_slot(selected_button.slot())
+                            .into_iter()
+                            .filter(|definition| player_inv.count_of(&definition.id) > 0)
+                            .map(Item::from_definition)
                             .collect();
+                        if items.is_empty() {
+                            ui.label("You dont own anything for this part");
+                        }
 
                         // For each item, we make a button  capable of sending an event with it is given file_path
                         for item in items.iter() {
@@ -198,190 +232,582 @@ fn send_trigger_event(
     );
 }
 
-/// Egui responsible to test features gaining currency, losing currency
-fn currency_ui(
+/// Egui to save, wear and delete outfits. Loadouts come from our core information, so they only show what server confirmed
+fn loadout_ui(
     mut contexts: bevy_egui::EguiContexts,
-    mut player_q: Query<(&PlayerId, &mut Currency), (With<Predicted>, With<Controlled>)>,
+    opt_core: Option<Res<ClientCoreInformation>>,
+    mut loadout_results: EventReader<MessageEvent<LoadoutResult>>,
+    mut new_name: Local<String>,
+    mut last_result: Local<String>,
     mut connection_manager: ResMut<ClientConnectionManager>,
 ) {
-    // Only should appear if replication already ocurred
-    // It is okay we can mutate locally, nonetheless server will override it via replication if not okaied validation
-    if let Ok((player_id, mut current_currency)) = player_q.get_single_mut() {
+    // Tell the player how his last loadout request went
+    for event in loadout_results.read() {
+        *last_result = match event.message() {
+            LoadoutResult::Saved(name) => format!("Saved loadout {}", name),
+            LoadoutResult::Applied(name) => format!("Wearing loadout {}", name),
+            LoadoutResult::Deleted(name) => format!("Deleted loadout {}", name),
+            LoadoutResult::Rejected(err) => format!("Server refused: {}", err),
+        };
+        info!("{}", *last_result);
+    }
+
+    // Only show loadouts once server sent our core
+    let Some(core) = opt_core else {
+        return;
+    };
+    let Some(egui_context) = contexts.try_ctx_mut() else {
+        return;
+    };
+    let mut requests = Vec::new();
+
+    egui::Window::new("Loadouts")
+        .default_open(false)
+        .default_pos((250.0, 300.0))
+        .show(egui_context, |ui| {
+            ui.label(&*last_result);
+            ui.label(format!(
+                "Saved {}/{}",
+                core.core.loadouts.saved.len(),
+                MAX_LOADOUTS
+            ));
+
+            // Saves what we are currently wearing
+            ui.horizontal(|ui| {
+                ui.add(
+                    egui::TextEdit::singleline(new_name.deref_mut())
+                        .char_limit(MAX_LOADOUT_NAME_LEN)
+                        .hint_text("Loadout name"),
+                );
+                if ui.button("Save current").clicked() {
+                    requests.push(LoadoutRequest::Save {
+                        name: new_name.clone(),
+                    });
+                }
+            });
+
+            for loadout in core.core.loadouts.saved.iter() {
+                ui.horizontal(|ui| {
+                    ui.label(&loadout.name);
+                    if ui.button("Wear").clicked() {
+                        requests.push(LoadoutRequest::Apply {
+                            name: loadout.name.clone(),
+                        });
+                    }
+                    if ui.button("Delete").clicked() {
+                        requests.push(LoadoutRequest::Delete {
+                            name: loadout.name.clone(),
+                        });
+                    }
+                });
+            }
+        });
+
+    for mut request in requests {
+        info!("Asking server {:?}", request);
+        let _ = connection_manager.send_message::<CommonChannel, LoadoutRequest>(&mut request);
+    }
+}
+
+/// Egui to rebind our actions. Left click a binding and press the new key or button, right click clears it.
+/// Sticks are fixed, left one moves and right one orbits the camera
+fn keybind_ui(
+    mut contexts: bevy_egui::EguiContexts,
+    mut keybinds: ResMut<CoreKeybinds>,
+    mut capture: ResMut<KeybindCapture>,
+) {
+    let Some(egui_context) = contexts.try_ctx_mut() else {
+        return;
+    };
+
+    egui::Window::new("Keybinds")
+        .default_open(false)
+        .default_pos((250.0, 450.0))
+        .show(egui_context, |ui| {
+            match capture.waiting {
+                Some((slot, _)) => ui.label(format!(
+                    "Press the new binding for {:?}, Escape cancels",
+                    slot
+                )),
+                None => ui.label(&capture.message),
+            };
+
+            egui::Grid::new("keybind_grid").show(ui, |ui| {
+                ui.label("Action");
+                ui.label("Primary");
+                ui.label("Secondary");
+                ui.end_row();
+
+                for slot in KeybindSlot::ALL {
+                    ui.label(format!("{:?}", slot));
+                    for column in 0..BINDINGS_PER_SLOT {
+                        let text = if capture.waiting == Some((slot, column)) {
+                            "...".to_string()
+                        } else {
+                            match keybinds.get(slot, column) {
+                                Some(binding) => binding.to_string(),
+                                None => "-".to_string(),
+                            }
+                        };
+                        let response = ui.button(text);
+                        if response.clicked() {
+                            capture.waiting = Some((slot, column));
+                        }
+                        if response.secondary_clicked() && keybinds.get(slot, column).is_some() {
+                            keybinds.set(slot, column, None);
+                            capture.message = format!("Cleared {:?}", slot);
+                        }
+                    }
+                    ui.end_row();
+                }
+            });
+
+            if ui.button("Reset to defaults").clicked() {
+                *keybinds = CoreKeybinds::default();
+                capture.waiting = None;
+                capture.message = "Keybinds reset to defaults".to_string();
+            }
+        });
+}
+
+/// Egui that shows how much currency we have, only server changes it. Via store or trades
+fn currency_ui(
+    mut contexts: bevy_egui::EguiContexts,
+    opt_core: Option<Res<ClientCoreInformation>>,
+) {
+    // Only should appear once server sent our core
+    if let Some(core) = opt_core {
+        let current_currency = core.core.currency;
         // Grab primary window ctx
         if let Some(egui_context) = contexts.try_ctx_mut() {
-            // Use the egui context
             egui::Window::new("Currency mechanics")
                 .default_open(false)
                 .default_pos((450.0, 0.0))
                 .show(egui_context, |ui| {
-                    ui.heading(format!("Total amount {}", current_currency.amount));
-                    if ui.button("Gain currency").clicked() {
-                        current_currency.add(10.0);
-
-                        // Send event here
-                        let _ = connection_manager.send_message::<CommonChannel, SaveMessage>(
-                            &mut SaveMessage {
-                                id: player_id.id,
-                                change_char: None,
-                                change_currency: Some(current_currency.clone()),
-                                change_inventory: None,
-                            },
-                        );
-                    }
-                    if ui.button("Lose currency").clicked() {
-                        // Adjust currency logic and send event here
-                        current_currency.sub(10.0);
-
-                        let _ = connection_manager.send_message::<CommonChannel, SaveMessage>(
-                            &mut SaveMessage {
-                                id: player_id.id,
-                                change_char: None,
-                                change_currency: Some(current_currency.clone()),
-                                change_inventory: None,
-                            },
-                        );
-                    }
+                    ui.heading(format!("Total amount {}", current_currency));
                 });
         }
     }
 }
 
+/// How many combat outcomes our combat egui remembers
+const COMBAT_LOG_LEN: usize = 8;
+
+/// Egui that shows our health and stamina plus the latest hits, blocks and deaths server told everyone about
+fn combat_ui(
+    mut contexts: bevy_egui::EguiContexts,
+    player_q: Query<(&Health, &Stamina, &MeleeState), (With<Predicted>, With<Controlled>)>,
+    mut hits: EventReader<MessageEvent<MeleeHit>>,
+    mut blocks: EventReader<MessageEvent<MeleeBlocked>>,
+    mut deaths: EventReader<MessageEvent<PlayerDied>>,
+    mut combat_log: Local<VecDeque<String>>,
+) {
+    // Read them even while window is closed, otherwise we would show stale fights once opened
+    for event in hits.read() {
+        let hit = event.message();
+        combat_log.push_front(format!(
+            "{} hit {} with a {:?} attack for {}, {} health left",
+            hit.attacker, hit.defender, hit.attack, hit.damage, hit.health_left
+        ));
+    }
+    for event in blocks.read() {
+        let blocked = event.message();
+        combat_log.push_front(format!(
+            "{} blocked a {:?} attack from {}, {} stamina left",
+            blocked.defender, blocked.attack, blocked.attacker, blocked.stamina_left
+        ));
+    }
+    for event in deaths.read() {
+        let died = event.message();
+        combat_log.push_front(format!("{} was killed by {}", died.id, died.killer));
+    }
+    combat_log.truncate(COMBAT_LOG_LEN);
+
+    // Only should appear if replication already ocurred
+    let Ok((health, stamina, melee)) = player_q.get_single() else {
+        return;
+    };
+    let Some(egui_context) = contexts.try_ctx_mut() else {
+        return;
+    };
+
+    egui::Window::new("Combat")
+        .default_open(false)
+        .default_pos((450.0, 100.0))
+        .show(egui_context, |ui| {
+            ui.add(
+                egui::ProgressBar::new(health.current / health.max)
+                    .text(format!("Health {:.0}/{:.0}", health.current, health.max)),
+            );
+            ui.add(
+                egui::ProgressBar::new(stamina.current / stamina.max)
+                    .text(format!("Stamina {:.0}/{:.0}", stamina.current, stamina.max)),
+            );
+            match (melee.attack, melee.blocking) {
+                (Some(attack), _) => ui.label(format!("Swinging {:?}", attack)),
+                (None, true) => ui.label("Blocking"),
+                (None, false) => ui.label("Ready"),
+            };
+            ui.separator();
+            for line in combat_log.iter() {
+                ui.label(line);
+            }
+        });
+}
+
 /// Egui representing our store mechanics things like buying items selling them should occur here
+/// Worth noting we only ask, server validates and sends back our new core information
 fn store_ui(
     mut contexts: bevy_egui::EguiContexts,
-    gltf_collection: Option<Res<GltfCollection>>,
-    mut player_q: Query<
-        (&PlayerId, &mut Currency, &mut Inventory),
-        (With<Predicted>, With<Controlled>),
-    >,
+    player_q: Query<&PlayerVisuals, (With<Predicted>, With<Controlled>)>,
+    opt_core: Option<Res<ClientCoreInformation>>,
+    catalog: Res<CoreItemCatalog>,
+    mut transaction_results: EventReader<MessageEvent<TransactionResult>>,
+    mut last_result: Local<String>,
     mut connection_manager: ResMut<ClientConnectionManager>,
 ) {
-    // Only show the store if assets are available and the player is replicated
-    if let Some(gltf_collection) = gltf_collection {
-        if let Ok((player_id, mut player_money, mut player_inv)) = player_q.get_single_mut() {
-            // Egui context
-
-            if let Some(egui_context) = contexts.try_ctx_mut() {
-                // Get the available items from the GLTF collection
-                let items: Vec<Item> = gltf_collection
-                    .gltf_files
-                    .keys()
-                    .into_iter()
-                    .map(|file_path| Item::new_from_filepath(file_path))
-                    .collect();
-
-                // Render the store UI
-                egui::Window::new("Store")
-                    .default_open(false)
-                    .default_pos((700.0, 0.0))
-                    .show(egui_context, |ui| {
-                        egui::ScrollArea::both().show(ui, |ui| {
-                            ui.horizontal(|ui| {
-                                // Buy section
-                                render_buy_section(
-                                    ui,
-                                    &items,
-                                    &player_id,
-                                    &mut player_inv,
-                                    &mut player_money,
-                                    &mut connection_manager,
-                                );
-
-                                // Sell section
-                                render_sell_section(
-                                    ui,
-                                    &player_id,
-                                    &mut player_inv,
-                                    &mut player_money,
-                                    &mut connection_manager,
-                                );
-                            });
+    // Tell the player how his last transaction went
+    for event in transaction_results.read() {
+        *last_result = match event.message() {
+            TransactionResult::Bought { item, currency } => {
+                format!("Bought {}, you now have {}", item, currency)
+            }
+            TransactionResult::Sold { item, currency } => {
+                format!("Sold {}, you now have {}", item, currency)
+            }
+            TransactionResult::Rejected(err) => format!("Server refused: {}", err),
+        };
+        info!("{}", *last_result);
+    }
+
+    // Only show the store if the player is replicated and server sent our core
+    let Some(core) = opt_core else {
+        return;
+    };
+    let (player_money, player_inv) = (&core.core.currency, &core.core.inventory);
+    if let Ok(player_visuals) = player_q.get_single() {
+        if let Some(egui_context) = contexts.try_ctx_mut() {
+            // Render the store UI
+            egui::Window::new("Store")
+                .default_open(false)
+                .default_pos((700.0, 0.0))
+                .show(egui_context, |ui| {
+                    ui.label(format!("Money: {}", player_money));
+                    ui.label(format!(
+                        "Inventory: {}/{}",
+                        player_inv.entries.len(),
+                        player_inv.capacity
+                    ));
+                    ui.label(&*last_result);
+                    egui::ScrollArea::both().show(ui, |ui| {
+                        ui.horizontal(|ui| {
+                            // Buy section
+                            render_buy_section(ui, player_inv, &catalog, &mut connection_manager);
+
+                            // Sell section
+                            render_sell_section(
+                                ui,
+                                player_inv,
+                                player_visuals,
+                                &catalog,
+                                &mut connection_manager,
+                            );
                         });
                     });
-            }
+                });
         }
     }
 }
 
-/// Render the "Buy" section
+/// Render the "Buy" section, everything purchasable in our catalog. Unique items we already own cant be clicked
 fn render_buy_section(
     ui: &mut egui::Ui,
-    items: &[Item],
-    player_id: &PlayerId,
-    player_inv: &mut Inventory,
-    player_money: &mut Currency,
+    player_inv: &Inventory,
+    catalog: &CoreItemCatalog,
     connection_manager: &mut ResMut<ClientConnectionManager>,
 ) {
     ui.vertical(|ui| {
         ui.heading("Buy items");
-        for item in items {
+        for definition in catalog.purchasable() {
             ui.horizontal(|ui| {
                 // Button to buy the item
-                let item_name = item.name.to_string();
-                if ui.button(&item_name).clicked() {
-                    player_inv.insert_item(item.clone());
-                    player_money.sub(item.item_type.value());
-                    info!("Bought action, for item {} price {}", item_name, {
-                        item.item_type.value()
-                    });
-                    let _ = connection_manager.send_message::<CommonChannel, SaveMessage>(
-                        &mut SaveMessage {
-                            id: player_id.id,
-                            change_char: None,
-                            change_currency: Some(player_money.clone()),
-                            change_inventory: Some(player_inv.clone()),
+                let owned = player_inv.count_of(&definition.id);
+                let can_buy = !(definition.unique && owned > 0);
+                if ui
+                    .add_enabled(can_buy, egui::Button::new(&definition.display_name))
+                    .clicked()
+                {
+                    info!("Asking server to buy {}", definition.id);
+                    let _ = connection_manager.send_message::<CommonChannel, BuyItemRequest>(
+                        &mut BuyItemRequest {
+                            item_id: definition.id.clone(),
                         },
                     );
                 }
 
                 // Item price
-                ui.label(format!("Cost: {}", item.item_type.value()));
+                ui.label(format!(
+                    "Cost: {} {:?} Owned: {}",
+                    Currency::new(definition.price),
+                    definition.rarity,
+                    owned
+                ));
             });
         }
     });
 }
 
-/// Render the "Sell" section
+/// Render the "Sell" section, one button per stack. Sells one at a time, equipped items cant be clicked
 fn render_sell_section(
     ui: &mut egui::Ui,
-    player_id: &PlayerId,
-    player_inv: &mut Inventory,
-    player_money: &mut Currency,
+    player_inv: &Inventory,
+    player_visuals: &PlayerVisuals,
+    catalog: &CoreItemCatalog,
     connection_manager: &mut ResMut<ClientConnectionManager>,
 ) {
     ui.vertical(|ui| {
         ui.heading("Sell your items");
 
-        // Collect inventory items into a temporary vector to avoid borrowing issues
-        let items: Vec<_> = player_inv.items.values().cloned().collect();
-        for item in items {
+        // Sorted so egui doesnt jump around
+        let mut entries: Vec<&InventoryEntry> = player_inv.entries.values().collect();
+        entries.sort_by(|a, b| a.item.definition_id.cmp(&b.item.definition_id));
+
+        for entry in entries {
+            let item = &entry.item;
             ui.horizontal(|ui| {
                 // Button to sell the item
-                let item_name = item.name.to_string();
-                if ui.button(&item_name).clicked() {
-                    player_inv.remove_item(&item);
-                    player_money.add(item.item_type.value());
-                    info!("Sell action, for item {} price {}", item_name, {
-                        item.item_type.value()
-                    });
-
-                    let _ = connection_manager.send_message::<CommonChannel, SaveMessage>(
-                        &mut SaveMessage {
-                            id: player_id.id,
-                            change_char: None,
-                            change_currency: Some(player_money.clone()),
-                            change_inventory: Some(player_inv.clone()),
-                        },
+                let item_name = format!("{} x{}", item.name, entry.count);
+                let equipped = entry.count <= player_visuals.equipped_count(&item.id);
+                if ui
+                    .add_enabled(!equipped, egui::Button::new(&item_name))
+                    .clicked()
+                {
+                    info!("Asking server to sell {}", item_name);
+                    let _ = connection_manager.send_message::<CommonChannel, SellItemRequest>(
+                        &mut SellItemRequest { item_id: item.id },
                     );
                 }
 
-                // Item price
-                ui.label(format!("Cost: {}", item.item_type.value()));
+                // Item sell value
+                if let Some(definition) = catalog.get(&item.definition_id) {
+                    ui.label(format!(
+                        "Sells for: {}",
+                        Currency::new(definition.sell_value)
+                    ));
+                }
             });
         }
     });
 }
 
+/// What our trade window remembers between frames
+#[derive(Default)]
+struct TradeUiState {
+    /// Who invited us and is waiting for an answer
+    invites: Vec<ClientId>,
+    /// Current session, as server sees it
+    session: Option<TradeView>,
+    /// What we are about to offer, item instance to how many of it
+    draft_items: HashMap<Uuid, u32>,
+    /// Money we are about to offer in minor units
+    draft_currency: u64,
+    /// How our last trade request went
+    last_message: String,
+}
+
+/// Trade window, invite other players and swap items and money with them.
+/// Server holds the session, we only send what we would like to offer and lock or confirm it
+fn trade_ui(
+    mut contexts: bevy_egui::EguiContexts,
+    player_q: Query<&PlayerVisuals, (With<Predicted>, With<Controlled>)>,
+    opt_core: Option<Res<ClientCoreInformation>>,
+    others_q: Query<&PlayerId, (With<Predicted>, With<PlayerMarker>, Without<Controlled>)>,
+    mut trade_updates: EventReader<MessageEvent<TradeUpdate>>,
+    mut state: Local<TradeUiState>,
+    mut connection_manager: ResMut<ClientConnectionManager>,
+) {
+    // Keep up with what server tells us
+    for event in trade_updates.read() {
+        match event.message() {
+            TradeUpdate::Invited { inviter } => {
+                state.last_message = format!("Player {} wants to trade", inviter);
+                if !state.invites.contains(inviter) {
+                    state.invites.push(*inviter);
+                }
+            }
+            TradeUpdate::Session(view) => {
+                state.invites.retain(|inviter| *inviter != view.partner);
+                state.session = Some(view.clone());
+            }
+            TradeUpdate::Completed => {
+                state.last_message = "Trade completed".to_string();
+                state.session = None;
+                state.draft_items.clear();
+                state.draft_currency = 0;
+            }
+            TradeUpdate::Cancelled { reason } => {
+                state.last_message = format!("Trade cancelled: {}", reason);
+                state.session = None;
+                state.draft_items.clear();
+                state.draft_currency = 0;
+            }
+            TradeUpdate::Refused(err) => {
+                state.last_message = format!("Server refused: {}", err);
+            }
+        }
+        info!("{}", state.last_message);
+    }
+
+    // Only show the trade if the player is replicated and server sent our core
+    let Ok(player_visuals) = player_q.get_single() else {
+        return;
+    };
+    let Some(core) = opt_core else {
+        return;
+    };
+    let (player_money, player_inv) = (&core.core.currency, &core.core.inventory);
+    let Some(egui_context) = contexts.try_ctx_mut() else {
+        return;
+    };
+    let state = state.deref_mut();
+    let mut requests = Vec::new();
+
+    egui::Window::new("Trade")
+        .default_open(false)
+        .default_pos((700.0, 300.0))
+        .show(egui_context, |ui| {
+            ui.label(&state.last_message);
+
+            let Some(view) = &state.session else {
+                // No session - Invite someone or answer an invite
+                ui.heading("Players");
+                for other in others_q.iter() {
+                    if ui.button(format!("Invite {}", other.id)).clicked() {
+                        requests.push(TradeRequest::Invite { partner: other.id });
+                    }
+                }
+                ui.heading("Invites");
+                for inviter in state.invites.clone() {
+                    ui.horizontal(|ui| {
+                        ui.label(format!("From {}", inviter));
+                        if ui.button("Accept").clicked() {
+                            requests.push(TradeRequest::Respond {
+                                inviter: inviter,
+                                accept: true,
+                            });
+                        }
+                        if ui.button("Decline").clicked() {
+                            requests.push(TradeRequest::Respond {
+                                inviter: inviter,
+                                accept: false,
+                            });
+                            state.invites.retain(|other| *other != inviter);
+                        }
+                    });
+                }
+                return;
+            };
+
+            ui.heading(format!("Trading with {}", view.partner));
+            ui.horizontal(|ui| {
+                // Our draft, what we would like to give
+                ui.vertical(|ui| {
+                    ui.label(format!("Your offer - {:?}", view.my_phase));
+
+                    // Sorted so egui doesnt jump around
+                    let mut entries: Vec<&InventoryEntry> = player_inv.entries.values().collect();
+                    entries.sort_by(|a, b| a.item.definition_id.cmp(&b.item.definition_id));
+                    for entry in entries {
+                        let item = &entry.item;
+                        // Equipped ones can only be offered from the rest of the stack
+                        let max = entry
+                            .count
+                            .saturating_sub(player_visuals.equipped_count(&item.id));
+                        ui.horizontal(|ui| {
+                            let mut count = state.draft_items.get(&item.id).copied().unwrap_or(0);
+                            ui.label(format!("{} x{}", item.name, entry.count));
+                            ui.add_enabled(
+                                max > 0,
+                                egui::DragValue::new(&mut count).range(0..=max),
+                            );
+                            if count > 0 {
+                                state.draft_items.insert(item.id, count);
+                            } else {
+                                state.draft_items.remove(&item.id);
+                            }
+                        });
+                    }
+                    ui.horizontal(|ui| {
+                        ui.label(format!("Money, you have {}", player_money));
+                        ui.add(
+                            egui::DragValue::new(&mut state.draft_currency)
+                                .range(0..=player_money.amount),
+                        );
+                    });
+                    ui.label(format!("Sent offer: {}", describe_offer(&view.my_offer)));
+                });
+
+                // Their side, only what server validated
+                ui.vertical(|ui| {
+                    ui.label(format!("Their offer - {:?}", view.their_phase));
+                    ui.label(describe_offer(&view.their_offer));
+                });
+            });
+
+            ui.horizontal(|ui| {
+                if ui.button("Send offer").clicked() {
+                    requests.push(TradeRequest::Offer {
+                        items: state
+                            .draft_items
+                            .iter()
+                            .map(|(item_id, count)| (*item_id, *count))
+                            .collect(),
+                        currency: state.draft_currency,
+                    });
+                }
+                if ui
+                    .add_enabled(
+                        view.my_phase == TradePhase::Editing,
+                        egui::Button::new("Lock"),
+                    )
+                    .clicked()
+                {
+                    requests.push(TradeRequest::Lock);
+                }
+                let both_locked =
+                    view.my_phase != TradePhase::Editing && view.their_phase != TradePhase::Editing;
+                if ui
+                    .add_enabled(
+                        both_locked && view.my_phase != TradePhase::Confirmed,
+                        egui::Button::new("Confirm"),
+                    )
+                    .clicked()
+                {
+                    requests.push(TradeRequest::Confirm);
+                }
+                if ui.button("Cancel").clicked() {
+                    requests.push(TradeRequest::Cancel);
+                }
+            });
+        });
+
+    for mut request in requests {
+        info!("Asking server {:?}", request);
+        let _ = connection_manager.send_message::<CommonChannel, TradeRequest>(&mut request);
+    }
+}
+
+/// Callable function - One line summary of a trade offer
+fn describe_offer(offer: &TradeOffer) -> String {
+    let mut lines: Vec<String> = offer
+        .items
+        .iter()
+        .map(|entry| format!("{} x{}", entry.item.name, entry.count))
+        .collect();
+    lines.push(format!("{}", offer.currency));
+    lines.join(", ")
+}
+
 /// Egui to test disconnection and connection of clients
 fn manage_connection_ui(
     mut contexts: bevy_egui::EguiContexts,
@@ -392,7 +818,7 @@ fn manage_connection_ui(
         egui::Window::new("Manage connections")
             .default_pos((250.0, 50.0))
             .default_open(false)
-            .show(&egui_context, |ui| match network_state.get() {
+            .show(egui_context, |ui| match network_state.get() {
                 NetworkingState::Connected => {
                     if ui.button("Disconnect client").clicked() {
                         commands.disconnect_client();
@@ -403,7 +829,8 @@ fn manage_connection_ui(
                 }
                 NetworkingState::Disconnected => {
                     if ui.button("Connect client").clicked() {
-                        commands.connect_client();
+                        // Every connection requires a fresh token
+                        commands.trigger(LoginEvent);
                     }
                 }
             });
@@ -0,0 +1,387 @@
+use super::protocol::{PlayerActions, PlayerMarker, STICK_DEADZONE};
+use crate::shared::config::CoreConfig;
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use leafwing_input_manager::prelude::*;
+use lightyear::prelude::client::Predicted;
+use lightyear::shared::replication::components::Controlled;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+/// Plugin responsible for our keybinds. Rebinding is purely client side, server only ever sees the resulting action state
+pub struct ClientKeybindPlugin;
+
+/// How many bindings each slot can have, primary and secondary
+pub const BINDINGS_PER_SLOT: usize = 2;
+
+/// Every rebindable action, movement is split in the four directions of his virtual dpad. Sticks are not rebindable
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum KeybindSlot {
+    MoveForward,
+    MoveBackward,
+    MoveLeft,
+    MoveRight,
+    LightAttack,
+    HeavyAttack,
+    Block,
+}
+
+impl KeybindSlot {
+    /// Every slot in the order our keybind screen shows them
+    pub const ALL: [KeybindSlot; 7] = [
+        KeybindSlot::MoveForward,
+        KeybindSlot::MoveBackward,
+        KeybindSlot::MoveLeft,
+        KeybindSlot::MoveRight,
+        KeybindSlot::LightAttack,
+        KeybindSlot::HeavyAttack,
+        KeybindSlot::Block,
+    ];
+
+    /// Move directions become a virtual dpad, which only takes keyboard keys. Gamepads move with the left stick
+    pub fn keys_only(&self) -> bool {
+        matches!(
+            self,
+            KeybindSlot::MoveForward
+                | KeybindSlot::MoveBackward
+                | KeybindSlot::MoveLeft
+                | KeybindSlot::MoveRight
+        )
+    }
+}
+
+/// A single physical button, from keyboard mouse or gamepad
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Binding {
+    Key(KeyCode),
+    Mouse(MouseButton),
+    Gamepad(GamepadButton),
+}
+
+impl fmt::Display for Binding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Binding::Key(key) => write!(f, "{:?}", key),
+            Binding::Mouse(button) => write!(f, "Mouse {:?}", button),
+            Binding::Gamepad(button) => write!(f, "Gamepad {:?}", button),
+        }
+    }
+}
+
+/// Our keybind profile, the input map of our player is built from it and it is what we write into the settings file of that user.
+/// Loaded once at startup, before anything can ask for the input map
+#[derive(Resource, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CoreKeybinds {
+    pub slots: HashMap<KeybindSlot, [Option<Binding>; BINDINGS_PER_SLOT]>,
+}
+
+impl Default for CoreKeybinds {
+    /// Same bindings as [`PlayerActions::default_input_map`]
+    fn default() -> Self {
+        let mut slots = HashMap::new();
+        slots.insert(
+            KeybindSlot::MoveForward,
+            [
+                Some(Binding::Key(KeyCode::KeyW)),
+                Some(Binding::Key(KeyCode::ArrowUp)),
+            ],
+        );
+        slots.insert(
+            KeybindSlot::MoveBackward,
+            [
+                Some(Binding::Key(KeyCode::KeyS)),
+                Some(Binding::Key(KeyCode::ArrowDown)),
+            ],
+        );
+        slots.insert(
+            KeybindSlot::MoveLeft,
+            [
+                Some(Binding::Key(KeyCode::KeyA)),
+                Some(Binding::Key(KeyCode::ArrowLeft)),
+            ],
+        );
+        slots.insert(
+            KeybindSlot::MoveRight,
+            [
+                Some(Binding::Key(KeyCode::KeyD)),
+                Some(Binding::Key(KeyCode::ArrowRight)),
+            ],
+        );
+        slots.insert(
+            KeybindSlot::LightAttack,
+            [
+                Some(Binding::Key(KeyCode::KeyJ)),
+                Some(Binding::Gamepad(GamepadButton::West)),
+            ],
+        );
+        slots.insert(
+            KeybindSlot::HeavyAttack,
+            [
+                Some(Binding::Key(KeyCode::KeyK)),
+                Some(Binding::Gamepad(GamepadButton::North)),
+            ],
+        );
+        slots.insert(
+            KeybindSlot::Block,
+            [
+                Some(Binding::Key(KeyCode::KeyL)),
+                Some(Binding::Gamepad(GamepadButton::LeftTrigger2)),
+            ],
+        );
+        Self { slots: slots }
+    }
+}
+
+impl CoreKeybinds {
+    /// Callable function - Reads the keybinds of that user, if he never saved any or the file is broken he gets the defaults
+    pub fn load(path: &Path) -> Self {
+        match fs::read_to_string(path) {
+            Ok(text) => match ron::from_str::<CoreKeybinds>(&text) {
+                Ok(keybinds) => {
+                    info!("Loaded keybinds from {}", path.display());
+                    keybinds
+                }
+                Err(err) => {
+                    warn!(
+                        "Keybinds file {} is malformed, using defaults: {}",
+                        path.display(),
+                        err
+                    );
+                    CoreKeybinds::default()
+                }
+            },
+            Err(err) if err.kind() == ErrorKind::NotFound => CoreKeybinds::default(),
+            Err(err) => {
+                warn!(
+                    "Couldnt read keybinds file {}, using defaults: {}",
+                    path.display(),
+                    err
+                );
+                CoreKeybinds::default()
+            }
+        }
+    }
+
+    /// Callable function - Writes the keybinds into a temp file and renames it into place, so a crash never leaves half a file
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|err| err.to_string())?;
+        }
+        let text = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())
+            .map_err(|err| err.to_string())?;
+        let mut temp_path = path.as_os_str().to_owned();
+        temp_path.push(".tmp");
+        fs::write(&temp_path, text).map_err(|err| err.to_string())?;
+        fs::rename(&temp_path, path).map_err(|err| err.to_string())
+    }
+
+    /// What is bound to that slot in that column
+    pub fn get(&self, slot: KeybindSlot, column: usize) -> Option<Binding> {
+        self.slots
+            .get(&slot)
+            .and_then(|bindings| bindings.get(column).copied().flatten())
+    }
+
+    /// Binds or clears that slot in that column
+    pub fn set(&mut self, slot: KeybindSlot, column: usize, binding: Option<Binding>) {
+        let bindings = self.slots.entry(slot).or_insert([None; BINDINGS_PER_SLOT]);
+        bindings[column] = binding;
+    }
+
+    /// Tells me which other slot and column already uses that binding, if any
+    pub fn conflict(
+        &self,
+        binding: Binding,
+        slot: KeybindSlot,
+        column: usize,
+    ) -> Option<(KeybindSlot, usize)> {
+        for other in KeybindSlot::ALL {
+            for other_column in 0..BINDINGS_PER_SLOT {
+                let is_itself = other == slot && other_column == column;
+                if !is_itself && self.get(other, other_column) == Some(binding) {
+                    return Some((other, other_column));
+                }
+            }
+        }
+        None
+    }
+
+    /// Builds the input map our player uses. A dpad column only counts when all four directions are keys
+    pub fn input_map(&self) -> InputMap<PlayerActions> {
+        let mut input_map = InputMap::default()
+            .with_dual_axis(
+                PlayerActions::Move,
+                GamepadStick::LEFT.with_circle_deadzone(STICK_DEADZONE),
+            )
+            .with_dual_axis(
+                PlayerActions::Look,
+                GamepadStick::RIGHT.with_circle_deadzone(STICK_DEADZONE),
+            );
+
+        for column in 0..BINDINGS_PER_SLOT {
+            let directions = [
+                KeybindSlot::MoveForward,
+                KeybindSlot::MoveBackward,
+                KeybindSlot::MoveLeft,
+                KeybindSlot::MoveRight,
+            ]
+            .map(|slot| self.get(slot, column));
+            if let [Some(Binding::Key(up)), Some(Binding::Key(down)), Some(Binding::Key(left)), Some(Binding::Key(right))] =
+                directions
+            {
+                input_map
+                    .insert_dual_axis(PlayerActions::Move, VirtualDPad::new(up, down, left, right));
+            }
+
+            for (slot, action) in [
+                (KeybindSlot::LightAttack, PlayerActions::LightAttack),
+                (KeybindSlot::HeavyAttack, PlayerActions::HeavyAttack),
+                (KeybindSlot::Block, PlayerActions::Block),
+            ] {
+                match self.get(slot, column) {
+                    Some(Binding::Key(key)) => {
+                        input_map.insert(action, key);
+                    }
+                    Some(Binding::Mouse(button)) => {
+                        input_map.insert(action, button);
+                    }
+                    Some(Binding::Gamepad(button)) => {
+                        input_map.insert(action, button);
+                    }
+                    None => {}
+                }
+            }
+        }
+        input_map
+    }
+}
+
+/// Where our keybinds file lives, one per user so people sharing a machine keep their own
+#[derive(Resource)]
+pub struct KeybindsFile {
+    pub path: PathBuf,
+}
+
+impl KeybindsFile {
+    /// Usernames can have anything in them, only keep what is safe for a file name
+    pub fn of_user(config: &CoreConfig, username: &str) -> Self {
+        let file_name: String = username
+            .chars()
+            .map(|char| {
+                if char.is_ascii_alphanumeric() || char == '-' || char == '_' {
+                    char
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        Self {
+            path: PathBuf::from(&config.settings.dir).join(format!("{}.keybinds.ron", file_name)),
+        }
+    }
+}
+
+/// Which slot and column is waiting for the next button press, filled by the keybind egui
+#[derive(Resource, Default)]
+pub struct KeybindCapture {
+    pub waiting: Option<(KeybindSlot, usize)>,
+    /// Last thing that happened, shown in the keybind egui
+    pub message: String,
+}
+
+impl Plugin for ClientKeybindPlugin {
+    fn build(&self, app: &mut App) {
+        // Resources
+        app.init_resource::<KeybindCapture>();
+
+        // Update because it reads our inputs of this frame
+        app.add_systems(Update, capture_next_binding);
+
+        // Only when keybinds changed after startup, loading them is not a change worth saving
+        app.add_systems(
+            Update,
+            persist_keybinds
+                .run_if(resource_changed::<CoreKeybinds>.and(not(resource_added::<CoreKeybinds>))),
+        );
+    }
+}
+
+/// When a slot is waiting, the next key mouse or gamepad button pressed becomes his binding. Refuses it if another slot already uses it
+fn capture_next_binding(
+    keys: Res<ButtonInput<KeyCode>>,
+    mouse: Res<ButtonInput<MouseButton>>,
+    gamepads: Query<&Gamepad>,
+    mut capture: ResMut<KeybindCapture>,
+    mut keybinds: ResMut<CoreKeybinds>,
+) {
+    let Some((slot, column)) = capture.waiting else {
+        return;
+    };
+    if keys.just_pressed(KeyCode::Escape) {
+        capture.waiting = None;
+        capture.message = "Rebinding cancelled".to_string();
+        return;
+    }
+
+    let pressed = keys
+        .get_just_pressed()
+        .next()
+        .map(|key| Binding::Key(*key))
+        .or_else(|| {
+            mouse
+                .get_just_pressed()
+                .next()
+                .map(|button| Binding::Mouse(*button))
+        })
+        .or_else(|| {
+            gamepads
+                .iter()
+                .find_map(|gamepad| gamepad.get_just_pressed().next())
+                .map(|button| Binding::Gamepad(*button))
+        });
+    let Some(binding) = pressed else {
+        return;
+    };
+
+    if slot.keys_only() && !matches!(binding, Binding::Key(_)) {
+        capture.message = format!("{:?} only takes keyboard keys", slot);
+        return;
+    }
+    if let Some((other, _)) = keybinds.conflict(binding, slot, column) {
+        capture.waiting = None;
+        capture.message = format!(
+            "{} is already bound to {:?}, clear it there first",
+            binding, other
+        );
+        return;
+    }
+
+    info!("Bound {} to {:?}", binding, slot);
+    keybinds.set(slot, column, Some(binding));
+    capture.waiting = None;
+    capture.message = format!("Bound {} to {:?}", binding, slot);
+}
+
+/// Writes our keybinds into the file of our user and swaps the input map of our player, server never knows about any of it
+fn persist_keybinds(
+    keybinds: Res<CoreKeybinds>,
+    keybinds_file: Res<KeybindsFile>,
+    mut player_q: Query<
+        &mut InputMap<PlayerActions>,
+        (With<PlayerMarker>, With<Predicted>, With<Controlled>),
+    >,
+) {
+    if let Err(err) = keybinds.save(&keybinds_file.path) {
+        error!(
+            "Couldnt save keybinds into {}: {}",
+            keybinds_file.path.display(),
+            err
+        );
+    }
+    for mut input_map in player_q.iter_mut() {
+        *input_map = keybinds.input_map();
+    }
+}
@@ -1,3 +1,5 @@
+use crate::shared::catalog::CoreItemCatalog;
+use bevy::asset::RecursiveDependencyLoadState;
 use bevy::prelude::*;
 use bevy::utils::HashMap;
 use bevy_asset_loader::prelude::*;
@@ -8,29 +10,11 @@ use super::ClientAppState;
 /// from LoadingAssets to ClientAppState in the ClientAppState state.
 pub struct LoadAssetsPlugin;
 
-/// Gltf collection, currently stores  all assets that are imported gltfs.
-#[derive(AssetCollection, Resource, Reflect)]
+/// Gltf collection, stores every gltf our item catalog points to keyed by his path.
+/// Not an asset collection as paths come from the catalog, adding a cosmetic only needs a catalog entry. See load_catalog_gltfs
+#[derive(Resource, Reflect, Default)]
 #[reflect(Resource)]
 pub struct GltfCollection {
-    #[asset(
-        paths(
-            // Weapons
-            "weapons/katana.glb",
-            // Full characters - This guy is off for now to avoid annoying warning
-            // "characters/character_mesh.glb",
-            // Heads
-            "characters/visual_parts/def_m_head.glb",
-            // Torsos
-            "characters/visual_parts/def_m_torso.glb",
-            // Legs
-            "characters/visual_parts/def_m_legs.glb",
-            // Arms
-            "characters/visual_parts/def_m_arms.glb",
-            //Skeletons
-            "characters/anim_skeletons/def_m_main_skeleton.glb"
-        ),
-        collection(typed, mapped)
-    )]
     pub gltf_files: HashMap<String, Handle<Gltf>>,
 }
 
@@ -44,15 +28,73 @@ pub struct ImagesCollection {
 
 impl Plugin for LoadAssetsPlugin {
     fn build(&self, app: &mut App) {
+        // Empty until our catalog loads
+        app.init_resource::<GltfCollection>();
+
         // Add in world debugger
         app.register_type::<GltfCollection>();
         app.register_type::<ImagesCollection>();
         app.add_loading_state(
             // Simple syntax sugar for our state transition and which specific collections we shall load, this can be used multiple times.
-            LoadingState::new(ClientAppState::LoadingAssets)
-                .load_collection::<GltfCollection>()
-                .load_collection::<ImagesCollection>()
-                .continue_to_state(ClientAppState::Game),
+            // No continue to state, gltfs come from the catalog so finish_loading decides when we are done
+            LoadingState::new(ClientAppState::LoadingAssets).load_collection::<ImagesCollection>(),
         );
+
+        // Update because catalog may be hot reloaded with new items
+        app.add_systems(Update, load_catalog_gltfs);
+
+        // Update because it waits for assets
+        app.add_systems(
+            Update,
+            finish_loading.run_if(in_state(ClientAppState::LoadingAssets)),
+        );
+    }
+}
+
+/// Whenever our catalog changes, asks asset server for every gltf we dont have yet
+fn load_catalog_gltfs(
+    catalog: Res<CoreItemCatalog>,
+    asset_server: Res<AssetServer>,
+    mut gltf_collection: ResMut<GltfCollection>,
+) {
+    if !catalog.is_changed() {
+        return;
+    }
+    for definition in catalog.items.values() {
+        if !gltf_collection
+            .gltf_files
+            .contains_key(&definition.gltf_path)
+        {
+            info!("Loading gltf {}", definition.gltf_path);
+            let handle = asset_server.load(definition.gltf_path.clone());
+            gltf_collection
+                .gltf_files
+                .insert(definition.gltf_path.clone(), handle);
+        }
+    }
+}
+
+/// Moves us into game once our images, catalog and every catalog gltf finished loading.
+/// Gltfs that failed count as done, spawning them later only warns
+fn finish_loading(
+    catalog: Res<CoreItemCatalog>,
+    gltf_collection: Res<GltfCollection>,
+    images: Option<Res<ImagesCollection>>,
+    asset_server: Res<AssetServer>,
+    mut next_state: ResMut<NextState<ClientAppState>>,
+) {
+    if images.is_none() || catalog.items.is_empty() || gltf_collection.gltf_files.is_empty() {
+        return;
+    }
+    let finished = gltf_collection.gltf_files.values().all(|handle| {
+        asset_server.is_loaded_with_dependencies(handle)
+            || matches!(
+                asset_server.recursive_dependency_load_state(handle),
+                RecursiveDependencyLoadState::Failed(_)
+            )
+    });
+    if finished {
+        info!("Loaded {} gltfs", gltf_collection.gltf_files.len());
+        next_state.set(ClientAppState::Game);
     }
 }
@@ -1,23 +1,29 @@
 use crate::client::animation::ClientAnimationPlugin;
+use crate::client::auth::{ClientAuthPlugin, ClientCredentials};
+use crate::client::keybinds::{ClientKeybindPlugin, CoreKeybinds, KeybindsFile};
 use crate::client::load_assets::LoadAssetsPlugin;
-use crate::server::SERVER_ADDR;
+use crate::shared::config::CoreConfig;
+use crate::shared::egui::SharedEgui;
+use crate::shared::renderer::SharedRendererPlugin;
 use crate::shared::*;
 use bevy::{prelude::*, window::ClosingWindow};
 use camera::ClientCameraPlugin;
 use egui::ClientEguiPlugin;
 use lightyear::prelude::client::*;
 use lightyear::prelude::*;
+use lightyear::shared::events::components::MessageEvent;
 use player::ClientPlayerPlugin;
-use protocol::CoreSaveInfoMap;
+use protocol::{CoreInformation, CoreInformationMessage};
 use skybox::SkyboxPlugin;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use world::ClientWorldPlugin;
 
 /// Centralization plugin - When we pass in the cli the arg "client" this guy runs
 pub struct CoreClientPlugin {
     /// This is one of the only few plugins that actually require an argument
-    /// In this case we need t ograb
-    pub client_id: u64,
+    /// In this case we need to grab who is logging in, our client id is given by the token service
+    pub credentials: ClientCredentials,
+    /// Already merged config, cli flags + config file
+    pub config: CoreConfig,
 }
 
 /// Essential state for functionality - Basically tell me what is the current state of our app
@@ -39,10 +45,20 @@ pub struct CoreEasyClient {
     client_id: ClientId,
 }
 
+/// Our own core information as last confirmed by server, what we own and how much money we have.
+/// Similar to easy client he is only inserted once server sends it to us
+#[derive(Resource, Reflect)]
+#[reflect(Resource)]
+pub struct ClientCoreInformation {
+    pub core: CoreInformation,
+}
+
+pub mod auth;
 pub mod camera;
 // This guy is public because we need to share the Parts struct with the impl on shared
 mod animation;
 pub mod egui;
+pub mod keybinds;
 mod load_assets;
 mod player;
 mod skybox;
@@ -57,11 +73,24 @@ impl Plugin for CoreClientPlugin {
         }));
 
         // This looks weird but just imagine you are building a lot of plugins at once
-        app.add_plugins(build_client_plugin(&self.client_id));
+        app.add_plugins(build_client_plugin(&self.config));
+
+        // Config is also a resource, so systems can easily read it
+        app.insert_resource(self.config.clone());
+        app.insert_resource(self.credentials.clone());
+
+        // Keybinds of whoever is logging in, loaded right away so our player input map is built from them
+        let keybinds_file = KeybindsFile::of_user(&self.config, &self.credentials.username);
+        app.insert_resource(CoreKeybinds::load(&keybinds_file.path));
+        app.insert_resource(keybinds_file);
 
         // Add our shared plugin containing the protocol + other shared behaviour
         app.add_plugins(CoreSharedPlugin);
 
+        // Shared plugins that require a window or renderer, only client is guaranteed to have them
+        app.add_plugins(SharedEgui);
+        app.add_plugins(SharedRendererPlugin);
+
         //Add selfmade plugins
         app.add_plugins(ClientCameraPlugin);
         app.add_plugins(ClientEguiPlugin);
@@ -70,46 +99,40 @@ impl Plugin for CoreClientPlugin {
         app.add_plugins(LoadAssetsPlugin);
         app.add_plugins(SkyboxPlugin);
         app.add_plugins(ClientAnimationPlugin);
+        app.add_plugins(ClientAuthPlugin);
+        app.add_plugins(ClientKeybindPlugin);
 
         // Initializing center state of client
         app.init_state::<ClientAppState>();
 
-        // Initialiazing core resources and replicated ones
-        app.init_resource::<CoreSaveInfoMap>();
-
-        // Add our client-specific logic. Here we will just connect to the server only when we have our assets loaded
-        app.add_systems(OnEnter(ClientAppState::Game), connect_client);
-
         // Essential systems - Run in update because as reconnects may occur client id may vary, only prod tho.
         app.add_systems(Update, form_easy_client);
 
+        // Update because it listens to server messages
+        app.add_systems(Update, receive_own_core_information);
+
         // Observer checks if our client closed it is main window if so
         app.add_observer(on_app_exit_disconnect);
 
         // Debug
         app.register_type::<CoreEasyClient>();
+        app.register_type::<ClientCoreInformation>();
     }
 }
 
 /// Here we create the lightyear [`ClientPlugins`], a series of plugins responsible to setup our base client.
-fn build_client_plugin(client_id: &u64) -> ClientPlugins {
-    // This is super temporary, we use this just to avoid overlapping addresses with other clients
-    let client_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, *client_id as u8)), 4000);
+fn build_client_plugin(core_config: &CoreConfig) -> ClientPlugins {
+    let network = &core_config.network;
 
     // The NetConfig specifies how we establish a connection with the server.
     let net_config = NetConfig::Netcode {
         // Authentication is where you specify how the client should connect to the server
-        // This is where you provide the server address
-        auth: Authentication::Manual {
-            server_addr: SERVER_ADDR,
-            client_id: *client_id,
-            private_key: Key::default(),
-            protocol_id: 0,
-        },
+        // We dont have a token yet, client auth plugin grabs one from our token service right before connecting
+        auth: Authentication::None,
         // The IoConfig will specify the transport to use.
         io: IoConfig {
             // the address specified here is the client_address, because we open a UDP socket on the client
-            transport: ClientTransport::UdpSocket(client_addr),
+            transport: ClientTransport::UdpSocket(network.client_addr()),
             ..default()
         },
         // We can use either Steam (in which case we will use steam sockets and there is no need to specify
@@ -134,11 +157,6 @@ fn build_client_plugin(client_id: &u64) -> ClientPlugins {
     ClientPlugins::new(config)
 }
 
-/// Connect to the server
-fn connect_client(mut commands: Commands) {
-    commands.connect_client();
-}
-
 ///  When our app is closed we send a disconnect even to server
 fn on_app_exit_disconnect(
     trigger: Trigger<OnAdd, ClosingWindow>,
@@ -163,3 +181,15 @@ fn form_easy_client(mut connect_event: EventReader<ConnectEvent>, mut commands:
         })
     }
 }
+
+/// Whenever server confirms a change in our core information we store his version of it
+fn receive_own_core_information(
+    mut core_messages: EventReader<MessageEvent<CoreInformationMessage>>,
+    mut commands: Commands,
+) {
+    for event in core_messages.read() {
+        commands.insert_resource(ClientCoreInformation {
+            core: event.message().core.clone(),
+        });
+    }
+}
@@ -1,14 +1,17 @@
 use super::{
-    egui::ChangeCharEvent,
+    egui::{ChangeCharEvent, Parts},
+    keybinds::CoreKeybinds,
     load_assets::GltfCollection,
     protocol::{PlayerId, PlayerMarker, PlayerVisuals},
-    ClientAppState,
+    ClientAppState, CoreEasyClient,
 };
+use crate::shared::combat::update_melee;
+use crate::shared::movement::move_players;
 use crate::shared::protocol::*;
+use crate::shared::skeleton::{MAIN_HAND_BONE, OFF_HAND_BONE, OFF_HAND_GRIP};
 use crate::shared::CommonChannel;
 use bevy::animation::AnimationTarget;
 use bevy::{prelude::*, utils::HashMap};
-use leafwing_input_manager::prelude::*;
 use lightyear::{client::prediction::Predicted, shared::events::components::MessageEvent};
 use lightyear::{prelude::*, shared::replication::components::Controlled};
 use std::collections::VecDeque;
@@ -31,6 +34,24 @@ struct ClientIdPlayerMap {
 struct BodyPartMap {
     pub map: HashMap<(ClientId, String), Entity>,
 }
+/// Same idea as body part map but for weapons, keyed by slot as both hands may hold the same gltf
+#[derive(Resource, Default, Reflect)]
+#[reflect(Resource)]
+struct WeaponMap {
+    pub map: HashMap<(ClientId, Parts), Entity>,
+}
+
+/// Marks a weapon scene waiting for his hand bone, skeleton bones take a while to spawn. See attach_weapons_to_hands
+#[derive(Component)]
+struct WeaponSocket {
+    /// Player that wields it
+    player: Entity,
+    /// Name of the bone to parent into
+    bone: &'static str,
+    /// How the weapon turns inside that bone, see OFF_HAND_GRIP
+    grip: Quat,
+}
+
 /// Event send everytime we spawn a visual scene
 #[derive(Event)]
 struct TranferAnim {
@@ -51,6 +72,7 @@ impl Plugin for ClientPlayerPlugin {
         // Init resources
         app.init_resource::<ClientIdPlayerMap>();
         app.init_resource::<BodyPartMap>();
+        app.init_resource::<WeaponMap>();
 
         // Init events
         app.add_event::<TranferAnim>();
@@ -68,9 +90,18 @@ impl Plugin for ClientPlayerPlugin {
         // In update because observer tends to be unstable (adds component in a disorderly fashion therefore it doesnt run sometimes)
         app.add_systems(Update, customize_player_on_other_clients);
 
+        // In update because it listens to server messages
+        app.add_systems(Update, rollback_rejected_customization);
+
+        // In update because it listens to server messages, a whole loadout respawn at once
+        app.add_systems(Update, respawn_applied_loadout);
+
         // In post update because observer are too fast paced - And because we want all bones to be spawned. Which takes a while
         app.add_systems(PostUpdate, transfer_anim_info);
 
+        // In post update for the same reason, weapons wait for the skeleton hand bones
+        app.add_systems(PostUpdate, attach_weapons_to_hands);
+
         // In update because reset anim requires apply deferred, but doesnt care about schedule
         app.add_systems(Update, reset_anim);
 
@@ -80,12 +111,15 @@ impl Plugin for ClientPlayerPlugin {
         // In update because it is added component based
         app.add_systems(Update, add_animation_player_to_player);
 
-        // Fixed update because input systems should be frame unrelated
-        app.add_systems(FixedUpdate, move_player);
+        // Fixed update because input systems should be frame unrelated, same function server simulates with
+        app.add_systems(FixedUpdate, move_players::<With<Predicted>>);
+        // Swings and stamina are predicted too, only server decides hits
+        app.add_systems(FixedUpdate, update_melee::<With<Predicted>>);
 
         // Debug
         app.register_type::<ClientIdPlayerMap>();
         app.register_type::<BodyPartMap>();
+        app.register_type::<WeaponMap>();
     }
 }
 
@@ -108,6 +142,7 @@ fn fill_client_id_map(
 fn render_predicted_player(
     player: Query<(Entity, &PlayerId, &PlayerVisuals), Added<Predicted>>,
     mut body_part_map: ResMut<BodyPartMap>,
+    mut weapon_map: ResMut<WeaponMap>,
     gltf_collection: Res<GltfCollection>,
     gltfs: Res<Assets<Gltf>>,
     mut transfer_anim_writer: EventWriter<TranferAnim>,
@@ -128,10 +163,22 @@ fn render_predicted_player(
                     .insert((player_id.id, item.file_path.clone()), entity);
             }
             transfer_anim_writer.send(TranferAnim {
-                id: player_id.id.clone(),
+                id: player_id.id,
                 part_name: item.name.to_string(),
             });
         }
+        for (part, item) in player_visuals.iter_weapons() {
+            equip_weapon(
+                &player_id.id,
+                &part,
+                Some(item),
+                &parent,
+                &mut weapon_map,
+                &gltf_collection,
+                &gltfs,
+                &mut commands,
+            );
+        }
     }
 }
 
@@ -179,7 +226,7 @@ fn add_animation_player_to_player(
 /// -> THe function customize_local_player shall consume this local event and do all the actions necessary to make the customization occurs
 /// -> After that we send a message to server, a save message, server shall validate it
 /// -> If he okays, he will change the confirmed entity, and propagate the save message to the other clients
-/// -> If not he will tell that client, he can test it but when he leaves he will enter a rollback state where we reverse him.
+/// -> If not he sends a visual change rejected message, we despawn the preview and respawn the confirmed part. See rollback_rejected_customization
 /// -> Why save message? Well because indepently of what happens we will have to save the entire save, might as well make that clear.
 /// -> Why like this? Well to ensure no visual hacks and also to let player test out visuals he doesnt have access to.
 /// -> Why predicted player? Well because we solely want to change predicted entities via client, confirmed are the ones altered by server!
@@ -188,6 +235,7 @@ fn customize_local_player(
     player_visuals: Query<&mut PlayerVisuals, With<Predicted>>,
     player_map: Res<ClientIdPlayerMap>,
     mut body_part_map: ResMut<BodyPartMap>,
+    mut weapon_map: ResMut<WeaponMap>,
     gltf_collection: Res<GltfCollection>,
     gltfs: Res<Assets<Gltf>>,
     mut connection_manager: ResMut<ClientConnectionManager>,
@@ -206,41 +254,52 @@ fn customize_local_player(
             .get(*player_entity)
             .expect("Player to be online and to have visual component");
 
-        // Determine the current part
+        // Determine the current part, weapon slots may be empty
         let current_item = player_visual.get_visual(body_part);
-        let curr_file_path = &current_item.file_path;
+        let curr_file_path = current_item.map(|item| item.file_path.clone());
         let new_file_path = &new_item.file_path;
-        if curr_file_path != new_file_path {
-            customize_player(
-                client_id,
-                new_item,
-                curr_file_path,
-                new_file_path,
-                &player_entity,
-                &mut body_part_map,
-                &gltf_collection,
-                &gltfs,
-                &mut commands,
-            );
+        if curr_file_path.as_ref() != Some(new_file_path) {
+            if body_part.is_weapon() {
+                equip_weapon(
+                    client_id,
+                    body_part,
+                    Some(new_item),
+                    player_entity,
+                    &mut weapon_map,
+                    &gltf_collection,
+                    &gltfs,
+                    &mut commands,
+                );
+            } else {
+                customize_player(
+                    client_id,
+                    new_item,
+                    &curr_file_path.unwrap_or_default(),
+                    new_file_path,
+                    player_entity,
+                    &mut body_part_map,
+                    &gltf_collection,
+                    &gltfs,
+                    &mut commands,
+                );
+                transfer_anim_writer.send(TranferAnim {
+                    id: *client_id,
+                    part_name: new_item.name.to_string(),
+                });
+            }
             if connection_manager
                 .send_message::<CommonChannel, SaveMessage>(&mut SaveMessage {
                     id: *client_id,
                     change_char: Some(event.clone()),
-                    change_currency: None,
-                    change_inventory: None,
                 })
                 .is_err()
             {
                 warn!("Failed to send save to server!")
             }
-            transfer_anim_writer.send(TranferAnim {
-                id: client_id.clone(),
-                part_name: new_item.name.to_string(),
-            });
         } else {
             info!(
                 "Visual item '{}' for client {} is already current; no changes made",
-                curr_file_path, client_id
+                new_file_path, client_id
             );
         }
     } else {
@@ -255,6 +314,7 @@ fn customize_player_on_other_clients(
     player_visuals: Query<&mut PlayerVisuals, With<Predicted>>,
     player_map: Res<ClientIdPlayerMap>,
     mut body_part_map: ResMut<BodyPartMap>,
+    mut weapon_map: ResMut<WeaponMap>,
     opt_gltf_collection: Option<Res<GltfCollection>>,
     gltfs: Res<Assets<Gltf>>,
     mut transfer_anim_writer: EventWriter<TranferAnim>,
@@ -275,23 +335,38 @@ fn customize_player_on_other_clients(
                 .expect("Player to be online and to have visual component");
 
             let current_item = player_visual.get_visual(body_part);
-            let curr_file_path = &current_item.file_path;
+            let curr_file_path = current_item
+                .map(|item| item.file_path.clone())
+                .unwrap_or_default();
             let new_file_path = &new_item.file_path;
 
             if let Some(gltf_collection) = &opt_gltf_collection {
+                if body_part.is_weapon() {
+                    equip_weapon(
+                        client_id,
+                        body_part,
+                        Some(new_item),
+                        player_entity,
+                        &mut weapon_map,
+                        gltf_collection,
+                        &gltfs,
+                        &mut commands,
+                    );
+                    continue;
+                }
                 customize_player(
                     client_id,
                     new_item,
-                    curr_file_path,
+                    &curr_file_path,
                     new_file_path,
-                    &player_entity,
+                    player_entity,
                     &mut body_part_map,
-                    &gltf_collection,
+                    gltf_collection,
                     &gltfs,
                     &mut commands,
                 );
                 transfer_anim_writer.send(TranferAnim {
-                    id: client_id.clone(),
+                    id: *client_id,
                     part_name: new_item.name.to_string(),
                 });
             } else {
@@ -301,6 +376,157 @@ fn customize_player_on_other_clients(
     }
 }
 
+/// Server refused our preview, most likely because we dont own that item. So we go back to what server confirmed
+/// -> First - Despawn the preview scene
+/// -> Second - Respawn the confirmed part of our player visuals
+/// -> Third - Transfer animation targets to the respawned part, otherwise he would be stuck in T pose
+fn rollback_rejected_customization(
+    mut rejections: EventReader<MessageEvent<VisualChangeRejected>>,
+    easy_client: Option<Res<CoreEasyClient>>,
+    player_map: Res<ClientIdPlayerMap>,
+    mut body_part_map: ResMut<BodyPartMap>,
+    mut weapon_map: ResMut<WeaponMap>,
+    opt_gltf_collection: Option<Res<GltfCollection>>,
+    gltfs: Res<Assets<Gltf>>,
+    mut transfer_anim_writer: EventWriter<TranferAnim>,
+    mut commands: Commands,
+) {
+    for event in rejections.read() {
+        let rejection = event.message();
+        warn!(
+            "Server refused our {:?} change, rolling back. Reason {}",
+            rejection.body_part, rejection.reason
+        );
+
+        let (Some(easy_client), Some(gltf_collection)) = (&easy_client, &opt_gltf_collection)
+        else {
+            warn!("This client is most probably in a loading state");
+            continue;
+        };
+        let client_id = easy_client.client_id;
+        let Some(player_entity) = player_map.map.get(&client_id) else {
+            warn!("Couldnt find predicted player to rollback");
+            continue;
+        };
+
+        // Weapon slots may go back to empty hands
+        if rejection.body_part.is_weapon() {
+            equip_weapon(
+                &client_id,
+                &rejection.body_part,
+                rejection.confirmed.as_ref(),
+                player_entity,
+                &mut weapon_map,
+                gltf_collection,
+                &gltfs,
+                &mut commands,
+            );
+            continue;
+        }
+
+        let Some(confirmed) = &rejection.confirmed else {
+            warn!("Server confirmed an empty {:?}", rejection.body_part);
+            continue;
+        };
+        if rejection.rejected.file_path == confirmed.file_path {
+            continue;
+        }
+
+        customize_player(
+            &client_id,
+            confirmed,
+            &rejection.rejected.file_path,
+            &confirmed.file_path,
+            player_entity,
+            &mut body_part_map,
+            gltf_collection,
+            &gltfs,
+            &mut commands,
+        );
+        transfer_anim_writer.send(TranferAnim {
+            id: client_id,
+            part_name: confirmed.name.to_string(),
+        });
+    }
+}
+
+/// Server okayed a whole loadout, we respawn every changed scene in one go. Works the same for us and for other clients
+/// -> First - Respawn changed body parts, if the skeleton changed every part needs his animation targets again
+/// -> Second - Swap weapons, they go to the hand bones of the skeleton
+fn respawn_applied_loadout(
+    mut applied_loadouts: EventReader<MessageEvent<LoadoutApplied>>,
+    player_map: Res<ClientIdPlayerMap>,
+    mut body_part_map: ResMut<BodyPartMap>,
+    mut weapon_map: ResMut<WeaponMap>,
+    opt_gltf_collection: Option<Res<GltfCollection>>,
+    gltfs: Res<Assets<Gltf>>,
+    mut transfer_anim_writer: EventWriter<TranferAnim>,
+    mut commands: Commands,
+) {
+    for event in applied_loadouts.read() {
+        let applied = event.message();
+        let client_id = applied.id;
+        let Some(gltf_collection) = &opt_gltf_collection else {
+            warn!("This client is most probably in a loading state");
+            continue;
+        };
+        let Some(player_entity) = player_map.map.get(&client_id) else {
+            warn!("Couldnt find player {} to apply his loadout", client_id);
+            continue;
+        };
+        info!("Applying loadout of {}", client_id);
+
+        let skeleton_changed =
+            applied.previous.skeleton.file_path != applied.current.skeleton.file_path;
+        let body = applied
+            .previous
+            .iter_visuals()
+            .zip(applied.current.iter_visuals());
+        for (previous, current) in body {
+            let changed = previous.file_path != current.file_path;
+            if changed {
+                customize_player(
+                    &client_id,
+                    current,
+                    &previous.file_path,
+                    &current.file_path,
+                    player_entity,
+                    &mut body_part_map,
+                    gltf_collection,
+                    &gltfs,
+                    &mut commands,
+                );
+            }
+            // Skeleton already carries our animations
+            if (changed || skeleton_changed) && current.item_type != ItemType::Skeleton {
+                transfer_anim_writer.send(TranferAnim {
+                    id: client_id,
+                    part_name: current.name.to_string(),
+                });
+            }
+        }
+
+        for part in [Parts::MainHand, Parts::OffHand] {
+            let previous = applied.previous.get_visual(&part);
+            let current = applied.current.get_visual(&part);
+            if previous.map(|item| &item.file_path) != current.map(|item| &item.file_path)
+                || skeleton_changed
+            {
+                equip_weapon(
+                    &client_id,
+                    &part,
+                    current,
+                    player_entity,
+                    &mut weapon_map,
+                    gltf_collection,
+                    &gltfs,
+                    &mut commands,
+                );
+            }
+        }
+    }
+}
+
 fn customize_player(
     client_id: &ClientId,
     new_item: &Item,
@@ -310,7 +536,7 @@ fn customize_player(
     body_part_map: &mut ResMut<BodyPartMap>,
     gltf_collection: &Res<GltfCollection>,
     gltfs: &Res<Assets<Gltf>>,
-    mut commands: &mut Commands,
+    commands: &mut Commands,
 ) {
     // Removing old part from the map
     let key = (*client_id, curr_file_path.to_string());
@@ -320,7 +546,7 @@ fn customize_player(
     }
 
     // Spawn new visual scene and insert into the map
-    if let Some(id) = spawn_visual_scene(&new_item, &gltf_collection, &gltfs, &mut commands) {
+    if let Some(id) = spawn_visual_scene(new_item, gltf_collection, gltfs, commands) {
         info!("Spawning new visual scene for {}", client_id);
         body_part_map
             .map
@@ -333,6 +559,67 @@ fn customize_player(
     }
 }
 
+/// Callable function - Swaps whatever is in that weapon slot for the new weapon. None leaves the hand empty
+/// Weapon is not parented right away, it waits in a socket until the hand bone exists
+fn equip_weapon(
+    client_id: &ClientId,
+    part: &Parts,
+    new_item: Option<&Item>,
+    player_ent: &Entity,
+    weapon_map: &mut ResMut<WeaponMap>,
+    gltf_collection: &Res<GltfCollection>,
+    gltfs: &Res<Assets<Gltf>>,
+    commands: &mut Commands,
+) {
+    // Removing old weapon from the map
+    if let Some(entity) = weapon_map.map.remove(&(*client_id, part.clone())) {
+        info!("Removing old weapon from entity {}", entity);
+        commands.entity(entity).despawn_recursive();
+    }
+
+    let Some(new_item) = new_item else {
+        return;
+    };
+    let (bone, grip) = match part {
+        Parts::OffHand => (OFF_HAND_BONE, OFF_HAND_GRIP),
+        _ => (MAIN_HAND_BONE, Quat::IDENTITY),
+    };
+    if let Some(id) = spawn_visual_scene(new_item, gltf_collection, gltfs, commands) {
+        info!("Spawning {} in {:?} for {}", new_item, part, client_id);
+        commands.entity(id).insert(WeaponSocket {
+            player: *player_ent,
+            bone: bone,
+            grip: grip,
+        });
+        weapon_map.map.insert((*client_id, part.clone()), id);
+    }
+}
+
+/// Parents waiting weapons to their hand bone, only looks inside the skeleton as visual parts carry bones with the same names
+fn attach_weapons_to_hands(
+    sockets: Query<(Entity, &WeaponSocket)>,
+    children: Query<&Children>,
+    names: Query<&Name>,
+    mut commands: Commands,
+) {
+    for (weapon, socket) in sockets.iter() {
+        let Some(skeleton) =
+            find_child_with_name_containing(&children, &names, &socket.player, "skeleton")
+        else {
+            continue;
+        };
+        let Some(hand) = find_child_with_name_containing(&children, &names, &skeleton, socket.bone)
+        else {
+            continue;
+        };
+        commands
+            .entity(weapon)
+            .set_parent(hand)
+            .insert(Transform::from_rotation(socket.grip))
+            .remove::<WeaponSocket>();
+    }
+}
+
 /// Transfer anim target ids, from each main skeleton bone to visual bone
 /// Warning this expects - THAT EVERY SINGLE VISUAL SCENE has a root bone
 fn transfer_anim_info(
@@ -370,7 +657,7 @@ fn transfer_anim_info(
 
         // Part that needs to have it is children targets killed
         let new_part =
-            find_child_with_name_containing(&children, &names, player_ent, &part_name).unwrap();
+            find_child_with_name_containing(&children, &names, player_ent, part_name).unwrap();
 
         // Carrier of anim player should be named root by default
         let new_root =
@@ -444,7 +731,7 @@ fn find_child_with_name_containing(
         }
     }
 
-    return None;
+    None
 }
 
 /// Receives the reset animation event reset all of the child animation player
@@ -468,40 +755,15 @@ fn reset_anim(
     }
 }
 
-/// Whenever we get a predicted entity that is controlled we add the input map unto it
+/// Whenever we get a predicted entity that is controlled we add the input map unto it, built from our own keybinds
 fn insert_input_map(
     query: Query<(Entity, Has<Controlled>), Added<Predicted>>,
+    keybinds: Res<CoreKeybinds>,
     mut commands: Commands,
 ) {
     for (entity, is_controlled) in query.iter() {
         if is_controlled {
-            commands
-                .entity(entity)
-                .insert(PlayerActions::default_input_map());
-        }
-    }
-}
-
-/// Move player solely moves the predicted controlled player, later server will also give you the true position
-fn move_player(
-    mut player_action: Query<(&ActionState<PlayerActions>, &mut Transform), With<Predicted>>,
-) {
-    for (player_action, mut transform) in player_action.iter_mut() {
-        // You know only act when we actually have something to do
-        if !player_action.get_pressed().is_empty() {
-            // Make this shared
-            if player_action.pressed(&PlayerActions::Forward) {
-                transform.translation += Vec3::new(0.0, 0.0, 0.1);
-            }
-            if player_action.pressed(&PlayerActions::Backward) {
-                transform.translation -= Vec3::new(0.0, 0.0, 0.1);
-            }
-            if player_action.pressed(&PlayerActions::Left) {
-                transform.translation += Vec3::new(0.1, 0.0, 0.0);
-            }
-            if player_action.pressed(&PlayerActions::Right) {
-                transform.translation -= Vec3::new(0.1, 0.0, 0.0);
-            }
+            commands.entity(entity).insert(keybinds.input_map());
         }
     }
 }
@@ -1,7 +1,11 @@
 use bevy::prelude::*;
 use clap::Parser;
+use client::auth::ClientCredentials;
 use client::CoreClientPlugin;
+use server::auth::ServerAuthPlugin;
+use server::save_cli::{run_save_command, SaveCommand};
 use server::CoreServerPlugin;
+use shared::config::{ConfigArgs, CoreConfig};
 
 mod client;
 mod server;
@@ -12,11 +16,42 @@ mod shared;
 #[derive(Parser, PartialEq, Debug)]
 pub enum Cli {
     /// The program will act as server
-    Server,
+    Server {
+        /// Boots without window, renderer or egui. Ideal for dedicated servers, CI and GPU-less machines
+        #[arg(long, default_value_t = false)]
+        headless: bool,
+        /// Bind address, port, protocol id and config file
+        #[command(flatten)]
+        config: ConfigArgs,
+    },
+    /// The program will act as a standalone token service, handing out connect tokens for our server
+    AuthServer {
+        /// Token service address, protocol id and config file
+        #[command(flatten)]
+        config: ConfigArgs,
+    },
     /// The program will act as a client
     Client {
-        #[arg(short, long, default_value = None)]
-        client_id: Option<u64>,
+        /// Account username
+        #[arg(short, long)]
+        username: String,
+        /// Account password, sent in plain text to the token service
+        #[arg(short, long)]
+        password: String,
+        /// Creates the account on first login, refused if the username is already taken
+        #[arg(long, default_value_t = false)]
+        register: bool,
+        /// Connect address, port, protocol id and config file
+        #[command(flatten)]
+        config: ConfigArgs,
+    },
+    /// Offline inspection and editing of our save, no bevy app is started
+    Save {
+        #[command(subcommand)]
+        command: SaveCommand,
+        /// Save backend and config file
+        #[command(flatten)]
+        config: ConfigArgs,
     },
 }
 
@@ -30,13 +65,34 @@ fn main() {
     // Meaning we wont have host client, and server-client types.
     match cli {
         //The program will act as a server
-        Cli::Server => app.add_plugins(CoreServerPlugin),
+        Cli::Server { headless, config } => app.add_plugins(CoreServerPlugin {
+            headless: headless,
+            config: CoreConfig::load(&config),
+        }),
+        //The program will solely hand out connect tokens, no window and no game simulation
+        Cli::AuthServer { config } => app
+            .add_plugins(MinimalPlugins)
+            .add_plugins(bevy::log::LogPlugin::default())
+            .insert_resource(CoreConfig::load(&config))
+            .add_plugins(ServerAuthPlugin),
         //The program will act as a client
-        Cli::Client { client_id } => {
-            let client_id = client_id.unwrap_or(0);
-            app.add_plugins(CoreClientPlugin {
-                client_id: client_id,
-            })
+        Cli::Client {
+            username,
+            password,
+            register,
+            config,
+        } => app.add_plugins(CoreClientPlugin {
+            credentials: ClientCredentials {
+                username: username,
+                password: password,
+                register: register,
+            },
+            config: CoreConfig::load(&config),
+        }),
+        //The program will edit or print our save and then leave
+        Cli::Save { command, config } => {
+            run_save_command(command, &CoreConfig::load(&config));
+            return;
         }
     };
 
@@ -0,0 +1,318 @@
+use crate::shared::config::CoreConfig;
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use bevy::prelude::*;
+use bevy::tasks::{block_on, poll_once, IoTaskPool, Task};
+use bevy::utils::HashMap;
+use lightyear::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+use super::storage::{read_with_recovery, write_atomically};
+
+/// Where we store our accounts
+const ACCOUNTS_FILE_PATH: &str = "./psycho_duel/src/server/save_files/accounts.bar";
+
+/// Previous generations of our accounts file we keep around, not configurable as losing accounts orphans every save
+const ACCOUNTS_BACKUPS: usize = 3;
+
+/// Everything we know about an account, worth noting the player uid is what identifies a player. Client id is just a transport thing
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Account {
+    /// Unique name used to login
+    pub username: String,
+    /// Argon2 hashed password, we never store plain passwords
+    pub password_hash: String,
+    /// Seconds since unix epoch of when this account was created
+    pub created_at: u64,
+    /// Stable internal id, our save map is keyed by him
+    pub player_uid: Uuid,
+}
+
+/// Registry of every account and of which client id is currently assigned to which player.
+/// Persisted locally via bincode, only our token service changes it
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct AccountRegistry {
+    /// Username to account
+    accounts: HashMap<String, Account>,
+    /// Last client id handed out to each player, a token is only valid for that specific client id
+    sessions: HashMap<ClientId, Uuid>,
+}
+
+/// Plugin responsible for telling our game server which player owns which client id.
+/// Game server never touches accounts, he only hears about sessions. Either straight from our in process token service
+/// or, when it runs via the auth-server subcommand, by reading the accounts file it writes
+pub struct ServerAccountPlugin;
+
+impl Plugin for ServerAccountPlugin {
+    fn build(&self, app: &mut App) {
+        // Pre initialized empty, sessions from previous runs are loaded on startup
+        app.init_resource::<CoreAccountRegistry>();
+
+        // Startup because sessions handed out before a restart should still be known
+        app.add_systems(Startup, load_sessions);
+
+        // PreUpdate so sessions are already known when connect events get handled in update
+        app.add_systems(PreUpdate, receive_sessions);
+    }
+}
+
+/// Game side view of our accounts, a plain map of client id to player uid. Login threads never lock it,
+/// they push new sessions into our channel and we drain it once per frame
+#[derive(Resource)]
+pub struct CoreAccountRegistry {
+    /// Client id session to the player that owns it
+    sessions: HashMap<ClientId, Uuid>,
+    /// Cloned into our in process token service, every successful login goes through here
+    sender: Sender<(ClientId, Uuid)>,
+    /// Receiving side of our sessions, mutex only because receivers are not sync. Only our drain system locks it
+    receiver: Mutex<Receiver<(ClientId, Uuid)>>,
+    /// Background read of the accounts file, happens when a client we dont know connects and token service runs on another process
+    reloading: Option<Task<Result<AccountRegistry, String>>>,
+    /// A client we dont know connected, our sessions must be read again from the accounts file
+    stale: bool,
+}
+
+impl Default for CoreAccountRegistry {
+    fn default() -> Self {
+        let (sender, receiver) = channel();
+        Self {
+            sessions: HashMap::default(),
+            sender: sender,
+            receiver: Mutex::new(receiver),
+            reloading: None,
+            stale: false,
+        }
+    }
+}
+
+impl CoreAccountRegistry {
+    /// Callable function - Tell me the player uid that owns that client id session
+    pub fn player_of(&self, client_id: &ClientId) -> Option<Uuid> {
+        self.sessions.get(client_id).copied()
+    }
+
+    /// Callable function - Where our in process token service pushes every session it hands out
+    pub fn session_sender(&self) -> Sender<(ClientId, Uuid)> {
+        self.sender.clone()
+    }
+}
+
+/// Grabs the sessions handed out before our server booted
+fn load_sessions(mut accounts: ResMut<CoreAccountRegistry>) {
+    match AccountRegistry::read() {
+        Ok(registry) => {
+            info!("Loaded {} account sessions", registry.sessions.len());
+            accounts.sessions = registry.sessions;
+        }
+        Err(err) => error!("Couldnt load account sessions {}", err),
+    }
+}
+
+/// Drains sessions sent by our token service. When the token service is another process, a connecting client we dont know
+/// makes us read the accounts file in the background, the token service always writes it before handing out the token
+fn receive_sessions(
+    mut accounts: ResMut<CoreAccountRegistry>,
+    core_config: Res<CoreConfig>,
+    mut connections: EventReader<ServerConnectEvent>,
+) {
+    let received: Vec<(ClientId, Uuid)> = accounts
+        .receiver
+        .lock()
+        .expect("Session receiver lock to not be poisoned")
+        .try_iter()
+        .collect();
+    for (client_id, player_uid) in received {
+        // Old sessions of this player are no longer valid
+        accounts.sessions.retain(|_, uid| *uid != player_uid);
+        accounts.sessions.insert(client_id, player_uid);
+    }
+
+    if let Some(task) = accounts.reloading.as_mut() {
+        if let Some(result) = block_on(poll_once(task)) {
+            accounts.reloading = None;
+            match result {
+                Ok(registry) => accounts.sessions = registry.sessions,
+                Err(err) => error!("Couldnt reload account sessions {}", err),
+            }
+        }
+    }
+
+    for event in connections.read() {
+        if accounts.player_of(&event.client_id).is_none() && !core_config.auth.in_process {
+            accounts.stale = true;
+        }
+    }
+    // One read at a time, if he started before that client logged in we read again once he is done
+    if accounts.stale && accounts.reloading.is_none() {
+        accounts.stale = false;
+        accounts.reloading = Some(IoTaskPool::get().spawn(async { AccountRegistry::read() }));
+    }
+}
+
+/// Auth side handle of our accounts, cloned into every login thread
+#[derive(Clone)]
+pub struct SharedAccounts {
+    registry: Arc<Mutex<AccountRegistry>>,
+    /// Held while writing our file, so an older snapshot never lands on disk after a newer one
+    writer: Arc<Mutex<()>>,
+}
+
+impl SharedAccounts {
+    /// Reads our accounts, panics if neither the file nor any backup is readable. Starting empty would orphan every save
+    pub fn load() -> Self {
+        let registry = AccountRegistry::read().unwrap_or_else(|err| {
+            panic!(
+                "Failed to load accounts, fix it before booting the token service again. Error type {}",
+                err
+            )
+        });
+        info!("Loaded {} accounts", registry.accounts.len());
+        Self {
+            registry: Arc::new(Mutex::new(registry)),
+            writer: Arc::new(Mutex::new(())),
+        }
+    }
+
+    /// Callable function - Grabs our registry, only hold it for quick map operations
+    fn lock(&self) -> MutexGuard<'_, AccountRegistry> {
+        self.registry
+            .lock()
+            .expect("Account registry lock to not be poisoned")
+    }
+
+    /// Validates username and password, or registers the account when asked to.
+    /// If everything is okay hands out a fresh netcode client id for that player session.
+    /// Worth noting hashing and writing are slow, so they happen outside our lock
+    /// -> First - Snapshot the account under the lock
+    /// -> Second - Verify or hash the password without holding anything
+    /// -> Third - Take the lock again just to swap in the account and the session
+    /// -> Fourth - Write the file, a login only succeeds once it is on disk as the game server might be reading it
+    pub fn login(
+        &self,
+        username: &str,
+        password: &str,
+        register: bool,
+    ) -> Result<(u64, Uuid), String> {
+        if username.is_empty() || password.is_empty() {
+            return Err("Username and password cant be empty".to_string());
+        }
+
+        let existing = self.lock().accounts.get(username).cloned();
+
+        let account = match existing {
+            Some(_) if register => {
+                return Err(format!("Username {} is already taken", username));
+            }
+            None if !register => {
+                warn!("Login of unknown account {}", username);
+                return Err("Wrong username or password".to_string());
+            }
+            Some(account) => {
+                if !verify_password(password, &account.password_hash) {
+                    warn!("Wrong password for account {}", username);
+                    return Err("Wrong username or password".to_string());
+                }
+                account
+            }
+            None => Account {
+                username: username.to_string(),
+                password_hash: hash_password(password),
+                created_at: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|since| since.as_secs())
+                    .unwrap_or_default(),
+                player_uid: Uuid::new_v4(),
+            },
+        };
+        let player_uid = account.player_uid;
+
+        let netcode_id = {
+            let mut registry = self.lock();
+            if register {
+                // Someone registered that same username while we were hashing
+                if registry.accounts.contains_key(username) {
+                    return Err(format!("Username {} is already taken", username));
+                }
+                info!("Registering new account {}", username);
+                registry.accounts.insert(username.to_string(), account);
+            }
+            // Old sessions of this player are no longer valid
+            registry.sessions.retain(|_, uid| *uid != player_uid);
+            let netcode_id = new_netcode_id(&registry.sessions);
+            registry
+                .sessions
+                .insert(ClientId::Netcode(netcode_id), player_uid);
+            netcode_id
+        };
+
+        self.save().map_err(|err| {
+            error!("Couldnt store accounts {}", err);
+            "Couldnt store your session, try again".to_string()
+        })?;
+        Ok((netcode_id, player_uid))
+    }
+
+    /// Writes the latest state of our registry. Encoding happens after grabbing the writer,
+    /// so whoever writes last always writes the newest accounts
+    fn save(&self) -> Result<(), String> {
+        let _writer = self
+            .writer
+            .lock()
+            .expect("Accounts writer lock to not be poisoned");
+        let bytes = bincode::serialize(&*self.lock()).map_err(|err| err.to_string())?;
+        write_atomically(Path::new(ACCOUNTS_FILE_PATH), &bytes, ACCOUNTS_BACKUPS)
+    }
+}
+
+impl AccountRegistry {
+    /// Reads accounts file recovering from backups if needed, if none exist we start with no accounts
+    pub fn read() -> Result<Self, String> {
+        read_with_recovery(Path::new(ACCOUNTS_FILE_PATH), ACCOUNTS_BACKUPS, |bytes| {
+            bincode::deserialize(bytes).map_err(|err| err.to_string())
+        })
+        .map(Option::unwrap_or_default)
+    }
+
+    /// Grab an account by his username
+    pub fn account(&self, username: &str) -> Option<&Account> {
+        self.accounts.get(username)
+    }
+}
+
+/// Random non zero netcode id that isnt currently in use
+fn new_netcode_id(sessions: &HashMap<ClientId, Uuid>) -> u64 {
+    loop {
+        let netcode_id = Uuid::new_v4().as_u64_pair().0;
+        if netcode_id != 0 && !sessions.contains_key(&ClientId::Netcode(netcode_id)) {
+            return netcode_id;
+        }
+    }
+}
+
+/// Hashes a password with a random salt
+fn hash_password(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .expect("To be able to hash password")
+        .to_string()
+}
+
+/// Checks if password corresponds to the stored hash
+fn verify_password(password: &str, password_hash: &str) -> bool {
+    match PasswordHash::new(password_hash) {
+        Ok(parsed) => Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok(),
+        Err(err) => {
+            error!("Stored password hash is malformed {}", err);
+            false
+        }
+    }
+}
@@ -0,0 +1,312 @@
+use crate::shared::config::CoreConfig;
+use crate::shared::protocol::{login_codec, LoginRequest, LoginResponse, MAX_LOGIN_MESSAGE_BYTES};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use bevy::prelude::*;
+use bincode::Options;
+use lightyear::connection::netcode::ConnectToken;
+use lightyear::prelude::ClientId;
+use lightyear::prelude::Key;
+use std::fs;
+use std::io::{self, ErrorKind, Read};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use super::account::{CoreAccountRegistry, SharedAccounts};
+
+/// Whole time a login connection gets, from accepting it to writing our answer. A per read timeout alone isnt enough,
+/// a client trickling one byte every few seconds would hold his thread forever
+const AUTH_CONNECTION_DEADLINE: Duration = Duration::from_secs(10);
+
+/// Max logins answered at the same time, connections above that are dropped right away instead of spawning more threads
+const MAX_AUTH_CONNECTIONS: usize = 64;
+
+/// Plugin responsible for our token service, the guy that logs users in and hands out netcode connect tokens.
+/// Only the server (and the token service) knows the private key, so no process can claim a client id that is not his.
+/// Client ids are handed out per session, the account registry tells us which player owns them
+/// Can either run inside our game server or standalone via the auth-server subcommand.
+/// IMPORTANT - Requests travel as plain bincode over raw tcp, passwords included. Only expose it on localhost or behind a tls proxy
+pub struct ServerAuthPlugin;
+
+impl Plugin for ServerAuthPlugin {
+    fn build(&self, app: &mut App) {
+        // Startup because it should be available as soon as the server is
+        app.add_systems(Startup, start_auth_service);
+    }
+}
+
+/// The guy that actually issues connect tokens, worth noting he is not a bevy resource as he lives on his own thread
+struct TokenIssuer {
+    /// Key that signs our tokens, must be the same as the server one
+    private_key: Key,
+    /// Netcode protocol id, must be the same as the server one
+    protocol_id: u64,
+    /// Address of our game server, which gets written inside the token
+    game_server_addr: SocketAddr,
+    /// Every known account, only our login threads touch it
+    accounts: SharedAccounts,
+    /// When running in process, every session we hand out is pushed to our game server through here
+    sessions: Option<Sender<(ClientId, Uuid)>>,
+}
+
+impl TokenIssuer {
+    /// Forms our issuer from config
+    fn new(core_config: &CoreConfig, sessions: Option<Sender<(ClientId, Uuid)>>) -> Self {
+        Self {
+            private_key: load_or_create_private_key(&core_config.auth.private_key_path),
+            protocol_id: core_config.network.protocol_id,
+            game_server_addr: core_config.network.server_addr(),
+            accounts: SharedAccounts::load(),
+            sessions: sessions,
+        }
+    }
+
+    /// Validates a login via our account registry, if everything is okay generates a token for the given session client id
+    fn login(&self, request: &LoginRequest) -> LoginResponse {
+        let login = self
+            .accounts
+            .login(&request.username, &request.password, request.register);
+
+        let netcode_id = match login {
+            Ok((netcode_id, player_uid)) => {
+                // Sent before the token exists, so our game server knows him by the time he connects
+                if let Some(sessions) = &self.sessions {
+                    if sessions
+                        .send((ClientId::Netcode(netcode_id), player_uid))
+                        .is_err()
+                    {
+                        return LoginResponse::Refused("Game server is not running".to_string());
+                    }
+                }
+                netcode_id
+            }
+            Err(reason) => return LoginResponse::Refused(reason),
+        };
+
+        match ConnectToken::build(
+            self.game_server_addr,
+            self.protocol_id,
+            netcode_id,
+            self.private_key,
+        )
+        .generate()
+        {
+            Ok(token) => match token.try_into_bytes() {
+                Ok(bytes) => LoginResponse::Token(bytes.to_vec()),
+                Err(err) => LoginResponse::Refused(format!("Couldnt serialize token {:?}", err)),
+            },
+            Err(err) => LoginResponse::Refused(format!("Couldnt generate token {:?}", err)),
+        }
+    }
+
+    /// Reads one login request from the stream and answer it. Gives up once our deadline passes or the request gets bigger than any login could be
+    fn handle_stream(&self, stream: TcpStream) {
+        let deadline = Instant::now() + AUTH_CONNECTION_DEADLINE;
+        let reader = DeadlineReader {
+            stream: &stream,
+            deadline: deadline,
+        };
+        let response = match login_codec()
+            .deserialize_from::<_, LoginRequest>(reader.take(MAX_LOGIN_MESSAGE_BYTES))
+        {
+            Ok(request) => self.login(&request),
+            Err(err) => LoginResponse::Refused(format!("Malformed login request {}", err)),
+        };
+        // Whatever is left of our deadline, password hashing may have eaten all of it
+        let left = deadline
+            .saturating_duration_since(Instant::now())
+            .max(Duration::from_millis(100));
+        if let Err(err) = stream.set_write_timeout(Some(left)) {
+            warn!("Couldnt set auth connection write timeout {}", err);
+            return;
+        }
+        if let Err(err) = login_codec().serialize_into(&stream, &response) {
+            warn!("Couldnt answer login request {}", err);
+        }
+    }
+}
+
+/// Reads from a login stream but never past his deadline, each read only waits for whatever time is left
+struct DeadlineReader<'a> {
+    stream: &'a TcpStream,
+    deadline: Instant,
+}
+
+impl Read for DeadlineReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = self.deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(io::Error::new(
+                ErrorKind::TimedOut,
+                "Login connection ran out of time",
+            ));
+        }
+        let mut stream = self.stream;
+        stream.set_read_timeout(Some(left))?;
+        // Unix reports a read timeout as would block, callers only care that we ran out of time
+        stream.read(buf).map_err(|err| match err.kind() {
+            ErrorKind::WouldBlock => {
+                io::Error::new(ErrorKind::TimedOut, "Login connection ran out of time")
+            }
+            _ => err,
+        })
+    }
+}
+
+/// One of our login slots, given back when dropped. Lives inside the login thread so even a panicking login frees it
+struct ConnectionSlot {
+    active: Arc<AtomicUsize>,
+}
+
+impl ConnectionSlot {
+    /// Grabs a slot, none if every one of them is taken
+    fn take(active: &Arc<AtomicUsize>) -> Option<Self> {
+        let slot = Self {
+            active: active.clone(),
+        };
+        if active.fetch_add(1, Ordering::SeqCst) >= MAX_AUTH_CONNECTIONS {
+            // Dropping it gives back what we just added
+            drop(slot);
+            return None;
+        }
+        Some(slot)
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Blocks the current thread accepting login connections forever. Each connection is answered on his own thread,
+/// so one slow client or one slow password hash doesnt make everybody else wait
+fn run_auth_service(issuer: TokenIssuer, auth_addr: SocketAddr) {
+    let listener = TcpListener::bind(auth_addr).expect("To be able to bind auth service address");
+    info!("Auth service listening on {}", auth_addr);
+    let issuer = Arc::new(issuer);
+    let active = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                warn!("Failed to accept auth connection {}", err);
+                continue;
+            }
+        };
+        let Some(slot) = ConnectionSlot::take(&active) else {
+            warn!("Too many logins at once, dropping connection");
+            continue;
+        };
+        let issuer = issuer.clone();
+        thread::spawn(move || {
+            let _slot = slot;
+            issuer.handle_stream(stream);
+        });
+    }
+}
+
+/// Runs the token service on it is own thread, as listening to tcp connections is blocking.
+/// Account registry only exists when our game server runs in this same process
+fn start_auth_service(core_config: Res<CoreConfig>, accounts: Option<Res<CoreAccountRegistry>>) {
+    let sessions = accounts.map(|accounts| accounts.session_sender());
+    let issuer = TokenIssuer::new(&core_config, sessions);
+    let auth_addr = core_config.auth.auth_addr();
+    thread::spawn(move || run_auth_service(issuer, auth_addr));
+}
+
+/// Reads our private key from disk, if it doesnt exist we generate a new one and store it.
+/// IMPORTANT - This file must never leave the server machine
+pub fn load_or_create_private_key(path: &str) -> Key {
+    match fs::read(path) {
+        Ok(bytes) => bytes
+            .try_into()
+            .expect("Private key file to have exactly 32 bytes"),
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            info!("Private key doesnt exist generating a new one at {}", path);
+            // Os rng is cryptographically secure, which is exactly what we want for a signing key
+            let mut key: Key = [0; 32];
+            OsRng.fill_bytes(&mut key);
+            if let Some(parent) = Path::new(path).parent() {
+                fs::create_dir_all(parent).expect("To be able to create private key folder");
+            }
+            fs::write(path, key).expect("To be able to write private key");
+            key
+        }
+        Err(err) => panic!(
+            "Failed to read private key for an unexpected reason: {}",
+            err
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    /// Both ends of a local tcp connection
+    fn connected_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (client, server)
+    }
+
+    #[test]
+    fn trickling_client_runs_out_of_time() {
+        let (mut client, server) = connected_pair();
+        // One byte every 20 ms, each read alone would never time out
+        let trickle = thread::spawn(move || {
+            for _ in 0..50 {
+                if client.write_all(&[0]).is_err() {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(20));
+            }
+        });
+        let started = Instant::now();
+        let mut reader = DeadlineReader {
+            stream: &server,
+            deadline: started + Duration::from_millis(200),
+        };
+        let err = io::copy(&mut reader, &mut io::sink()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert!(started.elapsed() < Duration::from_millis(500));
+        drop(server);
+        trickle.join().unwrap();
+    }
+
+    #[test]
+    fn huge_length_prefix_is_refused() {
+        // Username claiming to be 4 GB long
+        let mut bytes = u64::MAX.to_le_bytes().to_vec();
+        bytes.extend_from_slice(b"abc");
+        let result = login_codec()
+            .deserialize_from::<_, LoginRequest>(bytes.as_slice().take(MAX_LOGIN_MESSAGE_BYTES));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn slots_are_given_back_even_on_panic() {
+        let active = Arc::new(AtomicUsize::new(0));
+        let slots: Vec<ConnectionSlot> = (0..MAX_AUTH_CONNECTIONS)
+            .map(|_| ConnectionSlot::take(&active).unwrap())
+            .collect();
+        assert!(ConnectionSlot::take(&active).is_none());
+        drop(slots);
+
+        let slot = ConnectionSlot::take(&active).unwrap();
+        let _ = thread::spawn(move || {
+            let _slot = slot;
+            panic!("Login blew up");
+        })
+        .join();
+        assert_eq!(active.load(Ordering::SeqCst), 0);
+    }
+}
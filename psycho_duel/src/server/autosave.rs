@@ -0,0 +1,373 @@
+use crate::shared::config::CoreConfig;
+use crate::shared::protocol::{CoreInformation, CoreSaveInfoMap};
+use bevy::app::AppExit;
+use bevy::prelude::*;
+use bevy::tasks::{block_on, poll_once, IoTaskPool, Task};
+use bevy::utils::{Duration, HashSet};
+use uuid::Uuid;
+
+use super::ledger::LedgerEntry;
+use super::storage::{CoreSaveBackend, SaveBatch};
+
+/// Plugin responsible for writing our core save info map to disk without stalling the server tick.
+/// Systems that change a saved resource only mark that player as dirty, every interval we batch every dirty player
+/// and write them in a background thread. On graceful shutdown we flush whatever is left.
+pub struct AutosavePlugin;
+
+impl Plugin for AutosavePlugin {
+    fn build(&self, app: &mut App) {
+        let interval = app.world().resource::<CoreConfig>().save.autosave_interval;
+        app.insert_resource(CoreSaveScheduler::new(interval));
+
+        // Last because we want to catch every change made this frame
+        app.add_systems(Last, (autosave, flush_on_exit.after(autosave)));
+    }
+}
+
+/// Dirty tracking of our save map. Whenever you change something in core save info map mark it here,
+/// whenever you change a balance record it here
+#[derive(Resource)]
+pub struct CoreSaveScheduler {
+    /// Players that changed since our last write
+    dirty: HashSet<Uuid>,
+    /// Balance changes since our last write
+    ledger: Vec<LedgerEntry>,
+    /// Starts when the first player gets dirty, when he finishes we write the whole batch
+    timer: Timer,
+    /// Background write currently happening, returns what failed so we can retry it
+    in_flight: Option<Task<SaveBatch>>,
+}
+
+impl CoreSaveScheduler {
+    pub fn new(interval: f32) -> Self {
+        Self {
+            dirty: HashSet::default(),
+            ledger: Vec::new(),
+            timer: Timer::new(Duration::from_secs_f32(interval), TimerMode::Once),
+            in_flight: None,
+        }
+    }
+
+    /// Callable function - Tell the scheduler this player needs to be written on the next batch
+    pub fn mark_dirty(&mut self, player_uid: &Uuid) {
+        // First change of a batch, start counting
+        if self.dirty.is_empty() {
+            self.timer.reset();
+        }
+        self.dirty.insert(*player_uid);
+    }
+
+    /// Callable function - Records a balance change, that player is marked dirty as his currency changed
+    pub fn record(&mut self, entry: LedgerEntry) {
+        self.mark_dirty(&entry.player_uid);
+        self.ledger.push(entry);
+    }
+
+    /// Callable function - Grabs a copy of every dirty player and every recorded balance change, clearing both
+    fn take_batch(&mut self, save_info: &CoreSaveInfoMap) -> SaveBatch {
+        let players: Vec<(Uuid, CoreInformation)> = self
+            .dirty
+            .drain()
+            .filter_map(|player_uid| {
+                save_info
+                    .map
+                    .get(&player_uid)
+                    .map(|core| (player_uid, core.clone()))
+            })
+            .collect();
+        SaveBatch {
+            players: players,
+            ledger: std::mem::take(&mut self.ledger),
+        }
+    }
+
+    /// Callable function - Failed writes go back into the scheduler, so one interval later we retry them.
+    /// Failed ledger entries go before newer ones so our ledger stays in order
+    fn retry(&mut self, failed: SaveBatch) {
+        if !failed.players.is_empty() || !failed.ledger.is_empty() {
+            warn!(
+                "Retrying {} players and {} ledger entries on the next autosave",
+                failed.players.len(),
+                failed.ledger.len()
+            );
+        }
+        for (player_uid, _) in failed.players {
+            self.mark_dirty(&player_uid);
+        }
+        if !failed.ledger.is_empty() {
+            // Touching dirty so our timer starts again even if no player failed
+            self.mark_dirty(&failed.ledger[0].player_uid);
+            self.ledger.splice(0..0, failed.ledger);
+        }
+    }
+}
+
+/// One interval after the first change writes every dirty player in a background task.
+/// Only one write happens at a time, so a player is never saved out of order. Worst case a change reaches disk
+/// after one interval plus the duration of the write that was already happening
+fn autosave(
+    time: Res<Time<Real>>,
+    save_info: Res<CoreSaveInfoMap>,
+    save_backend: Res<CoreSaveBackend>,
+    mut scheduler: ResMut<CoreSaveScheduler>,
+) {
+    scheduler.timer.tick(time.delta());
+
+    if let Some(task) = scheduler.in_flight.as_mut() {
+        match block_on(poll_once(task)) {
+            Some(failed) => {
+                scheduler.in_flight = None;
+                scheduler.retry(failed);
+            }
+            None => return,
+        }
+    }
+
+    if !scheduler.timer.finished() || scheduler.dirty.is_empty() {
+        return;
+    }
+
+    let batch = scheduler.take_batch(&save_info);
+    info!(
+        "Autosaving {} players and {} ledger entries",
+        batch.players.len(),
+        batch.ledger.len()
+    );
+    let save_backend = save_backend.clone();
+    let task = IoTaskPool::get().spawn(async move { save_backend.store_batch(batch) });
+    scheduler.in_flight = Some(task);
+}
+
+/// When app is closing waits for the background write and then synchronously writes whatever is still dirty
+fn flush_on_exit(
+    mut exit: EventReader<AppExit>,
+    save_info: Res<CoreSaveInfoMap>,
+    save_backend: Res<CoreSaveBackend>,
+    mut scheduler: ResMut<CoreSaveScheduler>,
+) {
+    if exit.read().last().is_none() {
+        return;
+    }
+
+    if let Some(task) = scheduler.in_flight.take() {
+        let failed = block_on(task);
+        scheduler.retry(failed);
+    }
+
+    let batch = scheduler.take_batch(&save_info);
+    info!(
+        "Server closing, flushing {} players and {} ledger entries to disk",
+        batch.players.len(),
+        batch.ledger.len()
+    );
+    let failed = save_backend.store_batch(batch);
+    if !failed.players.is_empty() || !failed.ledger.is_empty() {
+        error!(
+            "Couldnt save {} players and {} ledger entries before closing, their last changes are lost",
+            failed.players.len(),
+            failed.ledger.len()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::ledger::{Counterparty, LedgerReason};
+    use crate::server::storage::SaveBackend;
+    use crate::shared::catalog::{CoreItemCatalog, ItemCatalog};
+    use lightyear::prelude::ClientId;
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+
+    /// Save map with a fresh player per uid given
+    fn save_info(player_uids: &[Uuid]) -> CoreSaveInfoMap {
+        let catalog = ItemCatalog::read(Path::new("assets/items.catalog.ron"))
+            .expect("Shipped catalog to be valid");
+        let catalog = CoreItemCatalog {
+            handle: Handle::default(),
+            items: catalog
+                .items
+                .into_iter()
+                .map(|item| (item.id.clone(), item))
+                .collect(),
+        };
+        let mut save_info = CoreSaveInfoMap::default();
+        for (index, player_uid) in player_uids.iter().enumerate() {
+            let core = CoreInformation::new(ClientId::Netcode(index as u64), &catalog);
+            save_info.map.insert(*player_uid, core);
+        }
+        save_info
+    }
+
+    fn entry(player_uid: Uuid, delta: i64) -> LedgerEntry {
+        LedgerEntry::new(
+            player_uid,
+            delta,
+            100,
+            LedgerReason::AdminGrant,
+            Counterparty::Admin,
+            None,
+            None,
+        )
+    }
+
+    /// Backend that refuses the first ledger appends, remembering whatever it stored
+    #[derive(Default)]
+    struct FlakyBackend {
+        ledger_failures: u32,
+        players: Vec<Uuid>,
+        ledger: Vec<LedgerEntry>,
+    }
+
+    impl SaveBackend for FlakyBackend {
+        fn load(&mut self) -> Result<CoreSaveInfoMap, String> {
+            Ok(CoreSaveInfoMap::default())
+        }
+
+        fn store_player(
+            &mut self,
+            player_uid: &Uuid,
+            _core: &CoreInformation,
+        ) -> Result<(), String> {
+            self.players.push(*player_uid);
+            Ok(())
+        }
+
+        fn remove_player(&mut self, player_uid: &Uuid) -> Result<(), String> {
+            self.players.retain(|uid| uid != player_uid);
+            Ok(())
+        }
+
+        fn list(&self) -> Result<Vec<Uuid>, String> {
+            Ok(self.players.clone())
+        }
+
+        fn append_ledger(&mut self, entries: &[LedgerEntry]) -> Result<(), String> {
+            if self.ledger_failures > 0 {
+                self.ledger_failures -= 1;
+                return Err("Disk said no".to_string());
+            }
+            self.ledger.extend_from_slice(entries);
+            Ok(())
+        }
+
+        fn load_ledger(&self) -> Result<Vec<LedgerEntry>, String> {
+            Ok(self.ledger.clone())
+        }
+    }
+
+    #[test]
+    fn dirty_players_are_batched_once() {
+        let player_uids = [Uuid::new_v4(), Uuid::new_v4()];
+        let save_info = save_info(&player_uids);
+        let mut scheduler = CoreSaveScheduler::new(1.0);
+
+        scheduler.mark_dirty(&player_uids[0]);
+        scheduler.mark_dirty(&player_uids[0]);
+        scheduler.mark_dirty(&player_uids[1]);
+        // Not in our save map, nothing to write
+        scheduler.mark_dirty(&Uuid::new_v4());
+        scheduler.record(entry(player_uids[1], 5));
+
+        let batch = scheduler.take_batch(&save_info);
+        let mut written: Vec<Uuid> = batch.players.iter().map(|(uid, _)| *uid).collect();
+        written.sort();
+        let mut expected = player_uids.to_vec();
+        expected.sort();
+        assert_eq!(written, expected);
+        assert_eq!(batch.ledger.len(), 1);
+
+        let empty = scheduler.take_batch(&save_info);
+        assert!(empty.players.is_empty() && empty.ledger.is_empty());
+    }
+
+    #[test]
+    fn timer_counts_from_first_change_of_a_batch() {
+        let player_uids = [Uuid::new_v4(), Uuid::new_v4()];
+        let save_info = save_info(&player_uids);
+        let mut scheduler = CoreSaveScheduler::new(1.0);
+
+        scheduler.mark_dirty(&player_uids[0]);
+        scheduler.timer.tick(Duration::from_secs_f32(0.6));
+        // Later changes ride along, they dont push the write back
+        scheduler.mark_dirty(&player_uids[1]);
+        scheduler.timer.tick(Duration::from_secs_f32(0.5));
+        assert!(scheduler.timer.finished());
+
+        scheduler.take_batch(&save_info);
+        scheduler.mark_dirty(&player_uids[0]);
+        assert!(!scheduler.timer.finished());
+    }
+
+    #[test]
+    fn failed_writes_are_retried_in_order() {
+        let player_uid = Uuid::new_v4();
+        let save_info = save_info(&[player_uid]);
+        let flaky = Arc::new(Mutex::new(FlakyBackend {
+            ledger_failures: 1,
+            ..default()
+        }));
+        let save_backend = CoreSaveBackend {
+            backend: Arc::new(Mutex::new(Box::new(SharedBackend(flaky.clone())))),
+        };
+        let mut scheduler = CoreSaveScheduler::new(1.0);
+        let first = [entry(player_uid, 10), entry(player_uid, -4)];
+        for entry in first.iter() {
+            scheduler.record(entry.clone());
+        }
+
+        // Ledger fails, so players are held back too
+        let failed = save_backend.store_batch(scheduler.take_batch(&save_info));
+        assert_eq!(failed.players.len(), 1);
+        assert_eq!(failed.ledger, first.to_vec());
+        assert!(flaky.lock().unwrap().players.is_empty());
+
+        // Newer entries go after the ones we retry
+        let newer = entry(player_uid, 1);
+        scheduler.record(newer.clone());
+        scheduler.retry(failed);
+        let failed = save_backend.store_batch(scheduler.take_batch(&save_info));
+        assert!(failed.players.is_empty() && failed.ledger.is_empty());
+
+        let flaky = flaky.lock().unwrap();
+        assert_eq!(flaky.players, vec![player_uid]);
+        assert_eq!(
+            flaky.ledger,
+            vec![first[0].clone(), first[1].clone(), newer]
+        );
+    }
+
+    /// Lets our test peek into the backend after handing it to the save backend
+    struct SharedBackend(Arc<Mutex<FlakyBackend>>);
+
+    impl SaveBackend for SharedBackend {
+        fn load(&mut self) -> Result<CoreSaveInfoMap, String> {
+            self.0.lock().unwrap().load()
+        }
+
+        fn store_player(
+            &mut self,
+            player_uid: &Uuid,
+            core: &CoreInformation,
+        ) -> Result<(), String> {
+            self.0.lock().unwrap().store_player(player_uid, core)
+        }
+
+        fn remove_player(&mut self, player_uid: &Uuid) -> Result<(), String> {
+            self.0.lock().unwrap().remove_player(player_uid)
+        }
+
+        fn list(&self) -> Result<Vec<Uuid>, String> {
+            self.0.lock().unwrap().list()
+        }
+
+        fn append_ledger(&mut self, entries: &[LedgerEntry]) -> Result<(), String> {
+            self.0.lock().unwrap().append_ledger(entries)
+        }
+
+        fn load_ledger(&self) -> Result<Vec<LedgerEntry>, String> {
+            self.0.lock().unwrap().load_ledger()
+        }
+    }
+}
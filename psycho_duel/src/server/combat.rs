@@ -0,0 +1,226 @@
+use crate::server::protocol::*;
+use crate::shared::catalog::CoreItemCatalog;
+use crate::shared::combat::*;
+use crate::shared::movement::move_players;
+use bevy::prelude::*;
+use leafwing_input_manager::prelude::*;
+use lightyear::prelude::*;
+
+use super::CommonChannel;
+
+/// Where dead players come back with full health and stamina
+const RESPAWN_POINT: Vec3 = Vec3::ZERO;
+
+/// Plugin responsible for melee combat. Swings and stamina are stepped the same way client predicts them,
+/// but only server sweeps blades into bodies and decides who got hit, blocked or died
+pub struct ServerCombatPlugin;
+
+/// Server only - Who that swing already hit, so one swing only hits each player once. Emptied whenever a new swing starts
+#[derive(Component, Default)]
+pub struct MeleeVictims {
+    pub entities: Vec<Entity>,
+}
+
+impl Plugin for ServerCombatPlugin {
+    fn build(&self, app: &mut App) {
+        // Observer when player is created
+        app.add_observer(add_combat_components);
+
+        // Fixed update same as movement, blades are swept against where bodies are after moving this tick
+        app.add_systems(
+            FixedUpdate,
+            (update_melee::<With<PlayerMarker>>, resolve_hits)
+                .chain()
+                .after(move_players::<With<PlayerMarker>>),
+        );
+    }
+}
+
+/// Every player starts with full health, full stamina and his weapon down
+fn add_combat_components(player: Trigger<OnAdd, PlayerMarker>, mut commands: Commands) {
+    commands
+        .entity(player.entity())
+        .insert(Health::default())
+        .insert(Stamina::default())
+        .insert(MeleeState::default())
+        .insert(MeleeVictims::default());
+}
+
+/// One blade that touched one body this tick
+struct PendingHit {
+    attacker: Entity,
+    attacker_id: ClientId,
+    attacker_translation: Vec3,
+    defender: Entity,
+    attack: AttackKind,
+}
+
+/// Sweeps every active blade through this tick and applies what it touched. Blocks cost stamina, hits cost health,
+/// and every outcome is sent to all clients
+fn resolve_hits(
+    mut players: Query<
+        (
+            Entity,
+            &PlayerId,
+            &mut Transform,
+            &ActionState<PlayerActions>,
+            &PlayerVisuals,
+            &mut MeleeState,
+            &mut MeleeVictims,
+            &mut Health,
+            &mut Stamina,
+        ),
+        With<PlayerMarker>,
+    >,
+    catalog: Res<CoreItemCatalog>,
+    mut connection_manager: ResMut<ServerConnectionManager>,
+) {
+    // A fresh swing forgets who the previous one hit
+    for (_, _, _, _, _, melee, mut victims, _, _) in players.iter_mut() {
+        if melee.tick == 0 && !victims.entities.is_empty() {
+            victims.entities.clear();
+        }
+    }
+
+    // First - Find every blade that touched a body, nothing changes yet so order of players doesnt matter
+    let mut pending = Vec::new();
+    for (attacker, attacker_id, transform, _, visuals, melee, victims, _, _) in players.iter() {
+        let Some(attack) = melee.attack else {
+            continue;
+        };
+        let Some(weapon) = swing_weapon(visuals, &catalog) else {
+            continue;
+        };
+        let sweep = swept_blade(transform, &weapon, attack, melee.tick);
+        if sweep.is_empty() {
+            continue;
+        }
+        for (defender, _, defender_transform, _, _, _, _, _, _) in players.iter() {
+            if defender == attacker || victims.entities.contains(&defender) {
+                continue;
+            }
+            if sweep.iter().any(|blade| {
+                blade_hits_body(
+                    *blade,
+                    weapon.profile.blade_radius,
+                    defender_transform.translation,
+                )
+            }) {
+                pending.push(PendingHit {
+                    attacker: attacker,
+                    attacker_id: attacker_id.id,
+                    attacker_translation: transform.translation,
+                    defender: defender,
+                    attack: attack,
+                });
+            }
+        }
+    }
+
+    // Second - Apply them
+    for hit in pending {
+        if let Ok((_, _, _, _, _, _, mut victims, _, _)) = players.get_mut(hit.attacker) {
+            victims.entities.push(hit.defender);
+        }
+
+        let Ok((
+            _,
+            defender_id,
+            mut transform,
+            action_state,
+            _,
+            mut melee,
+            _,
+            mut health,
+            mut stamina,
+        )) = players.get_mut(hit.defender)
+        else {
+            continue;
+        };
+        // Already died this tick to someone else
+        if health.current <= 0.0 {
+            continue;
+        }
+
+        let block_cost = hit.attack.damage() * BLOCK_STAMINA_PER_DAMAGE;
+        if blocks_from(
+            transform.translation,
+            action_state,
+            &melee,
+            hit.attacker_translation,
+        ) {
+            if stamina.current >= block_cost {
+                stamina.current -= block_cost;
+                info!(
+                    "Player {} blocked {:?} from {}",
+                    defender_id.id, hit.attack, hit.attacker_id
+                );
+                let mut blocked = MeleeBlocked {
+                    attacker: hit.attacker_id,
+                    defender: defender_id.id,
+                    attack: hit.attack,
+                    stamina_left: stamina.current,
+                };
+                if connection_manager
+                    .send_message_to_target::<CommonChannel, MeleeBlocked>(
+                        &mut blocked,
+                        NetworkTarget::All,
+                    )
+                    .is_err()
+                {
+                    warn!("Couldnt broadcast block of player {}", defender_id.id)
+                }
+                continue;
+            }
+            // Guard broken, not enough stamina to hold that hit
+            stamina.current = 0.0;
+        }
+
+        health.current = (health.current - hit.attack.damage()).max(0.0);
+        info!(
+            "Player {} hit {} with {:?}, health left {}",
+            hit.attacker_id, defender_id.id, hit.attack, health.current
+        );
+        let mut melee_hit = MeleeHit {
+            attacker: hit.attacker_id,
+            defender: defender_id.id,
+            attack: hit.attack,
+            damage: hit.attack.damage(),
+            health_left: health.current,
+        };
+        if connection_manager
+            .send_message_to_target::<CommonChannel, MeleeHit>(&mut melee_hit, NetworkTarget::All)
+            .is_err()
+        {
+            warn!("Couldnt broadcast hit on player {}", defender_id.id)
+        }
+
+        if health.current <= 0.0 {
+            info!(
+                "Player {} was killed by {}",
+                defender_id.id, hit.attacker_id
+            );
+            let mut died = PlayerDied {
+                id: defender_id.id,
+                killer: hit.attacker_id,
+            };
+            if connection_manager
+                .send_message_to_target::<CommonChannel, PlayerDied>(&mut died, NetworkTarget::All)
+                .is_err()
+            {
+                warn!("Couldnt broadcast death of player {}", defender_id.id)
+            }
+            // Respawn right away, health stays at zero for this tick so nobody else hits a dead player
+            transform.translation = RESPAWN_POINT;
+            *melee = MeleeState::default();
+            *stamina = Stamina::default();
+        }
+    }
+
+    // Dead players were kept at zero until every hit of this tick was applied
+    for (_, _, _, _, _, _, _, mut health, _) in players.iter_mut() {
+        if health.current <= 0.0 {
+            *health = Health::default();
+        }
+    }
+}
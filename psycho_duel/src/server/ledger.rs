@@ -0,0 +1,112 @@
+use crate::shared::protocol::Item;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+/// One balance change of one player. Every server side change of currency must create one of those,
+/// they are written by our autosave alongside core save info map and never edited after. Good for audits and refunds
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LedgerEntry {
+    /// Transaction id, a retried write carries the same one so our backends store him only once.
+    /// Entries written before ids existed get a fresh one when read
+    #[serde(default = "Uuid::new_v4")]
+    pub id: Uuid,
+    /// Whose balance changed
+    pub player_uid: Uuid,
+    /// How much it changed in minor units, negative when money left the player
+    pub delta: i64,
+    /// Balance after the change in minor units
+    pub balance: u64,
+    /// Why it changed
+    pub reason: LedgerReason,
+    /// Who was on the other side of it
+    pub counterparty: Counterparty,
+    /// Item involved, if any
+    pub item: Option<Item>,
+    /// Server tick it happened on. None when done offline via save cli
+    /// Worth noting ticks wrap around, use timestamp to order entries
+    pub tick: Option<u16>,
+    /// Seconds since unix epoch
+    pub timestamp: u64,
+}
+
+impl LedgerEntry {
+    /// Creates an entry stamped with the current time
+    pub fn new(
+        player_uid: Uuid,
+        delta: i64,
+        balance: u64,
+        reason: LedgerReason,
+        counterparty: Counterparty,
+        item: Option<Item>,
+        tick: Option<u16>,
+    ) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or_default();
+        Self {
+            id: Uuid::new_v4(),
+            player_uid: player_uid,
+            delta: delta,
+            balance: balance,
+            reason: reason,
+            counterparty: counterparty,
+            item: item,
+            tick: tick,
+            timestamp: timestamp,
+        }
+    }
+}
+
+/// Every reason a balance may change
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LedgerReason {
+    /// Player bought from our store
+    StorePurchase,
+    /// Player sold to our store
+    StoreSale,
+    /// Someone granted money via save cli
+    AdminGrant,
+    /// Someone removed money via save cli
+    AdminRemoval,
+    /// Player gave money or an item away in a trade
+    TradeSent,
+    /// Player got money or an item in a trade
+    TradeReceived,
+}
+
+/// Who was on the other side of a balance change
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Counterparty {
+    /// Our store, money comes from and goes to nowhere
+    Store,
+    /// Someone using the save cli
+    Admin,
+    /// Another player
+    Player(Uuid),
+}
+
+/// Pretty one liner for our save cli
+impl fmt::Display for LedgerEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "[{}] {} {:?} {:+} minor units, balance {} minor units, counterparty {:?}",
+            self.timestamp,
+            self.player_uid,
+            self.reason,
+            self.delta,
+            self.balance,
+            self.counterparty
+        )?;
+        if let Some(item) = &self.item {
+            write!(f, ", item {} ({})", item.definition_id, item.id)?;
+        }
+        if let Some(tick) = self.tick {
+            write!(f, ", tick {}", tick)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,186 @@
+use crate::server::protocol::*;
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use lightyear::prelude::*;
+use lightyear::server::events::MessageEvent;
+use uuid::Uuid;
+
+use super::account::CoreAccountRegistry;
+use super::autosave::CoreSaveScheduler;
+use super::player::ServerClientIdPlayerMap;
+use super::save::send_own_core;
+use super::CommonChannel;
+
+/// Plugin responsible for saved outfits. Clients ask to save, apply or delete a loadout, applying validates every slot
+/// against the inventory and changes them all at once, either the whole loadout is worn or nothing changes
+pub struct ServerLoadoutPlugin;
+
+impl Plugin for ServerLoadoutPlugin {
+    fn build(&self, app: &mut App) {
+        // Update because it listens to client messages
+        app.add_systems(Update, handle_loadout_requests);
+    }
+}
+
+/// Reads every loadout request, on success saves it and tells the owner. Applied loadouts are also broadcasted so everyone respawns the changed parts
+fn handle_loadout_requests(
+    mut loadout_requests: EventReader<MessageEvent<LoadoutRequest>>,
+    mut core_info_map: ResMut<CoreSaveInfoMap>,
+    mut save_scheduler: ResMut<CoreSaveScheduler>,
+    accounts: Res<CoreAccountRegistry>,
+    player_map: Res<ServerClientIdPlayerMap>,
+    mut players: Query<(&mut PlayerVisuals, &mut Loadouts, &Inventory)>,
+    mut connection_manager: ResMut<ServerConnectionManager>,
+) {
+    for request in loadout_requests.read() {
+        let client_id = *request.context();
+
+        let Some(player_uid) = accounts.player_of(&client_id) else {
+            warn!(
+                "Client {} sent a loadout request without a session",
+                client_id
+            );
+            continue;
+        };
+        let Some(player_entity) = player_map.map.get(&client_id) else {
+            continue;
+        };
+        let Some(core) = core_info_map.map.get_mut(&player_uid) else {
+            continue;
+        };
+        let Ok((mut visuals, mut loadouts, inventory)) = players.get_mut(*player_entity) else {
+            continue;
+        };
+
+        let result = match request.message() {
+            LoadoutRequest::Save { name } => save_loadout(name, &visuals, &mut loadouts),
+            LoadoutRequest::Apply { name } => match loadouts.get(name) {
+                Some(loadout) => match resolve_loadout(&loadout.visuals, inventory) {
+                    Ok(resolved) => {
+                        let mut applied = LoadoutApplied {
+                            id: client_id,
+                            previous: visuals.clone(),
+                            current: resolved.clone(),
+                        };
+                        *visuals = resolved;
+                        if connection_manager
+                            .send_message_to_target::<CommonChannel, LoadoutApplied>(
+                                &mut applied,
+                                NetworkTarget::All,
+                            )
+                            .is_err()
+                        {
+                            warn!("Even tho server applied the loadout couldnt broadcast it to all clients!")
+                        }
+                        Ok(LoadoutResult::Applied(name.clone()))
+                    }
+                    Err(err) => Err(err),
+                },
+                None => Err(LoadoutError::Unknown(name.clone())),
+            },
+            LoadoutRequest::Delete { name } => {
+                let before = loadouts.saved.len();
+                loadouts.saved.retain(|loadout| loadout.name != *name);
+                if loadouts.saved.len() == before {
+                    Err(LoadoutError::Unknown(name.clone()))
+                } else {
+                    Ok(LoadoutResult::Deleted(name.clone()))
+                }
+            }
+        };
+
+        let mut result = match result {
+            Ok(result) => {
+                info!("Player {} {:?}", player_uid, result);
+                core.player_visuals = visuals.clone();
+                core.loadouts = loadouts.clone();
+                save_scheduler.mark_dirty(&player_uid);
+                send_own_core(&mut connection_manager, client_id, core);
+                result
+            }
+            Err(err) => {
+                warn!("Refused loadout request of client {}: {}", client_id, err);
+                LoadoutResult::Rejected(err)
+            }
+        };
+        if connection_manager
+            .send_message_to_target::<CommonChannel, LoadoutResult>(
+                &mut result,
+                NetworkTarget::Single(client_id),
+            )
+            .is_err()
+        {
+            warn!("Couldnt send loadout result to client {}", client_id)
+        }
+    }
+}
+
+/// Callable function - Stores what the player is wearing under that name, same name overwrites
+fn save_loadout(
+    name: &str,
+    visuals: &PlayerVisuals,
+    loadouts: &mut Loadouts,
+) -> Result<LoadoutResult, LoadoutError> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > MAX_LOADOUT_NAME_LEN {
+        return Err(LoadoutError::InvalidName);
+    }
+    let loadout = Loadout {
+        name: name.to_string(),
+        visuals: visuals.clone(),
+    };
+    if let Some(existing) = loadouts.saved.iter_mut().find(|saved| saved.name == name) {
+        *existing = loadout;
+    } else if loadouts.saved.len() >= MAX_LOADOUTS {
+        return Err(LoadoutError::Full { max: MAX_LOADOUTS });
+    } else {
+        loadouts.saved.push(loadout);
+    }
+    Ok(LoadoutResult::Saved(name.to_string()))
+}
+
+/// Callable function - Turns a saved loadout into visuals pointing to instances the player owns right now.
+/// Prefers the instance that was saved, otherwise any other of the same catalog item. Each instance covers as many slots as his stack count
+fn resolve_loadout(
+    loadout: &PlayerVisuals,
+    inventory: &Inventory,
+) -> Result<PlayerVisuals, LoadoutError> {
+    let mut taken: HashMap<Uuid, u32> = HashMap::new();
+    let mut resolve = |wanted: &Item, expected_type: ItemType| {
+        let mut candidates: Vec<&InventoryEntry> = inventory
+            .entries
+            .values()
+            .filter(|entry| entry.item.definition_id == wanted.definition_id)
+            .collect();
+        // False sorts first, so the saved instance comes first
+        candidates.sort_by_key(|entry| entry.item.id != wanted.id);
+
+        let Some(entry) = candidates
+            .into_iter()
+            .find(|entry| entry.count > taken.get(&entry.item.id).copied().unwrap_or(0))
+        else {
+            return Err(LoadoutError::NotOwned(wanted.definition_id.clone()));
+        };
+        if entry.item.item_type != expected_type {
+            return Err(LoadoutError::WrongSlot(wanted.definition_id.clone()));
+        }
+        *taken.entry(entry.item.id).or_default() += 1;
+        Ok(entry.item.clone())
+    };
+
+    Ok(PlayerVisuals {
+        skeleton: resolve(&loadout.skeleton, ItemType::Skeleton)?,
+        head: resolve(&loadout.head, ItemType::Visual)?,
+        torso: resolve(&loadout.torso, ItemType::Visual)?,
+        leg: resolve(&loadout.leg, ItemType::Visual)?,
+        arm: resolve(&loadout.arm, ItemType::Visual)?,
+        main_hand: match &loadout.main_hand {
+            Some(item) => Some(resolve(item, ItemType::Weapon)?),
+            None => None,
+        },
+        off_hand: match &loadout.off_hand {
+            Some(item) => Some(resolve(item, ItemType::Weapon)?),
+            None => None,
+        },
+    })
+}
@@ -0,0 +1,801 @@
+use crate::shared::protocol::*;
+use bevy::prelude::*;
+use bincode::{deserialize, serialize};
+use lightyear::prelude::ClientId;
+use serde::Serialize;
+use uuid::Uuid;
+
+/// Every versioned save starts with these bytes, if they are missing we are dealing with a legacy v0 save
+const SAVE_MAGIC: [u8; 4] = *b"PSDL";
+
+/// Version of the layout we currently write. Workflow when changing core information shape:
+/// -> First - Copy the old shapes into a frozen module below (example: v0), they must never change again
+/// -> Second - Bump this guy, write the migration function from the old shape to the new one and chain it in map_from_vN and core_from_vN
+/// -> Third - Add a fixture of the old version in tests/fixtures and a test that loads it
+pub const CURRENT_SAVE_VERSION: u32 = 6;
+
+/// Serializes any save struct with our magic + version header in front of it
+pub fn encode_with_header<T: Serialize>(value: &T) -> Result<Vec<u8>, String> {
+    let mut bytes = SAVE_MAGIC.to_vec();
+    bytes.extend_from_slice(&CURRENT_SAVE_VERSION.to_le_bytes());
+    bytes.extend(serialize(value).map_err(|err| err.to_string())?);
+    Ok(bytes)
+}
+
+/// Decodes a whole save map of any known version, migrating it to the current layout
+pub fn decode_save_map(bytes: &[u8]) -> Result<CoreSaveInfoMap, String> {
+    let (version, payload) = split_header(bytes);
+    let save_info = match version {
+        0 => map_from_v1(migrate_map_v0_to_v1(
+            deserialize(payload).map_err(|err| malformed(0, err))?,
+        )),
+        1 => map_from_v1(deserialize(payload).map_err(|err| malformed(1, err))?),
+        2 => map_from_v2(deserialize(payload).map_err(|err| malformed(2, err))?),
+        3 => map_from_v3(deserialize(payload).map_err(|err| malformed(3, err))?),
+        4 => map_from_v4(deserialize(payload).map_err(|err| malformed(4, err))?),
+        5 => map_from_v5(deserialize(payload).map_err(|err| malformed(5, err))?),
+        6 => deserialize(payload).map_err(|err| malformed(6, err))?,
+        _ => return Err(too_new(version)),
+    };
+    if version != CURRENT_SAVE_VERSION {
+        info!(
+            "Migrated save map from version {} to {}",
+            version, CURRENT_SAVE_VERSION
+        );
+    }
+    Ok(save_info)
+}
+
+/// Decodes one sole player record of any known version, migrating it to the current layout
+pub fn decode_player(bytes: &[u8]) -> Result<CoreInformation, String> {
+    let (version, payload) = split_header(bytes);
+    match version {
+        // Player layout didnt change between v0 and v1, only the map key did
+        0 | 1 => deserialize(payload)
+            .map(core_from_v1)
+            .map_err(|err| malformed(version, err)),
+        2 => deserialize(payload)
+            .map(core_from_v2)
+            .map_err(|err| malformed(version, err)),
+        3 => deserialize(payload)
+            .map(core_from_v3)
+            .map_err(|err| malformed(version, err)),
+        4 => deserialize(payload)
+            .map(core_from_v4)
+            .map_err(|err| malformed(version, err)),
+        5 => deserialize(payload)
+            .map(core_from_v5)
+            .map_err(|err| malformed(version, err)),
+        6 => deserialize(payload).map_err(|err| malformed(version, err)),
+        _ => Err(too_new(version)),
+    }
+}
+
+/// Runs every migration from that version up to the current one. When bumping the version, add a new one here
+/// and make the previous last one go through it
+fn map_from_v1(old: v1::CoreSaveInfoMap) -> CoreSaveInfoMap {
+    map_from_v2(migrate_map_v1_to_v2(old))
+}
+
+fn map_from_v2(old: v2::CoreSaveInfoMap) -> CoreSaveInfoMap {
+    map_from_v3(migrate_map_v2_to_v3(old))
+}
+
+fn map_from_v3(old: v3::CoreSaveInfoMap) -> CoreSaveInfoMap {
+    map_from_v4(migrate_map_v3_to_v4(old))
+}
+
+fn map_from_v4(old: v4::CoreSaveInfoMap) -> CoreSaveInfoMap {
+    map_from_v5(migrate_map_v4_to_v5(old))
+}
+
+fn map_from_v5(old: v5::CoreSaveInfoMap) -> CoreSaveInfoMap {
+    migrate_map_v5_to_v6(old)
+}
+
+/// Same as the map ones, but for sole player records
+fn core_from_v1(old: v1::CoreInformation) -> CoreInformation {
+    core_from_v2(migrate_core_v1_to_v2(old))
+}
+
+fn core_from_v2(old: v2::CoreInformation) -> CoreInformation {
+    core_from_v3(migrate_core_v2_to_v3(old))
+}
+
+fn core_from_v3(old: v3::CoreInformation) -> CoreInformation {
+    core_from_v4(migrate_core_v3_to_v4(old))
+}
+
+fn core_from_v4(old: v4::CoreInformation) -> CoreInformation {
+    core_from_v5(migrate_core_v4_to_v5(old))
+}
+
+fn core_from_v5(old: v5::CoreInformation) -> CoreInformation {
+    migrate_core_v5_to_v6(old)
+}
+
+/// Tell me the version of a save without decoding it
+pub fn save_version(bytes: &[u8]) -> u32 {
+    split_header(bytes).0
+}
+
+/// Returns the version and the payload after the header. No magic means legacy v0
+fn split_header(bytes: &[u8]) -> (u32, &[u8]) {
+    if bytes.len() >= 8 && bytes[..4] == SAVE_MAGIC {
+        let version = u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+        (version, &bytes[8..])
+    } else {
+        (0, bytes)
+    }
+}
+
+/// Pretty error for when bincode fails
+fn malformed(version: u32, err: bincode::Error) -> String {
+    format!("Save with version {} is malformed {}", version, err)
+}
+
+/// Pretty error for saves written by a newer server
+fn too_new(version: u32) -> String {
+    format!(
+        "Save version {} is newer than the one this server knows {}",
+        version, CURRENT_SAVE_VERSION
+    )
+}
+
+/// Frozen shapes of our first save layout, headerless and keyed by lightyear client id
+mod v0 {
+    use super::v1::CoreInformation;
+    use serde::Deserialize;
+
+    /// Same layout as lightyear client id back then, frozen so lightyear updates dont break old saves
+    #[derive(Deserialize)]
+    pub enum LegacyClientId {
+        Netcode(u64),
+        Steam(u64),
+        Local(u64),
+    }
+
+    /// A hashmap and a vec of tuples have the same layout in bincode
+    #[derive(Deserialize)]
+    pub struct CoreSaveInfoMap {
+        pub map: Vec<(LegacyClientId, CoreInformation)>,
+    }
+}
+
+/// Frozen shapes of v1, items only had a random uuid and no catalog id
+mod v1 {
+    use super::v0::LegacyClientId;
+    use serde::Deserialize;
+    use uuid::Uuid;
+
+    #[derive(Deserialize, Clone)]
+    pub enum ItemType {
+        Visual,
+        Weapon,
+        Skeleton,
+    }
+
+    /// Bevy name serializes as a plain string
+    #[derive(Deserialize)]
+    pub struct Item {
+        pub id: Uuid,
+        pub name: String,
+        pub file_path: String,
+        pub item_type: ItemType,
+    }
+
+    #[derive(Deserialize)]
+    pub struct PlayerId {
+        pub id: LegacyClientId,
+    }
+
+    #[derive(Deserialize)]
+    pub struct PlayerVisuals {
+        pub skeleton: Item,
+        pub head: Item,
+        pub torso: Item,
+        pub leg: Item,
+        pub arm: Item,
+        pub weapon_1: Item,
+    }
+
+    #[derive(Deserialize)]
+    pub struct Inventory {
+        pub items: Vec<(Uuid, Item)>,
+    }
+
+    #[derive(Deserialize)]
+    pub struct Currency {
+        pub amount: f32,
+    }
+
+    #[derive(Deserialize)]
+    pub struct CoreInformation {
+        pub player_id: PlayerId,
+        pub player_visuals: PlayerVisuals,
+        pub inventory: Inventory,
+        pub currency: Currency,
+    }
+
+    #[derive(Deserialize)]
+    pub struct CoreSaveInfoMap {
+        pub map: Vec<(Uuid, CoreInformation)>,
+    }
+}
+
+/// Frozen shapes of v2, items got a catalog id but inventory was a flat map without counts
+mod v2 {
+    use super::v1::{Currency, ItemType, PlayerId};
+    use serde::Deserialize;
+    use uuid::Uuid;
+
+    #[derive(Deserialize, Clone)]
+    pub struct Item {
+        pub id: Uuid,
+        pub definition_id: String,
+        pub name: String,
+        pub file_path: String,
+        pub item_type: ItemType,
+    }
+
+    #[derive(Deserialize)]
+    pub struct PlayerVisuals {
+        pub skeleton: Item,
+        pub head: Item,
+        pub torso: Item,
+        pub leg: Item,
+        pub arm: Item,
+        pub weapon_1: Item,
+    }
+
+    #[derive(Deserialize)]
+    pub struct Inventory {
+        pub items: Vec<(Uuid, Item)>,
+    }
+
+    #[derive(Deserialize)]
+    pub struct CoreInformation {
+        pub player_id: PlayerId,
+        pub player_visuals: PlayerVisuals,
+        pub inventory: Inventory,
+        pub currency: Currency,
+    }
+
+    #[derive(Deserialize)]
+    pub struct CoreSaveInfoMap {
+        pub map: Vec<(Uuid, CoreInformation)>,
+    }
+}
+
+/// Frozen shapes of v3, inventory got stacks but currency was still a float
+mod v3 {
+    use super::v1::{Currency, PlayerId};
+    use super::v2::{Item, PlayerVisuals};
+    use serde::Deserialize;
+    use uuid::Uuid;
+
+    #[derive(Deserialize)]
+    pub struct InventoryEntry {
+        pub item: Item,
+        pub count: u32,
+    }
+
+    #[derive(Deserialize)]
+    pub struct Inventory {
+        pub entries: Vec<(Uuid, InventoryEntry)>,
+        pub capacity: u32,
+    }
+
+    #[derive(Deserialize)]
+    pub struct CoreInformation {
+        pub player_id: PlayerId,
+        pub player_visuals: PlayerVisuals,
+        pub inventory: Inventory,
+        pub currency: Currency,
+    }
+
+    #[derive(Deserialize)]
+    pub struct CoreSaveInfoMap {
+        pub map: Vec<(Uuid, CoreInformation)>,
+    }
+}
+
+/// Frozen shapes of v4, a sole weapon slot whose starter katana never made it into the inventory.
+/// Items and inventory had the same shapes as v3, only currency became integer minor units
+mod v4 {
+    use super::v1::PlayerId;
+    use super::v2::PlayerVisuals;
+    use super::v3::Inventory;
+    use serde::Deserialize;
+    use uuid::Uuid;
+
+    #[derive(Deserialize)]
+    pub struct Currency {
+        pub amount: u64,
+    }
+
+    #[derive(Deserialize)]
+    pub struct CoreInformation {
+        pub player_id: PlayerId,
+        pub player_visuals: PlayerVisuals,
+        pub inventory: Inventory,
+        pub currency: Currency,
+    }
+
+    #[derive(Deserialize)]
+    pub struct CoreSaveInfoMap {
+        pub map: Vec<(Uuid, CoreInformation)>,
+    }
+}
+
+/// Frozen shapes of v5, weapons went to main and off hand but there were no loadouts
+mod v5 {
+    use super::v1::PlayerId;
+    use super::v2::Item;
+    use super::v3::Inventory;
+    use super::v4::Currency;
+    use serde::Deserialize;
+    use uuid::Uuid;
+
+    #[derive(Deserialize)]
+    pub struct PlayerVisuals {
+        pub skeleton: Item,
+        pub head: Item,
+        pub torso: Item,
+        pub leg: Item,
+        pub arm: Item,
+        pub main_hand: Option<Item>,
+        pub off_hand: Option<Item>,
+    }
+
+    #[derive(Deserialize)]
+    pub struct CoreInformation {
+        pub player_id: PlayerId,
+        pub player_visuals: PlayerVisuals,
+        pub inventory: Inventory,
+        pub currency: Currency,
+    }
+
+    #[derive(Deserialize)]
+    pub struct CoreSaveInfoMap {
+        pub map: Vec<(Uuid, CoreInformation)>,
+    }
+}
+
+/// v0 -> v1 Save map gets keyed by player uid. As legacy players had no account their uid is derived from their old client id.
+/// IMPORTANT - No account ever owns those uids, accounts get random v4 uids while these have version zero. That is on purpose,
+/// legacy client ids were picked by the clients themselves, so letting anyone claim them on login would hand their saves to whoever asks first.
+/// They are kept so nothing gets lost, an admin can still read them via the save subcommands
+fn migrate_map_v0_to_v1(old: v0::CoreSaveInfoMap) -> v1::CoreSaveInfoMap {
+    let mut save_info = v1::CoreSaveInfoMap { map: Vec::new() };
+    for (legacy_id, core) in old.map {
+        let player_uid = match legacy_id {
+            v0::LegacyClientId::Netcode(id)
+            | v0::LegacyClientId::Steam(id)
+            | v0::LegacyClientId::Local(id) => Uuid::from_u64_pair(0, id),
+        };
+        save_info.map.push((player_uid, core));
+    }
+    save_info
+}
+
+/// v1 -> v2 Items get a catalog id
+fn migrate_map_v1_to_v2(old: v1::CoreSaveInfoMap) -> v2::CoreSaveInfoMap {
+    v2::CoreSaveInfoMap {
+        map: old
+            .map
+            .into_iter()
+            .map(|(player_uid, core)| (player_uid, migrate_core_v1_to_v2(core)))
+            .collect(),
+    }
+}
+
+/// v1 -> v2 Every item gets his catalog id, back then every catalog id was the gltf file name without extension.
+/// Old visuals were random instances unrelated to the inventory, so they get pointed to the owned instance of the same catalog item
+fn migrate_core_v1_to_v2(old: v1::CoreInformation) -> v2::CoreInformation {
+    let items: Vec<(Uuid, v2::Item)> = old
+        .inventory
+        .items
+        .into_iter()
+        .map(|(item_id, item)| (item_id, migrate_item_v1_to_v2(item)))
+        .collect();
+
+    let equip = |item: v1::Item| {
+        let item = migrate_item_v1_to_v2(item);
+        items
+            .iter()
+            .map(|(_, owned)| owned)
+            .find(|owned| owned.definition_id == item.definition_id)
+            .cloned()
+            .unwrap_or(item)
+    };
+    let player_visuals = v2::PlayerVisuals {
+        skeleton: equip(old.player_visuals.skeleton),
+        head: equip(old.player_visuals.head),
+        torso: equip(old.player_visuals.torso),
+        leg: equip(old.player_visuals.leg),
+        arm: equip(old.player_visuals.arm),
+        weapon_1: equip(old.player_visuals.weapon_1),
+    };
+
+    v2::CoreInformation {
+        player_id: old.player_id,
+        player_visuals,
+        inventory: v2::Inventory { items },
+        currency: old.currency,
+    }
+}
+
+/// v1 -> v2 Catalog id comes from the gltf file name
+fn migrate_item_v1_to_v2(old: v1::Item) -> v2::Item {
+    let definition_id = old
+        .file_path
+        .rsplit('/')
+        .next()
+        .and_then(|file_name| file_name.split('.').next())
+        .unwrap_or(&old.file_path)
+        .to_string();
+    v2::Item {
+        id: old.id,
+        definition_id,
+        name: old.name,
+        file_path: old.file_path,
+        item_type: old.item_type,
+    }
+}
+
+/// v2 -> v3 Inventory gets stacks and a capacity
+fn migrate_map_v2_to_v3(old: v2::CoreSaveInfoMap) -> v3::CoreSaveInfoMap {
+    v3::CoreSaveInfoMap {
+        map: old
+            .map
+            .into_iter()
+            .map(|(player_uid, core)| (player_uid, migrate_core_v2_to_v3(core)))
+            .collect(),
+    }
+}
+
+/// v2 -> v3 Each old item becomes his own stack of one. We dont merge them as we have no catalog here to tell stack limits,
+/// and we keep everything even if it goes past capacity, capacity is only checked when adding
+fn migrate_core_v2_to_v3(old: v2::CoreInformation) -> v3::CoreInformation {
+    let entries = old
+        .inventory
+        .items
+        .into_iter()
+        .map(|(item_id, item)| (item_id, v3::InventoryEntry { item, count: 1 }))
+        .collect();
+
+    v3::CoreInformation {
+        player_id: old.player_id,
+        player_visuals: old.player_visuals,
+        inventory: v3::Inventory {
+            entries: entries,
+            capacity: DEFAULT_INVENTORY_CAPACITY,
+        },
+        currency: old.currency,
+    }
+}
+
+/// v3 -> v4 Currency becomes integer minor units
+fn migrate_map_v3_to_v4(old: v3::CoreSaveInfoMap) -> v4::CoreSaveInfoMap {
+    v4::CoreSaveInfoMap {
+        map: old
+            .map
+            .into_iter()
+            .map(|(player_uid, core)| (player_uid, migrate_core_v3_to_v4(core)))
+            .collect(),
+    }
+}
+
+/// v3 -> v4 Old float balance is rounded to the nearest minor unit, negative balances could happen back then and become zero
+fn migrate_core_v3_to_v4(old: v3::CoreInformation) -> v4::CoreInformation {
+    let minor_units = (old.currency.amount as f64 * MINOR_UNITS_PER_COIN as f64).round();
+    if minor_units < 0.0 {
+        warn!(
+            "Player had a negative balance {}, resetting it to zero",
+            old.currency.amount
+        );
+    }
+
+    v4::CoreInformation {
+        player_id: old.player_id,
+        player_visuals: old.player_visuals,
+        inventory: old.inventory,
+        // Float to int casts saturate, so negatives become zero
+        currency: v4::Currency {
+            amount: minor_units as u64,
+        },
+    }
+}
+
+/// v4 -> v5 Sole weapon slot becomes main and off hand
+fn migrate_map_v4_to_v5(old: v4::CoreSaveInfoMap) -> v5::CoreSaveInfoMap {
+    v5::CoreSaveInfoMap {
+        map: old
+            .map
+            .into_iter()
+            .map(|(player_uid, core)| (player_uid, migrate_core_v4_to_v5(core)))
+            .collect(),
+    }
+}
+
+/// v4 -> v5 Old weapon goes in the main hand and off hand starts empty. Starter katanas were worn but never owned, so if the player
+/// doesnt have that instance we point to one of the same catalog item, or give it to him. Capacity is ignored as in v2 -> v3
+fn migrate_core_v4_to_v5(old: v4::CoreInformation) -> v5::CoreInformation {
+    let mut inventory = old.inventory;
+    let weapon = old.player_visuals.weapon_1;
+    let main_hand = if inventory
+        .entries
+        .iter()
+        .any(|(item_id, _)| *item_id == weapon.id)
+    {
+        weapon
+    } else if let Some((_, owned)) = inventory
+        .entries
+        .iter()
+        .find(|(_, entry)| entry.item.definition_id == weapon.definition_id)
+    {
+        owned.item.clone()
+    } else {
+        inventory.entries.push((
+            weapon.id,
+            v3::InventoryEntry {
+                item: weapon.clone(),
+                count: 1,
+            },
+        ));
+        weapon
+    };
+
+    v5::CoreInformation {
+        player_id: old.player_id,
+        player_visuals: v5::PlayerVisuals {
+            skeleton: old.player_visuals.skeleton,
+            head: old.player_visuals.head,
+            torso: old.player_visuals.torso,
+            leg: old.player_visuals.leg,
+            arm: old.player_visuals.arm,
+            main_hand: Some(main_hand),
+            off_hand: None,
+        },
+        inventory,
+        currency: old.currency,
+    }
+}
+
+/// v5 -> v6 Players get loadouts
+fn migrate_map_v5_to_v6(old: v5::CoreSaveInfoMap) -> CoreSaveInfoMap {
+    let mut save_info = CoreSaveInfoMap::default();
+    for (player_uid, core) in old.map {
+        save_info
+            .map
+            .insert(player_uid, migrate_core_v5_to_v6(core));
+    }
+    save_info
+}
+
+/// v5 -> v6 Nobody had saved outfits, everyone starts without loadouts. Everything leaves the frozen shapes here
+fn migrate_core_v5_to_v6(old: v5::CoreInformation) -> CoreInformation {
+    let mut inventory = Inventory::empty();
+    inventory.capacity = old.inventory.capacity;
+    for (item_id, entry) in old.inventory.entries {
+        inventory.entries.insert(
+            item_id,
+            InventoryEntry {
+                item: migrate_item_v5_to_v6(entry.item),
+                count: entry.count,
+            },
+        );
+    }
+
+    let id = match old.player_id.id {
+        v0::LegacyClientId::Netcode(id) => ClientId::Netcode(id),
+        v0::LegacyClientId::Steam(id) => ClientId::Steam(id),
+        v0::LegacyClientId::Local(id) => ClientId::Local(id),
+    };
+
+    let visuals = old.player_visuals;
+    CoreInformation {
+        player_id: PlayerId { id },
+        player_visuals: PlayerVisuals {
+            skeleton: migrate_item_v5_to_v6(visuals.skeleton),
+            head: migrate_item_v5_to_v6(visuals.head),
+            torso: migrate_item_v5_to_v6(visuals.torso),
+            leg: migrate_item_v5_to_v6(visuals.leg),
+            arm: migrate_item_v5_to_v6(visuals.arm),
+            main_hand: visuals.main_hand.map(migrate_item_v5_to_v6),
+            off_hand: visuals.off_hand.map(migrate_item_v5_to_v6),
+        },
+        inventory,
+        currency: Currency::new(old.currency.amount),
+        loadouts: Loadouts::default(),
+    }
+}
+
+/// v5 -> v6 Item itself didnt change since v2, only leaves the frozen shape
+fn migrate_item_v5_to_v6(old: v2::Item) -> Item {
+    Item {
+        id: old.id,
+        definition_id: old.definition_id,
+        name: Name::new(old.name),
+        file_path: old.file_path,
+        item_type: match old.item_type {
+            v1::ItemType::Visual => ItemType::Visual,
+            v1::ItemType::Weapon => ItemType::Weapon,
+            v1::ItemType::Skeleton => ItemType::Skeleton,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Save written by our first builds, keyed by client id and without header
+    const SAVE_V0: &[u8] = include_bytes!("../../tests/fixtures/save_v0.bar");
+    /// Save keyed by player uid with header
+    const SAVE_V1: &[u8] = include_bytes!("../../tests/fixtures/save_v1.bar");
+    /// Save whose items carry a catalog id
+    const SAVE_V2: &[u8] = include_bytes!("../../tests/fixtures/save_v2.bar");
+    /// Save whose inventory has stacks and a capacity
+    const SAVE_V3: &[u8] = include_bytes!("../../tests/fixtures/save_v3.bar");
+    /// Save whose currency is integer minor units
+    const SAVE_V4: &[u8] = include_bytes!("../../tests/fixtures/save_v4.bar");
+    /// Save whose visuals have main and off hand weapon slots
+    const SAVE_V5: &[u8] = include_bytes!("../../tests/fixtures/save_v5.bar");
+    /// Save whose players have loadouts
+    const SAVE_V6: &[u8] = include_bytes!("../../tests/fixtures/save_v6.bar");
+    /// Single player records, used by directory and sqlite backends
+    const PLAYER_V0: &[u8] = include_bytes!("../../tests/fixtures/player_v0.bar");
+    const PLAYER_V1: &[u8] = include_bytes!("../../tests/fixtures/player_v1.bar");
+    const PLAYER_V2: &[u8] = include_bytes!("../../tests/fixtures/player_v2.bar");
+    const PLAYER_V3: &[u8] = include_bytes!("../../tests/fixtures/player_v3.bar");
+    const PLAYER_V4: &[u8] = include_bytes!("../../tests/fixtures/player_v4.bar");
+    const PLAYER_V5: &[u8] = include_bytes!("../../tests/fixtures/player_v5.bar");
+    const PLAYER_V6: &[u8] = include_bytes!("../../tests/fixtures/player_v6.bar");
+
+    #[test]
+    fn loads_v0_save_map() {
+        let save_info = decode_save_map(SAVE_V0).expect("v0 save to migrate");
+        assert_eq!(save_info.map.len(), 3);
+        for id in 1..=3 {
+            let core = &save_info.map[&Uuid::from_u64_pair(0, id)];
+            // Five body parts plus the starter katana v5 hands over
+            assert_eq!(core.inventory.entries.len(), 6);
+            assert_eq!(core.player_visuals.head.name.as_str(), "def_m_head.glb");
+        }
+    }
+
+    #[test]
+    fn legacy_saves_are_unreachable_by_accounts() {
+        let save_info = decode_save_map(SAVE_V0).unwrap();
+        for player_uid in save_info.map.keys() {
+            assert_eq!(player_uid.get_version_num(), 0);
+        }
+        // Same generator our token service uses when registering
+        assert_eq!(Uuid::new_v4().get_version_num(), 4);
+    }
+
+    #[test]
+    fn loads_v1_save_map() {
+        assert_eq!(
+            decode_save_map(SAVE_V1).expect("v1 save to load"),
+            decode_save_map(SAVE_V0).expect("v0 save to migrate")
+        );
+    }
+
+    #[test]
+    fn loads_v2_save_map() {
+        assert_eq!(
+            decode_save_map(SAVE_V2).expect("v2 save to load"),
+            decode_save_map(SAVE_V1).expect("v1 save to migrate")
+        );
+    }
+
+    #[test]
+    fn loads_v3_save_map() {
+        let save_info = decode_save_map(SAVE_V3).expect("v3 save to load");
+        assert_eq!(
+            save_info,
+            decode_save_map(SAVE_V2).expect("v2 save to migrate")
+        );
+        for core in save_info.map.values() {
+            assert_eq!(core.inventory.capacity, DEFAULT_INVENTORY_CAPACITY);
+            assert!(core
+                .inventory
+                .entries
+                .values()
+                .all(|entry| entry.count == 1));
+        }
+    }
+
+    #[test]
+    fn loads_v4_save_map() {
+        let save_info = decode_save_map(SAVE_V4).expect("v4 save to load");
+        assert_eq!(
+            save_info,
+            decode_save_map(SAVE_V3).expect("v3 save to migrate")
+        );
+        // Old default balance was 1.0
+        for core in save_info.map.values() {
+            assert_eq!(core.currency.amount, MINOR_UNITS_PER_COIN);
+        }
+    }
+
+    #[test]
+    fn loads_v5_save_map() {
+        let save_info = decode_save_map(SAVE_V5).expect("v5 save to load");
+        assert_eq!(
+            save_info,
+            decode_save_map(SAVE_V4).expect("v4 save to migrate")
+        );
+        for core in save_info.map.values() {
+            let main_hand = core.player_visuals.main_hand.as_ref().unwrap();
+            assert_eq!(core.inventory.entries[&main_hand.id].item, *main_hand);
+            assert!(core.player_visuals.off_hand.is_none());
+        }
+    }
+
+    #[test]
+    fn loads_v6_save_map() {
+        let save_info = decode_save_map(SAVE_V6).expect("v6 save to load");
+        assert_eq!(
+            save_info,
+            decode_save_map(SAVE_V5).expect("v5 save to migrate")
+        );
+        for core in save_info.map.values() {
+            assert!(core.loadouts.saved.is_empty());
+        }
+    }
+
+    #[test]
+    fn migrated_items_point_to_catalog_and_owned_instances() {
+        let save_info = decode_save_map(SAVE_V1).unwrap();
+        for core in save_info.map.values() {
+            let visuals = &core.player_visuals;
+            assert_eq!(visuals.head.definition_id, "def_m_head");
+            assert_eq!(visuals.skeleton.definition_id, "def_m_main_skeleton");
+            assert_eq!(visuals.main_hand.as_ref().unwrap().definition_id, "katana");
+            for worn in visuals
+                .iter_visuals()
+                .chain(visuals.iter_weapons().map(|(_, item)| item))
+            {
+                assert_eq!(core.inventory.entries[&worn.id].item, *worn);
+            }
+        }
+    }
+
+    #[test]
+    fn loads_player_records_of_every_version() {
+        let from_v0 = decode_player(PLAYER_V0).expect("v0 player to load");
+        let from_v1 = decode_player(PLAYER_V1).expect("v1 player to load");
+        let from_v2 = decode_player(PLAYER_V2).expect("v2 player to load");
+        let from_v3 = decode_player(PLAYER_V3).expect("v3 player to load");
+        let from_v4 = decode_player(PLAYER_V4).expect("v4 player to load");
+        let from_v5 = decode_player(PLAYER_V5).expect("v5 player to load");
+        let from_v6 = decode_player(PLAYER_V6).expect("v6 player to load");
+        assert_eq!(from_v0, from_v1);
+        assert_eq!(from_v1, from_v2);
+        assert_eq!(from_v2, from_v3);
+        assert_eq!(from_v3, from_v4);
+        assert_eq!(from_v4, from_v5);
+        assert_eq!(from_v5, from_v6);
+        assert_eq!(from_v0.currency, Currency::default());
+    }
+
+    #[test]
+    fn current_version_round_trips() {
+        let save_info = decode_save_map(SAVE_V0).unwrap();
+        let bytes = encode_with_header(&save_info).unwrap();
+        assert_eq!(split_header(&bytes).0, CURRENT_SAVE_VERSION);
+        assert_eq!(decode_save_map(&bytes).unwrap(), save_info);
+    }
+
+    #[test]
+    fn refuses_unknown_and_corrupted_saves() {
+        let mut newer = SAVE_V6.to_vec();
+        newer[4..8].copy_from_slice(&(CURRENT_SAVE_VERSION + 1).to_le_bytes());
+        assert!(decode_save_map(&newer).is_err());
+        assert!(decode_save_map(&SAVE_V6[..SAVE_V6.len() / 2]).is_err());
+    }
+}
@@ -1,31 +1,62 @@
+use crate::shared::config::CoreConfig;
+use crate::shared::egui::SharedEgui;
 use crate::shared::*;
+use account::ServerAccountPlugin;
+use auth::{load_or_create_private_key, ServerAuthPlugin};
+use autosave::AutosavePlugin;
 use bevy::prelude::*;
+use bevy::state::app::StatesPlugin;
+use combat::ServerCombatPlugin;
 use lightyear::prelude::server::*;
 use lightyear::prelude::*;
+use loadout::ServerLoadoutPlugin;
 use player::ServerPlayerPlugin;
 use save::SavePlugin;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use store::ServerStorePlugin;
+use trade::ServerTradePlugin;
 use world::ServerWorldPlugin;
 
-pub const SERVER_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 5000);
-
 /// Centralization plugin - When we pass in the cli the arg "server" this guy runs
-pub struct CoreServerPlugin;
+pub struct CoreServerPlugin {
+    /// If true we boot without window, renderer and egui. Only the pieces the simulation needs are added
+    pub headless: bool,
+    /// Already merged config, cli flags + config file
+    pub config: CoreConfig,
+}
 
+pub mod account;
+pub mod auth;
+mod autosave;
+mod combat;
+mod ledger;
+mod loadout;
+mod migration;
 mod player;
 mod save;
+pub mod save_cli;
+mod storage;
+mod store;
+mod trade;
 mod world;
 
 impl Plugin for CoreServerPlugin {
     fn build(&self, app: &mut App) {
         // Different from client server doesnt require a lot of things, we usually shouldnt have a screen or render anything on him.
-        // But as we are in development stage we gonna live it the default ones
-        app.add_plugins(DefaultPlugins.set(bevy::log::LogPlugin {
-            level: bevy::log::Level::INFO,
-            ..default()
-        }));
+        // Headless is what we want in CI and cloud vms, the windowed one is nice in development stage because of the inspector
+        if self.headless {
+            add_headless_plugins(app);
+        } else {
+            app.add_plugins(DefaultPlugins.set(bevy::log::LogPlugin {
+                level: bevy::log::Level::INFO,
+                ..default()
+            }));
+            app.add_plugins(SharedEgui);
+        }
         // Add lightyear plugins
-        app.add_plugins(build_server_plugin());
+        app.add_plugins(build_server_plugin(&self.config));
+
+        // Config is also a resource, so systems can easily read it
+        app.insert_resource(self.config.clone());
 
         // Add our shared plugin containing the protocol + other shared behaviour
         app.add_plugins(CoreSharedPlugin);
@@ -34,15 +65,43 @@ impl Plugin for CoreServerPlugin {
         app.add_systems(Startup, start_server);
 
         // Adding our self-made plugins
+        app.add_plugins(ServerAccountPlugin);
         app.add_plugins(SavePlugin);
+        app.add_plugins(AutosavePlugin);
+        app.add_plugins(ServerStorePlugin);
+        app.add_plugins(ServerTradePlugin);
+        app.add_plugins(ServerLoadoutPlugin);
         app.add_plugins(ServerPlayerPlugin);
+        app.add_plugins(ServerCombatPlugin);
         app.add_plugins(ServerWorldPlugin);
+
+        // If not in process, token service should be running via auth-server subcommand
+        if self.config.auth.in_process {
+            app.add_plugins(ServerAuthPlugin);
+        }
     }
 }
 
+/// Callable function - Adds the bare minimum bevy plugins our simulation needs, no window, no renderer, no egui.
+/// Worth noting assets and scenes are kept because our core resources may be loaded through them
+fn add_headless_plugins(app: &mut App) {
+    app.add_plugins(MinimalPlugins);
+    app.add_plugins(bevy::log::LogPlugin {
+        level: bevy::log::Level::INFO,
+        ..default()
+    });
+    app.add_plugins(TransformPlugin);
+    app.add_plugins(HierarchyPlugin);
+    app.add_plugins(StatesPlugin);
+    app.add_plugins(AssetPlugin::default());
+    app.add_plugins(bevy::scene::ScenePlugin);
+    // Turns ctrl c into a graceful exit, so our autosave can flush before dying
+    app.add_plugins(bevy::app::TerminalCtrlCHandlerPlugin);
+}
+
 /// Here we create the lightyear [`ServerPlugins`], a series of system responsible for setuping the logic of our server
 /// It is replication interval, if he shall have input delay, and other similar aspects.
-fn build_server_plugin() -> ServerPlugins {
+fn build_server_plugin(core_config: &CoreConfig) -> ServerPlugins {
     // The NetConfig specifies how we establish a connection with the server.
     // We can use either Steam (in which case we will use steam sockets and there is no need to specify
     // our own io) or Netcode (in which case we need to specify our own io).
@@ -50,10 +109,15 @@ fn build_server_plugin() -> ServerPlugins {
         // The IoConfig will specify the transport to use.
         io: IoConfig {
             // the address specified here is the server_address, because we open a UDP socket on the server
-            transport: ServerTransport::UdpSocket(SERVER_ADDR),
+            transport: ServerTransport::UdpSocket(core_config.network.server_addr()),
+            ..default()
+        },
+        config: NetcodeConfig {
+            protocol_id: core_config.network.protocol_id,
+            // Same key as our token service, only tokens signed by him are accepted
+            private_key: load_or_create_private_key(&core_config.auth.private_key_path),
             ..default()
         },
-        config: NetcodeConfig { ..default() },
     };
     let config = ServerConfig {
         // part of the config needs to be shared between the client and server
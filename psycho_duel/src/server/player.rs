@@ -1,8 +1,8 @@
 use crate::server::ClientId;
+use crate::shared::movement::move_players;
 use crate::shared::protocol::*;
 use bevy::prelude::*;
 use bevy::utils::HashMap;
-use leafwing_input_manager::prelude::*;
 use lightyear::prelude::server::*;
 use lightyear::prelude::*;
 
@@ -32,8 +32,8 @@ impl Plugin for ServerPlayerPlugin {
         // Wait until you receive the newest player input message before you actually replicate to the others
         app.add_systems(PreUpdate, replicate_inputs.after(MainSet::EmitEvents));
 
-        // Fixed update becaue movement should be frame unrelated
-        app.add_systems(FixedUpdate, move_player);
+        // Fixed update becaue movement should be frame unrelated, same function client predicts with
+        app.add_systems(FixedUpdate, move_players::<With<PlayerMarker>>);
 
         // In update because it is an event listener
         app.add_systems(Update, despawns_player_when_disconnects);
@@ -154,23 +154,3 @@ fn replicate_inputs(
             .unwrap()
     }
 }
-
-/// When player action is active - Do action
-fn move_player(mut player_action: Query<(&ActionState<PlayerActions>, &mut Transform)>) {
-    for (player_action, mut transform) in player_action.iter_mut() {
-        if !player_action.get_pressed().is_empty() {
-            if player_action.pressed(&PlayerActions::Forward) {
-                transform.translation += Vec3::new(0.0, 0.0, 0.1);
-            }
-            if player_action.pressed(&PlayerActions::Backward) {
-                transform.translation -= Vec3::new(0.0, 0.0, 0.1);
-            }
-            if player_action.pressed(&PlayerActions::Left) {
-                transform.translation += Vec3::new(0.1, 0.0, 0.0);
-            }
-            if player_action.pressed(&PlayerActions::Right) {
-                transform.translation -= Vec3::new(0.1, 0.0, 0.0);
-            }
-        }
-    }
-}
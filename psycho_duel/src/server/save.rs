@@ -1,33 +1,34 @@
 use crate::client::egui::ChangeCharEvent;
 use crate::server::protocol::*;
+use crate::shared::catalog::CoreItemCatalog;
+use crate::shared::config::CoreConfig;
 use bevy::prelude::*;
-use bincode::{deserialize_from, serialize_into};
 use lightyear::prelude::*;
 use lightyear::server::events::MessageEvent;
-use std::fs::File;
-use std::io::{BufReader, BufWriter, ErrorKind};
 
+use super::account::CoreAccountRegistry;
+use super::autosave::CoreSaveScheduler;
 use super::player::ServerClientIdPlayerMap;
 use super::protocol::{PlayerVisuals, SaveMessage};
+use super::storage::CoreSaveBackend;
 use super::CommonChannel;
 
 /// Plugin utilized to store specific username info, for example: What visuals he currently has? What itens he bought? The list goes on
-/// We shall store that locally via bin files while in dev.
+/// Where we store it depends on the save backend picked in config, bincode file, a folder of files or a sqlite database.
 /// In prod - This will differ a lot, this will be a queryable dataset. That we will have to consult and transform into the resource.
 pub struct SavePlugin;
 
-const SAVE_FILE_PATH: &str = "./psycho_duel/src/server/save_files/player_info.bar";
-
 impl Plugin for SavePlugin {
     fn build(&self, app: &mut App) {
-        // Init resource that is gonna be replicated
+        // Init our save map, he never leaves server. Clients only receive their own core information via message
         app.init_resource::<CoreSaveInfoMap>();
 
-        // Startup because ideally we should only run this once really early
-        app.add_systems(Startup, create_or_read_save_file);
+        // Backend picked via config, config is inserted by core server plugin
+        let save_config = app.world().resource::<CoreConfig>().save.clone();
+        app.insert_resource(CoreSaveBackend::from_config(&save_config));
 
-        // Update because if changes have been made we want to replicate those server changes to client
-        app.add_systems(Update, replicate_resource);
+        // Startup because ideally we should only run this once really early
+        app.add_systems(Startup, load_save);
 
         // Update because we want to keep listening to it
         app.add_systems(Update, handle_new_clients);
@@ -37,193 +38,231 @@ impl Plugin for SavePlugin {
     }
 }
 
-/// A simple function made to ensure that everyone knows to whon we are replicating this resource to
-fn replicate_resource(mut commands: Commands) {
-    commands.replicate_resource::<CoreSaveInfoMap, CommonChannel>(NetworkTarget::All);
-}
-
-/// Evaluates if it is a new client or someone who has already logged in
+/// Evaluates if it is a new client or someone who has already logged in.
+/// Worth noting we find his core information via account, so he keeps everything even if his client id changed.
+/// Clients whose session we still dont know, or who connect before our catalog loaded, wait until both are there or until they disconnect
 fn handle_new_clients(
     mut save_info: ResMut<CoreSaveInfoMap>,
+    mut save_scheduler: ResMut<CoreSaveScheduler>,
+    accounts: Res<CoreAccountRegistry>,
+    catalog: Res<CoreItemCatalog>,
     mut connections: EventReader<ServerConnectEvent>,
+    mut disconnections: EventReader<ServerDisconnectEvent>,
+    mut waiting: Local<Vec<ClientId>>,
+    mut connection_manager: ResMut<ServerConnectionManager>,
     mut commands: Commands,
 ) {
     for event in connections.read() {
-        let client_id = event.client_id;
+        if accounts.player_of(&event.client_id).is_none() {
+            warn!(
+                "Client {} connected before we knew his account session, waiting for it",
+                event.client_id
+            );
+        }
+        waiting.push(event.client_id);
+    }
+    for event in disconnections.read() {
+        waiting.retain(|client_id| *client_id != event.client_id);
+    }
+
+    // New players are built from our catalog, so nobody gets in before it loads
+    if !catalog.is_loaded() {
+        return;
+    }
+
+    let mut still_waiting = Vec::new();
+    for client_id in waiting.drain(..) {
+        let Some(player_uid) = accounts.player_of(&client_id) else {
+            still_waiting.push(client_id);
+            continue;
+        };
         info!("Handling connect event, checking if new player or old player");
 
-        // Check if the client already exists in the save info map
-        if let Some(core_information) = save_info.map.get(&client_id) {
+        // Check if the account already exists in the save info map
+        if let Some(core_information) = save_info.map.get_mut(&player_uid) {
             info!("Old player logging in");
+            // Client id is session based, so we point it to the new one
+            core_information.player_id = PlayerId { id: client_id };
             // Spawn an entity with the existing core information
             commands.spawn(core_information.clone());
+            send_own_core(&mut connection_manager, client_id, core_information);
         } else {
             info!("New player logging in");
             // Handle a new client by creating a default core information and insert him into map
-            let core_information = CoreInformation::new(client_id);
+            let core_information = CoreInformation::new(client_id, &catalog);
             commands.spawn(core_information.clone());
-            save_info.map.insert(client_id, core_information);
+            send_own_core(&mut connection_manager, client_id, &core_information);
 
-            // We use references here because you know i am trying to better my clone usage
-            save(&save_info);
+            save_info.map.insert(player_uid, core_information);
+            save_scheduler.mark_dirty(&player_uid);
         }
     }
+    *waiting = still_waiting;
 }
-/// A simple function that save in bincode files the adjusted resources CoreSaveInfoMap. Should occur everytime we modify that core resource in code,
-/// Example: User modifies current skin, save!
-fn save(save_info_map: &CoreSaveInfoMap) {
-    info!("Saving new information!");
-    // Unwraps here because I dont see how one would be able to just change a const or a already initialized struct field type
-    let mut f = BufWriter::new(File::create(SAVE_FILE_PATH).unwrap());
-    serialize_into(&mut f, &save_info_map).unwrap();
+/// Callable function - Sends that client his own core information, and only to him
+pub fn send_own_core(
+    connection_manager: &mut ServerConnectionManager,
+    client_id: ClientId,
+    core: &CoreInformation,
+) {
+    if connection_manager
+        .send_message_to_target::<CommonChannel, CoreInformationMessage>(
+            &mut CoreInformationMessage { core: core.clone() },
+            NetworkTarget::Single(client_id),
+        )
+        .is_err()
+    {
+        warn!("Couldnt send core information to client {}", client_id)
+    }
 }
 
-/// Currently this creates or reads a bincode file that stores all of our previous users infos
-/// We could later make it into a NOSQL database. Not sure if necessary
-fn create_or_read_save_file(mut commands: Commands) {
-    match File::open(SAVE_FILE_PATH) {
-        Ok(file) => {
-            info!("Managed to open pre-existing save file");
-            let buf_reader = BufReader::new(file);
-
-            let save_info = deserialize_from(buf_reader).unwrap_or_else(|err| {
-                error!("You probably changed core information filed, which means you need to recreate the save file! So for now you have no save");
-                error!("Error type {}",err);
-                CoreSaveInfoMap::default()
-            });
-
-            commands.insert_resource(save_info);
-        }
-        Err(err) if err.kind() == ErrorKind::NotFound => {
-            info!("Save file doesnt currently exist creating a default CoreSaveInfoMap");
-            let mut f = BufWriter::new(
-                File::create(SAVE_FILE_PATH).expect("To be able to create new file"),
-            );
-
-            let save_info = CoreSaveInfoMap::default();
-
-            serialize_into(&mut f, &save_info)
-                .expect("To be able to serialize into new file on startup");
-
-            commands.insert_resource(save_info);
-        }
-        Err(err) => {
-            panic!("Failed to open save file for an unexpected reason: {}", err);
-        }
-    }
+/// Loads every saved player from our backend into our core save info map, old versions are migrated on the fly.
+/// If we fail to load we stop the server, running with an empty map would wipe every player on the next save
+fn load_save(save_backend: Res<CoreSaveBackend>, mut commands: Commands) {
+    let mut backend = save_backend
+        .backend
+        .lock()
+        .expect("Save backend lock to not be poisoned");
+    let save_info = backend.load().unwrap_or_else(|err| {
+        panic!(
+            "Failed to load or migrate save, fix it before booting the server again. Error type {}",
+            err
+        )
+    });
+    info!("Loaded {} saved players", save_info.map.len());
+    commands.insert_resource(save_info);
 }
 
 /// First - Check save messages optional field sent by client
-/// Second - If they can occur he mutates server entity and by definition confirmed, if not, rollback mechanics - TODO
-/// Third - Save new informations
+/// Second - If they can occur he mutates server entity and by definition confirmed, if not we send a rejection so client rolls back his preview
+/// Third - Mark him dirty, autosave writes it on the next batch
 fn check_client_sent_core_information(
     mut save_from_client: EventReader<MessageEvent<SaveMessage>>,
     mut core_info_map: ResMut<CoreSaveInfoMap>,
+    mut save_scheduler: ResMut<CoreSaveScheduler>,
+    accounts: Res<CoreAccountRegistry>,
     player_map: Res<ServerClientIdPlayerMap>,
-    mut player_visual: Query<&mut PlayerVisuals>,
-    mut player_currency: Query<&mut Currency>,
-    mut player_inventory: Query<&mut Inventory>,
+    mut players: Query<(&mut PlayerVisuals, &Inventory)>,
     mut connection_manager: ResMut<ServerConnectionManager>,
 ) {
     for save_message in save_from_client.read() {
         let message = save_message.message();
-        let client_id = message.id;
+        // Trust the connection not the message, that is who actually sent it
+        let client_id = *save_message.context();
+
+        let Some(player_uid) = accounts.player_of(&client_id) else {
+            warn!(
+                "Client {} sent a save message without an account session",
+                client_id
+            );
+            continue;
+        };
+
+        let Some(change_char) = &message.change_char else {
+            continue;
+        };
 
-        if let Some(mut previous_core) = core_info_map.map.get_mut(&client_id) {
-            let player_entity = player_map.map.get(&client_id).unwrap();
+        if let Some(previous_core) = core_info_map.map.get_mut(&player_uid) {
+            let Some(player_entity) = player_map.map.get(&client_id) else {
+                warn!("Client {} sent a save message without a player", client_id);
+                continue;
+            };
+            let Ok((mut server_visual, player_inventory)) = players.get_mut(*player_entity) else {
+                continue;
+            };
 
             // Handle visual changes
-            validate_visual_change(
-                &message.change_char,
-                &mut previous_core,
-                &mut player_visual,
-                &player_inventory,
-                *player_entity,
-            );
+            match validate_visual_change(change_char, &mut server_visual, player_inventory) {
+                Ok(confirmed_change) => {
+                    previous_core.player_visuals = server_visual.clone();
 
-            // Handle currency changes
-            validate_currency_change(
-                &message.change_currency,
-                previous_core,
-                &mut player_currency,
-                *player_entity,
-            );
+                    // Broadcast visual changes to the others
+                    let mut message = SaveMessage {
+                        id: client_id,
+                        change_char: Some(confirmed_change),
+                    };
+                    if connection_manager
+                        .send_message_to_target::<CommonChannel, SaveMessage>(
+                            &mut message,
+                            NetworkTarget::AllExceptSingle(client_id),
+                        )
+                        .is_err()
+                    {
+                        warn!("Even tho server gave the okay couldnt broadcast message to all clients!")
+                    }
 
-            // Handle inventory changes
-            validate_inventory_change(
-                &message.change_inventory,
-                previous_core,
-                &mut player_inventory,
-                *player_entity,
-            );
+                    // Owner gets his confirmed core information
+                    send_own_core(&mut connection_manager, client_id, previous_core);
 
-            let mut message = save_message.message().clone();
-            // Broadcast save message
-            if connection_manager
-                .send_message_to_target::<CommonChannel, SaveMessage>(
-                    &mut message,
-                    NetworkTarget::AllExceptSingle(client_id),
-                )
-                .is_err()
-            {
-                warn!("Even tho server gave the okay couldnt broadcast message to all clients!")
+                    // Save core information
+                    save_scheduler.mark_dirty(&player_uid);
+                }
+                Err(mut rejection) => {
+                    warn!(
+                        "Refused visual change of client {}: {}",
+                        client_id, rejection.reason
+                    );
+                    if connection_manager
+                        .send_message_to_target::<CommonChannel, VisualChangeRejected>(
+                            &mut *rejection,
+                            NetworkTarget::Single(client_id),
+                        )
+                        .is_err()
+                    {
+                        warn!("Couldnt tell client {} to rollback his visuals", client_id)
+                    }
+                }
             }
-
-            // Save core information
-            save(&core_info_map);
         }
     }
 }
 
+/// Callable function - Only equips items the player actually owns. As client previews have no instance id, we find an owned instance
+/// of the same catalog item and equip that one, so our visuals always point to a real inventory item. If he doesnt own it we tell him what is the confirmed part
+/// Weapons only go in hands and body parts only in the body, both hands may hold the same weapon only if he owns two of it
 fn validate_visual_change(
-    change_char: &Option<ChangeCharEvent>,
-    previous_core: &mut CoreInformation,
-    player_visual: &mut Query<&mut PlayerVisuals>,
-    player_inventory: &Query<&mut Inventory>,
-    player_entity: Entity,
-) {
-    if let Some(change_visual) = change_char {
-        let mut server_visual = player_visual.get_mut(player_entity).unwrap();
-        let player_inventory = player_inventory.get(player_entity).unwrap();
-        let body_part = &change_visual.body_part;
-        let old_item = server_visual.get_visual_mut(body_part);
-        let new_item = &change_visual.item;
-        if player_inventory.items.get(&new_item.id).is_some() {
-            *old_item = new_item.clone();
-            previous_core.player_visuals = server_visual.clone();
-        } else {
-            *old_item = new_item.clone();
-            previous_core.player_visuals = server_visual.clone();
-            warn!("Validation failed: insufficient inventory. TODO: Rollback mechanic");
-        }
-    }
-}
+    change_visual: &ChangeCharEvent,
+    server_visual: &mut PlayerVisuals,
+    player_inventory: &Inventory,
+) -> Result<ChangeCharEvent, Box<VisualChangeRejected>> {
+    let body_part = &change_visual.body_part;
+    let new_item = &change_visual.item;
 
-fn validate_currency_change(
-    change_currency: &Option<Currency>,
-    previous_core: &mut CoreInformation,
-    player_currency: &mut Query<&mut Currency>,
-    player_entity: Entity,
-) {
-    if let Some(currency) = change_currency {
-        let mut prev_currency = player_currency.get_mut(player_entity).unwrap();
-        if currency.amount < 0.0 {
-            warn!("Validation failed: negative currency. TODO: Handle negative currency");
-        }
-        *prev_currency = *currency;
-        previous_core.currency = *prev_currency;
-    }
-}
+    let reject = |reason: String| {
+        Box::new(VisualChangeRejected {
+            body_part: body_part.clone(),
+            rejected: new_item.clone(),
+            confirmed: server_visual.get_visual(body_part).cloned(),
+            reason: reason,
+        })
+    };
 
-fn validate_inventory_change(
-    change_inventory: &Option<Inventory>,
-    previous_core: &mut CoreInformation,
-    player_inventory: &mut Query<&mut Inventory>,
-    player_entity: Entity,
-) {
-    if let Some(inventory) = change_inventory {
-        let mut prev_inventory = player_inventory.get_mut(player_entity).unwrap();
-        *prev_inventory = inventory.clone();
-        previous_core.inventory = inventory.clone();
+    if player_inventory.count_of(&new_item.definition_id) == 0 {
+        return Err(reject(format!("You dont own {}", new_item)));
     }
+    let Some(owned_item) =
+        player_inventory.find_spare(&new_item.definition_id, server_visual, body_part)
+    else {
+        return Err(reject(format!(
+            "Every {} you own is already equipped",
+            new_item
+        )));
+    };
+    // Trust our own instance not the client preview
+    let expected_type = if body_part.is_weapon() {
+        ItemType::Weapon
+    } else {
+        ItemType::Visual
+    };
+    if owned_item.item_type != expected_type {
+        return Err(reject(format!("{} doesnt go in {:?}", new_item, body_part)));
+    }
+    let owned_item = owned_item.clone();
+
+    server_visual.set_visual(body_part, owned_item.clone());
+    Ok(ChangeCharEvent {
+        client_id: change_visual.client_id,
+        body_part: body_part.clone(),
+        item: owned_item,
+    })
 }
@@ -0,0 +1,420 @@
+use crate::shared::catalog::ItemCatalog;
+use crate::shared::config::CoreConfig;
+use crate::shared::protocol::{CoreInformation, CoreSaveInfoMap, Item};
+use clap::{Subcommand, ValueEnum};
+use std::fs;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+use super::account::AccountRegistry;
+use super::ledger::{Counterparty, LedgerEntry, LedgerReason};
+use super::storage::CoreSaveBackend;
+
+/// Same catalog our asset loader reads, but straight from disk
+const CATALOG_FILE_PATH: &str = "./psycho_duel/assets/items.catalog.ron";
+
+/// Offline tools to inspect and edit our save, they talk straight to the save backend picked in config.
+/// No bevy app and no networking is started.
+/// IMPORTANT - Dont edit while the server is running, his autosave would overwrite your changes
+#[derive(Subcommand, PartialEq, Debug)]
+pub enum SaveCommand {
+    /// Prints the whole core save info map
+    Dump {
+        /// Output format
+        #[arg(long, value_enum, default_value_t = DumpFormat::Json)]
+        format: DumpFormat,
+        /// Writes into this file instead of printing
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Prints the core information of one player
+    Show {
+        /// Player uid or account username
+        player: String,
+    },
+    /// Gives an item to a player, example: katana
+    GrantItem {
+        /// Player uid or account username
+        player: String,
+        /// Catalog item id
+        item_id: String,
+    },
+    /// Takes one of an item from a player, equipped items are refused
+    RemoveItem {
+        /// Player uid or account username
+        player: String,
+        /// Item instance id, catalog id or item name
+        item: String,
+    },
+    /// Adds currency to a player, recorded in our ledger
+    GrantCurrency {
+        /// Player uid or account username
+        player: String,
+        /// How much in minor units, 100 = 1 coin
+        amount: u64,
+    },
+    /// Removes currency from a player, he cant go below zero. Recorded in our ledger
+    RemoveCurrency {
+        /// Player uid or account username
+        player: String,
+        /// How much in minor units, 100 = 1 coin
+        amount: u64,
+    },
+    /// Prints our ledger, every balance change ever made
+    Ledger {
+        /// Only show entries of this player uid or account username
+        player: Option<String>,
+    },
+    /// Reads a json dump and writes every player in it back into our save. Players absent from the json are kept
+    Import {
+        /// Json file made via dump
+        input: PathBuf,
+    },
+    /// Hands a save from before accounts existed to an account. Make sure that account really is his owner first,
+    /// legacy client ids were never authenticated
+    ClaimLegacy {
+        /// Client id that legacy save was keyed by
+        legacy_id: u64,
+        /// Account username receiving it
+        username: String,
+        /// Replaces the save that account already has, otherwise we refuse
+        #[arg(long)]
+        replace: bool,
+    },
+}
+
+/// Human readable formats we can dump to
+#[derive(ValueEnum, Clone, Copy, PartialEq, Debug)]
+pub enum DumpFormat {
+    Json,
+    Ron,
+}
+
+/// Callable function - Runs the given save command, if it fails we print why and exit with an error code
+pub fn run_save_command(command: SaveCommand, core_config: &CoreConfig) {
+    if let Err(err) = execute(command, core_config) {
+        eprintln!("Save command failed: {}", err);
+        std::process::exit(1);
+    }
+}
+
+/// Loads the save, applies the command and stores whoever changed
+fn execute(command: SaveCommand, core_config: &CoreConfig) -> Result<(), String> {
+    let save_backend = CoreSaveBackend::from_config(&core_config.save);
+    let mut save_info = save_backend
+        .backend
+        .lock()
+        .expect("Save backend lock to not be poisoned")
+        .load()?;
+
+    match command {
+        SaveCommand::Dump { format, output } => {
+            let text = to_text(&save_info, format)?;
+            match output {
+                Some(path) => fs::write(&path, text).map_err(|err| err.to_string())?,
+                None => println!("{}", text),
+            }
+        }
+        SaveCommand::Show { player } => {
+            let player_uid = find_player(&save_info, &player)?;
+            println!(
+                "{}",
+                to_text(&save_info.map[&player_uid], DumpFormat::Json)?
+            );
+        }
+        SaveCommand::GrantItem { player, item_id } => {
+            let player_uid = find_player(&save_info, &player)?;
+            let catalog = ItemCatalog::read(Path::new(CATALOG_FILE_PATH))?;
+            let Some(definition) = catalog.items.iter().find(|item| item.id == item_id) else {
+                return Err(format!("Catalog doesnt have {}", item_id));
+            };
+            let item = Item::new_instance(definition);
+            let core = save_info.map.get_mut(&player_uid).unwrap();
+            let stack_id = core
+                .inventory
+                .add(item.clone(), 1, definition)
+                .map_err(|err| err.to_string())?;
+            println!("Granting {} with id {} to {}", item, stack_id, player_uid);
+            store(&save_backend, &player_uid, core)?;
+        }
+        SaveCommand::RemoveItem { player, item } => {
+            let player_uid = find_player(&save_info, &player)?;
+            let core = save_info.map.get_mut(&player_uid).unwrap();
+            let Some(found) = core
+                .inventory
+                .entries
+                .values()
+                .map(|entry| &entry.item)
+                .find(|owned| {
+                    owned.id.to_string() == item
+                        || owned.definition_id == item
+                        || owned.name.as_str() == item
+                })
+                .cloned()
+            else {
+                return Err(format!("Player {} doesnt own item {}", player_uid, item));
+            };
+            println!(
+                "Removing one {} with id {} from {}",
+                found, found.id, player_uid
+            );
+            core.inventory
+                .remove_unequipped(&found.id, 1, &core.player_visuals)
+                .map_err(|err| err.to_string())?;
+            store(&save_backend, &player_uid, core)?;
+        }
+        SaveCommand::GrantCurrency { player, amount } => {
+            let player_uid = find_player(&save_info, &player)?;
+            let core = save_info.map.get_mut(&player_uid).unwrap();
+            let balance = core
+                .currency
+                .checked_add(amount)
+                .map_err(|err| err.to_string())?;
+            println!("{} now has {} currency", player_uid, core.currency);
+            let entry = LedgerEntry::new(
+                player_uid,
+                amount as i64,
+                balance,
+                LedgerReason::AdminGrant,
+                Counterparty::Admin,
+                None,
+                None,
+            );
+            store_with_ledger(&save_backend, &player_uid, core, entry)?;
+        }
+        SaveCommand::RemoveCurrency { player, amount } => {
+            let player_uid = find_player(&save_info, &player)?;
+            let core = save_info.map.get_mut(&player_uid).unwrap();
+            let balance = core
+                .currency
+                .checked_sub(amount)
+                .map_err(|err| err.to_string())?;
+            println!("{} now has {} currency", player_uid, core.currency);
+            let entry = LedgerEntry::new(
+                player_uid,
+                -(amount as i64),
+                balance,
+                LedgerReason::AdminRemoval,
+                Counterparty::Admin,
+                None,
+                None,
+            );
+            store_with_ledger(&save_backend, &player_uid, core, entry)?;
+        }
+        SaveCommand::Ledger { player } => {
+            let player_uid = match player {
+                Some(player) => Some(find_player(&save_info, &player)?),
+                None => None,
+            };
+            let ledger = save_backend
+                .backend
+                .lock()
+                .expect("Save backend lock to not be poisoned")
+                .load_ledger()?;
+            for entry in ledger
+                .iter()
+                .filter(|entry| player_uid.is_none_or(|uid| entry.player_uid == uid))
+            {
+                println!("{}", entry);
+            }
+        }
+        SaveCommand::Import { input } => {
+            let text = fs::read_to_string(&input).map_err(|err| err.to_string())?;
+            let imported: CoreSaveInfoMap =
+                serde_json::from_str(&text).map_err(|err| err.to_string())?;
+            let players: Vec<(Uuid, CoreInformation)> = imported.map.into_iter().collect();
+            let failed = save_backend.store_players(&players);
+            if !failed.is_empty() {
+                return Err(format!("Failed to import players {:?}", failed));
+            }
+            println!(
+                "Imported {} players from {}",
+                players.len(),
+                input.display()
+            );
+        }
+        SaveCommand::ClaimLegacy {
+            legacy_id,
+            username,
+            replace,
+        } => {
+            let player_uid = AccountRegistry::read()?
+                .account(&username)
+                .map(|account| account.player_uid)
+                .ok_or_else(|| format!("No account named {}", username))?;
+            claim_legacy(
+                &save_backend,
+                &mut save_info,
+                legacy_id,
+                player_uid,
+                replace,
+            )?;
+            println!(
+                "Legacy save of client {} now belongs to {} ({})",
+                legacy_id, username, player_uid
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Callable function - Moves a legacy save, keyed by his old client id, to that account player uid.
+/// Account is written first and only then the legacy save removed, a crash in between leaves a copy behind but never loses it
+fn claim_legacy(
+    save_backend: &CoreSaveBackend,
+    save_info: &mut CoreSaveInfoMap,
+    legacy_id: u64,
+    player_uid: Uuid,
+    replace: bool,
+) -> Result<(), String> {
+    let legacy_uid = Uuid::from_u64_pair(0, legacy_id);
+    if !save_info.map.contains_key(&legacy_uid) {
+        return Err(format!("No legacy save for client {}", legacy_id));
+    }
+    if save_info.map.contains_key(&player_uid) && !replace {
+        return Err(format!(
+            "Player {} already has a save, pass --replace to overwrite it",
+            player_uid
+        ));
+    }
+    let core = save_info.map.remove(&legacy_uid).unwrap();
+    let mut backend = save_backend
+        .backend
+        .lock()
+        .expect("Save backend lock to not be poisoned");
+    backend.store_player(&player_uid, &core)?;
+    backend.remove_player(&legacy_uid)?;
+    save_info.map.insert(player_uid, core);
+    Ok(())
+}
+
+/// Callable function - Finds a player either by his uid or by the username of his account
+fn find_player(save_info: &CoreSaveInfoMap, player: &str) -> Result<Uuid, String> {
+    let player_uid = match Uuid::parse_str(player) {
+        Ok(player_uid) => player_uid,
+        Err(_) => AccountRegistry::read()?
+            .account(player)
+            .map(|account| account.player_uid)
+            .ok_or_else(|| format!("No account named {}", player))?,
+    };
+    if save_info.map.contains_key(&player_uid) {
+        Ok(player_uid)
+    } else {
+        Err(format!("Player {} has no save", player_uid))
+    }
+}
+
+/// Callable function - Writes one player straight away, no autosave here
+fn store(
+    save_backend: &CoreSaveBackend,
+    player_uid: &Uuid,
+    core: &CoreInformation,
+) -> Result<(), String> {
+    save_backend
+        .backend
+        .lock()
+        .expect("Save backend lock to not be poisoned")
+        .store_player(player_uid, core)
+}
+
+/// Callable function - Same as store but records the balance change first, just like our autosave does
+fn store_with_ledger(
+    save_backend: &CoreSaveBackend,
+    player_uid: &Uuid,
+    core: &CoreInformation,
+    entry: LedgerEntry,
+) -> Result<(), String> {
+    save_backend
+        .backend
+        .lock()
+        .expect("Save backend lock to not be poisoned")
+        .append_ledger(&[entry])?;
+    store(save_backend, player_uid, core)
+}
+
+/// Callable function - Pretty prints anything serializable in the given format
+fn to_text<T: serde::Serialize>(value: &T, format: DumpFormat) -> Result<String, String> {
+    match format {
+        DumpFormat::Json => serde_json::to_string_pretty(value).map_err(|err| err.to_string()),
+        DumpFormat::Ron => ron::ser::to_string_pretty(value, ron::ser::PrettyConfig::default())
+            .map_err(|err| err.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::storage::{DirectoryBackend, SaveBackend};
+    use crate::shared::catalog::CoreItemCatalog;
+    use crate::shared::protocol::Currency;
+    use lightyear::prelude::ClientId;
+    use std::sync::{Arc, Mutex};
+
+    /// Directory backend in a throwaway folder holding one legacy save and, maybe, the account save
+    fn setup(account_save: Option<Uuid>) -> (tempfile::TempDir, CoreSaveBackend) {
+        let dir = tempfile::tempdir().unwrap();
+        let mut backend = DirectoryBackend::new(dir.path().to_str().unwrap(), 1);
+        let mut legacy = CoreInformation::new(ClientId::Netcode(7), &catalog());
+        legacy.currency = Currency::new(4200);
+        backend
+            .store_player(&Uuid::from_u64_pair(0, 7), &legacy)
+            .unwrap();
+        if let Some(player_uid) = account_save {
+            let fresh = CoreInformation::new(ClientId::Netcode(8), &catalog());
+            backend.store_player(&player_uid, &fresh).unwrap();
+        }
+        let save_backend = CoreSaveBackend {
+            backend: Arc::new(Mutex::new(Box::new(backend))),
+        };
+        (dir, save_backend)
+    }
+
+    fn catalog() -> CoreItemCatalog {
+        let catalog = ItemCatalog::read(Path::new("assets/items.catalog.ron"))
+            .expect("Shipped catalog to be valid");
+        CoreItemCatalog {
+            handle: Default::default(),
+            items: catalog
+                .items
+                .into_iter()
+                .map(|item| (item.id.clone(), item))
+                .collect(),
+        }
+    }
+
+    fn load(save_backend: &CoreSaveBackend) -> CoreSaveInfoMap {
+        save_backend.backend.lock().unwrap().load().unwrap()
+    }
+
+    #[test]
+    fn claiming_moves_legacy_save_to_account() {
+        let player_uid = Uuid::new_v4();
+        let (_dir, save_backend) = setup(None);
+        let mut save_info = load(&save_backend);
+
+        assert!(claim_legacy(&save_backend, &mut save_info, 8, player_uid, false).is_err());
+        claim_legacy(&save_backend, &mut save_info, 7, player_uid, false).unwrap();
+
+        let stored = load(&save_backend);
+        assert_eq!(stored, save_info);
+        assert!(!stored.map.contains_key(&Uuid::from_u64_pair(0, 7)));
+        assert_eq!(stored.map[&player_uid].currency, Currency::new(4200));
+        // Nobody claims it twice
+        assert!(claim_legacy(&save_backend, &mut save_info, 7, Uuid::new_v4(), false).is_err());
+    }
+
+    #[test]
+    fn claiming_over_an_existing_save_needs_replace() {
+        let player_uid = Uuid::new_v4();
+        let (_dir, save_backend) = setup(Some(player_uid));
+        let mut save_info = load(&save_backend);
+
+        assert!(claim_legacy(&save_backend, &mut save_info, 7, player_uid, false).is_err());
+        assert_eq!(load(&save_backend), save_info);
+
+        claim_legacy(&save_backend, &mut save_info, 7, player_uid, true).unwrap();
+        let stored = load(&save_backend);
+        assert_eq!(stored.map.len(), 1);
+        assert_eq!(stored.map[&player_uid].currency, Currency::new(4200));
+    }
+}
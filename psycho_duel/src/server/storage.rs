@@ -0,0 +1,718 @@
+use crate::shared::config::{SaveBackendKind, SaveConfig};
+use crate::shared::protocol::{CoreInformation, CoreSaveInfoMap};
+use bevy::prelude::*;
+use bevy::utils::HashSet;
+use rusqlite::{params, Connection};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+use super::ledger::LedgerEntry;
+use super::migration::{
+    decode_player, decode_save_map, encode_with_header, save_version, CURRENT_SAVE_VERSION,
+};
+
+/// Abstraction over where our core information lives. Save plugin only talks to this guy, so swapping
+/// a local file for a queryable dataset is just a config change
+/// IMPORTANT - Every backend must write via encode_with_header and read via the migration decoders, so old saves keep loading
+pub trait SaveBackend: Send + Sync {
+    /// Loads every saved player into our save map
+    fn load(&mut self) -> Result<CoreSaveInfoMap, String>;
+    /// Stores the core information of one sole player
+    fn store_player(&mut self, player_uid: &Uuid, core: &CoreInformation) -> Result<(), String>;
+    /// Stores a batch of players, returns the uids that failed so they can be retried.
+    /// Override it if your backend can write a whole batch at once
+    fn store_players(&mut self, players: &[(Uuid, CoreInformation)]) -> Vec<Uuid> {
+        let mut failed = Vec::new();
+        for (player_uid, core) in players {
+            if let Err(err) = self.store_player(player_uid, core) {
+                error!(
+                    "Failed to save player {} core information {}",
+                    player_uid, err
+                );
+                failed.push(*player_uid);
+            }
+        }
+        failed
+    }
+    /// Deletes the save of that player, deleting a player without save is fine
+    fn remove_player(&mut self, player_uid: &Uuid) -> Result<(), String>;
+    /// Lists the uid of every saved player
+    fn list(&self) -> Result<Vec<Uuid>, String>;
+    /// Appends balance changes to our ledger, entries already written are never touched again.
+    /// Must be idempotent, a retry after a partial write hands us entries that may already be stored
+    fn append_ledger(&mut self, entries: &[LedgerEntry]) -> Result<(), String>;
+    /// Reads the whole ledger, oldest first
+    fn load_ledger(&self) -> Result<Vec<LedgerEntry>, String>;
+}
+
+/// What our autosave writes at once, dirty players and the ledger entries recorded since the last write.
+/// Also what comes back from a write, containing only what failed
+#[derive(Default)]
+pub struct SaveBatch {
+    pub players: Vec<(Uuid, CoreInformation)>,
+    pub ledger: Vec<LedgerEntry>,
+}
+
+/// Resource that holds whatever save backend our config picked.
+/// Behind an arc mutex because our autosave writes from a background thread
+#[derive(Resource, Clone)]
+pub struct CoreSaveBackend {
+    pub backend: Arc<Mutex<Box<dyn SaveBackend>>>,
+}
+
+impl CoreSaveBackend {
+    /// Builds the backend pointed by our save config
+    pub fn from_config(save_config: &SaveConfig) -> Self {
+        let backend: Box<dyn SaveBackend> = match save_config.backend {
+            SaveBackendKind::BincodeFile => Box::new(BincodeFileBackend::new(
+                &save_config.path,
+                save_config.backups,
+            )),
+            SaveBackendKind::Directory => Box::new(DirectoryBackend::new(
+                &save_config.path,
+                save_config.backups,
+            )),
+            SaveBackendKind::Sqlite => Box::new(SqliteBackend::new(&save_config.path)),
+        };
+        info!(
+            "Using {:?} save backend at {}",
+            save_config.backend, save_config.path
+        );
+        Self {
+            backend: Arc::new(Mutex::new(backend)),
+        }
+    }
+
+    /// Callable function - Stores a batch of players, returns the uids that failed so they can be retried
+    pub fn store_players(&self, players: &[(Uuid, CoreInformation)]) -> Vec<Uuid> {
+        self.backend
+            .lock()
+            .expect("Save backend lock to not be poisoned")
+            .store_players(players)
+    }
+
+    /// Callable function - Writes ledger first and then players, so a balance change that reached our save is always in the ledger.
+    /// If the ledger fails we dont write players at all. Returns whatever failed so it can be retried
+    pub fn store_batch(&self, batch: SaveBatch) -> SaveBatch {
+        let mut backend = self
+            .backend
+            .lock()
+            .expect("Save backend lock to not be poisoned");
+        if !batch.ledger.is_empty() {
+            if let Err(err) = backend.append_ledger(&batch.ledger) {
+                error!(
+                    "Failed to append {} ledger entries, holding players back {}",
+                    batch.ledger.len(),
+                    err
+                );
+                return batch;
+            }
+        }
+        let failed_uids = backend.store_players(&batch.players);
+        SaveBatch {
+            players: batch
+                .players
+                .into_iter()
+                .filter(|(player_uid, _)| failed_uids.contains(player_uid))
+                .collect(),
+            ledger: Vec::new(),
+        }
+    }
+}
+
+/// Our original backend - The whole save map in one sole bincode file. As bincode cant write partially
+/// we keep a copy of the map, and rewrite the whole file every time a player is stored
+pub struct BincodeFileBackend {
+    path: PathBuf,
+    /// How many previous generations of our file we keep
+    backups: usize,
+    cache: CoreSaveInfoMap,
+}
+
+impl BincodeFileBackend {
+    pub fn new(path: &str, backups: usize) -> Self {
+        Self {
+            path: PathBuf::from(path),
+            backups: backups,
+            cache: CoreSaveInfoMap::default(),
+        }
+    }
+
+    /// Writes the cached map into our file
+    fn write(&self) -> Result<(), String> {
+        let bytes = encode_with_header(&self.cache)?;
+        write_atomically(&self.path, &bytes, self.backups)
+    }
+}
+
+impl SaveBackend for BincodeFileBackend {
+    fn load(&mut self) -> Result<CoreSaveInfoMap, String> {
+        match read_with_recovery(&self.path, self.backups, decode_save_map)? {
+            Some(save_info) => {
+                self.cache = save_info;
+            }
+            None => {
+                info!("Save file doesnt currently exist creating a default CoreSaveInfoMap");
+                self.cache = CoreSaveInfoMap::default();
+                self.write()?;
+            }
+        }
+        Ok(self.cache.clone())
+    }
+
+    fn store_player(&mut self, player_uid: &Uuid, core: &CoreInformation) -> Result<(), String> {
+        self.cache.map.insert(*player_uid, core.clone());
+        self.write()
+    }
+
+    /// Whole file gets rewritten anyway, so we write once per batch. Otherwise every player would rotate our backups
+    fn store_players(&mut self, players: &[(Uuid, CoreInformation)]) -> Vec<Uuid> {
+        for (player_uid, core) in players {
+            self.cache.map.insert(*player_uid, core.clone());
+        }
+        match self.write() {
+            Ok(()) => Vec::new(),
+            Err(err) => {
+                error!("Failed to save {} players {}", players.len(), err);
+                players.iter().map(|(player_uid, _)| *player_uid).collect()
+            }
+        }
+    }
+
+    fn remove_player(&mut self, player_uid: &Uuid) -> Result<(), String> {
+        if self.cache.map.remove(player_uid).is_some() {
+            self.write()?;
+        }
+        Ok(())
+    }
+
+    fn list(&self) -> Result<Vec<Uuid>, String> {
+        Ok(self.cache.map.keys().copied().collect())
+    }
+
+    /// Ledger lives next to our file, example player_info.ledger.jsonl
+    fn append_ledger(&mut self, entries: &[LedgerEntry]) -> Result<(), String> {
+        append_json_lines(&self.path.with_extension("ledger.jsonl"), entries)
+    }
+
+    fn load_ledger(&self) -> Result<Vec<LedgerEntry>, String> {
+        read_json_lines(&self.path.with_extension("ledger.jsonl"))
+    }
+}
+
+/// One bincode file per player inside a folder, named after their player uid.
+/// Good when the map gets big as storing a player only rewrites his file
+pub struct DirectoryBackend {
+    dir: PathBuf,
+    /// How many previous generations of each player file we keep
+    backups: usize,
+}
+
+impl DirectoryBackend {
+    pub fn new(dir: &str, backups: usize) -> Self {
+        fs::create_dir_all(dir).expect("To be able to create save directory");
+        Self {
+            dir: PathBuf::from(dir),
+            backups: backups,
+        }
+    }
+
+    /// File path of that specific player
+    fn player_path(&self, player_uid: &Uuid) -> PathBuf {
+        self.dir.join(format!("{}.bar", player_uid))
+    }
+}
+
+impl SaveBackend for DirectoryBackend {
+    fn load(&mut self) -> Result<CoreSaveInfoMap, String> {
+        let mut save_info = CoreSaveInfoMap::default();
+        for player_uid in self.list()? {
+            let path = self.player_path(&player_uid);
+            if let Some(core) = read_with_recovery(&path, self.backups, decode_player)
+                .map_err(|err| format!("Player {} save failed to load {}", player_uid, err))?
+            {
+                save_info.map.insert(player_uid, core);
+            }
+        }
+        Ok(save_info)
+    }
+
+    fn store_player(&mut self, player_uid: &Uuid, core: &CoreInformation) -> Result<(), String> {
+        let bytes = encode_with_header(core)?;
+        write_atomically(&self.player_path(player_uid), &bytes, self.backups)
+    }
+
+    /// Backups stay behind, they dont end in bar so we never load them
+    fn remove_player(&mut self, player_uid: &Uuid) -> Result<(), String> {
+        match fs::remove_file(self.player_path(player_uid)) {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(err.to_string()),
+            _ => Ok(()),
+        }
+    }
+
+    fn list(&self) -> Result<Vec<Uuid>, String> {
+        let entries = fs::read_dir(&self.dir).map_err(|err| err.to_string())?;
+        let mut player_uids = Vec::new();
+        for entry in entries {
+            let path = entry.map_err(|err| err.to_string())?.path();
+            if path.extension().is_some_and(|extension| extension == "bar") {
+                // Ignore strangers in our folder, only uid named files are ours
+                if let Some(player_uid) = uid_from_path(&path) {
+                    player_uids.push(player_uid);
+                }
+            }
+        }
+        Ok(player_uids)
+    }
+
+    /// Ledger lives inside our folder, it doesnt end in bar so list ignores it
+    fn append_ledger(&mut self, entries: &[LedgerEntry]) -> Result<(), String> {
+        append_json_lines(&self.dir.join("ledger.jsonl"), entries)
+    }
+
+    fn load_ledger(&self) -> Result<Vec<LedgerEntry>, String> {
+        read_json_lines(&self.dir.join("ledger.jsonl"))
+    }
+}
+
+/// Ledger files are json lines, one entry per line. Appending never rewrites older entries so no backups are needed,
+/// and being plain text whoever is auditing can simply open it. We fsync so an entry survives a power loss.
+/// A failed write is cut back to where it started, and a line torn by a crash is closed before we append after it
+fn append_json_lines(path: &Path, entries: &[LedgerEntry]) -> Result<(), String> {
+    let mut text = String::new();
+    for entry in entries {
+        text.push_str(&serde_json::to_string(entry).map_err(|err| err.to_string())?);
+        text.push('\n');
+    }
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .read(true)
+        .append(true)
+        .open(path)
+        .map_err(|err| err.to_string())?;
+    let length = file.metadata().map_err(|err| err.to_string())?.len();
+    if length > 0 {
+        let mut last = [0u8; 1];
+        file.seek(SeekFrom::Start(length - 1))
+            .and_then(|_| file.read_exact(&mut last))
+            .map_err(|err| err.to_string())?;
+        if last[0] != b'\n' {
+            text.insert(0, '\n');
+        }
+    }
+    if let Err(err) = file
+        .write_all(text.as_bytes())
+        .and_then(|_| file.sync_all())
+    {
+        // Half an entry left behind would be read as garbage, and whole ones would be written again by our retry
+        let _ = file.set_len(length);
+        return Err(err.to_string());
+    }
+    Ok(())
+}
+
+/// Reads a ledger file, a missing file is an empty ledger. A line cut in half by a crash is skipped,
+/// and so is an entry whose transaction id we already read
+fn read_json_lines(path: &Path) -> Result<Vec<LedgerEntry>, String> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err.to_string()),
+    };
+    let mut seen = HashSet::new();
+    let mut entries = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line.map_err(|err| err.to_string())?;
+        if line.is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<LedgerEntry>(&line) {
+            Ok(entry) => {
+                if seen.insert(entry.id) {
+                    entries.push(entry);
+                }
+            }
+            Err(err) => error!("Skipping unreadable ledger line {}", err),
+        }
+    }
+    Ok(entries)
+}
+
+/// Path of a backup generation, generation 1 is the newest one
+fn backup_path(path: &Path, generation: usize) -> PathBuf {
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(format!(".{}", generation));
+    PathBuf::from(backup)
+}
+
+/// Crash safe write - We never truncate our save file. Instead
+/// -> First - Write everything into a temp file and fsync it, if we crash here the old file is untouched
+/// -> Second - Rotate backups, the current file becomes generation 1 and the oldest generation is discarded
+/// -> Third - Atomically rename the temp file into place, readers either see the old or the new file never half of one
+pub(super) fn write_atomically(path: &Path, bytes: &[u8], backups: usize) -> Result<(), String> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let temp_path = PathBuf::from(temp_path);
+
+    let mut temp_file = File::create(&temp_path).map_err(|err| err.to_string())?;
+    temp_file.write_all(bytes).map_err(|err| err.to_string())?;
+    temp_file.sync_all().map_err(|err| err.to_string())?;
+
+    if backups > 0 && path.exists() {
+        for generation in (1..backups).rev() {
+            let older = backup_path(path, generation);
+            if older.exists() {
+                fs::rename(&older, backup_path(path, generation + 1))
+                    .map_err(|err| err.to_string())?;
+            }
+        }
+        // Copy instead of rename, so there is never a moment without our main file
+        fs::copy(path, backup_path(path, 1)).map_err(|err| err.to_string())?;
+    }
+
+    fs::rename(&temp_path, path).map_err(|err| err.to_string())?;
+
+    // Rename only survives a power loss if the folder itself is synced, some OSes cant open folders so we ignore it there
+    if let Some(parent) = path.parent() {
+        if let Ok(dir) = File::open(parent) {
+            let _ = dir.sync_all();
+        }
+    }
+    Ok(())
+}
+
+/// Reads our file, if it is corrupted or missing we recover from the newest valid backup.
+/// Returns none when there is no file nor backups, meaning a fresh start
+/// Worth noting a save from a newer server is never skipped, falling back to an older backup there would lose data
+pub(super) fn read_with_recovery<T>(
+    path: &Path,
+    backups: usize,
+    decode: fn(&[u8]) -> Result<T, String>,
+) -> Result<Option<T>, String> {
+    let mut first_err = None;
+    for generation in 0..=backups {
+        let candidate = if generation == 0 {
+            path.to_path_buf()
+        } else {
+            backup_path(path, generation)
+        };
+        let bytes = match fs::read(&candidate) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == ErrorKind::NotFound => continue,
+            Err(err) => return Err(err.to_string()),
+        };
+        if save_version(&bytes) > CURRENT_SAVE_VERSION {
+            return decode(&bytes).map(Some);
+        }
+        match decode(&bytes) {
+            Ok(value) => {
+                if generation > 0 {
+                    warn!(
+                        "Save {} was corrupted, recovered from backup {}",
+                        path.display(),
+                        candidate.display()
+                    );
+                }
+                return Ok(Some(value));
+            }
+            Err(err) => {
+                error!("Save {} is unreadable {}", candidate.display(), err);
+                first_err.get_or_insert(err);
+            }
+        }
+    }
+    match first_err {
+        Some(err) => Err(format!(
+            "No valid save nor backup found, first error {}",
+            err
+        )),
+        None => Ok(None),
+    }
+}
+
+/// Grabs the player uid from a file named after him
+fn uid_from_path(path: &Path) -> Option<Uuid> {
+    path.file_stem()
+        .and_then(|stem| stem.to_str())
+        .and_then(|stem| Uuid::parse_str(stem).ok())
+}
+
+/// Embedded sqlite database, one row per player with his versioned bincode core information.
+/// Connection is not sync so we keep it behind a mutex
+pub struct SqliteBackend {
+    connection: Mutex<Connection>,
+}
+
+impl SqliteBackend {
+    pub fn new(path: &str) -> Self {
+        let connection = Connection::open(path).expect("To be able to open sqlite save database");
+        connection
+            .execute(
+                "CREATE TABLE IF NOT EXISTS players (uid TEXT PRIMARY KEY, core BLOB NOT NULL)",
+                [],
+            )
+            .expect("To be able to create players table");
+        connection
+            .execute(
+                "CREATE TABLE IF NOT EXISTS ledger (id INTEGER PRIMARY KEY AUTOINCREMENT, player_uid TEXT NOT NULL, entry TEXT NOT NULL, entry_id TEXT)",
+                [],
+            )
+            .expect("To be able to create ledger table");
+        // Ledgers from before transaction ids lack that column, their old rows keep a null id
+        let has_entry_id = connection
+            .query_row(
+                "SELECT COUNT(*) FROM pragma_table_info('ledger') WHERE name = 'entry_id'",
+                [],
+                |row| row.get::<_, i64>(0),
+            )
+            .expect("To be able to read ledger columns")
+            > 0;
+        if !has_entry_id {
+            connection
+                .execute("ALTER TABLE ledger ADD COLUMN entry_id TEXT", [])
+                .expect("To be able to add transaction ids to ledger");
+        }
+        connection
+            .execute(
+                "CREATE UNIQUE INDEX IF NOT EXISTS ledger_entry_id ON ledger (entry_id)",
+                [],
+            )
+            .expect("To be able to index ledger transaction ids");
+        Self {
+            connection: Mutex::new(connection),
+        }
+    }
+}
+
+impl SaveBackend for SqliteBackend {
+    fn load(&mut self) -> Result<CoreSaveInfoMap, String> {
+        let connection = self.connection.lock().map_err(|err| err.to_string())?;
+        let mut statement = connection
+            .prepare("SELECT uid, core FROM players")
+            .map_err(|err| err.to_string())?;
+        let rows = statement
+            .query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, Vec<u8>>(1)?))
+            })
+            .map_err(|err| err.to_string())?;
+
+        let mut save_info = CoreSaveInfoMap::default();
+        for row in rows {
+            let (uid, bytes) = row.map_err(|err| err.to_string())?;
+            let player_uid = Uuid::parse_str(&uid).map_err(|err| err.to_string())?;
+            let core = decode_player(&bytes)
+                .map_err(|err| format!("Player {} save failed to load {}", player_uid, err))?;
+            save_info.map.insert(player_uid, core);
+        }
+        Ok(save_info)
+    }
+
+    fn store_player(&mut self, player_uid: &Uuid, core: &CoreInformation) -> Result<(), String> {
+        let bytes = encode_with_header(core)?;
+        let connection = self.connection.lock().map_err(|err| err.to_string())?;
+        connection
+            .execute(
+                "INSERT OR REPLACE INTO players (uid, core) VALUES (?1, ?2)",
+                params![player_uid.to_string(), bytes],
+            )
+            .map_err(|err| err.to_string())?;
+        Ok(())
+    }
+
+    fn remove_player(&mut self, player_uid: &Uuid) -> Result<(), String> {
+        let connection = self.connection.lock().map_err(|err| err.to_string())?;
+        connection
+            .execute(
+                "DELETE FROM players WHERE uid = ?1",
+                params![player_uid.to_string()],
+            )
+            .map_err(|err| err.to_string())?;
+        Ok(())
+    }
+
+    fn list(&self) -> Result<Vec<Uuid>, String> {
+        let connection = self.connection.lock().map_err(|err| err.to_string())?;
+        let mut statement = connection
+            .prepare("SELECT uid FROM players")
+            .map_err(|err| err.to_string())?;
+        let rows = statement
+            .query_map([], |row| row.get::<_, String>(0))
+            .map_err(|err| err.to_string())?;
+
+        let mut player_uids = Vec::new();
+        for row in rows {
+            let uid = row.map_err(|err| err.to_string())?;
+            player_uids.push(Uuid::parse_str(&uid).map_err(|err| err.to_string())?);
+        }
+        Ok(player_uids)
+    }
+
+    /// Ledger is a table, entries stay as json so their shape can grow without altering the table.
+    /// Transaction ids are unique, so entries we already stored are ignored
+    fn append_ledger(&mut self, entries: &[LedgerEntry]) -> Result<(), String> {
+        let mut connection = self.connection.lock().map_err(|err| err.to_string())?;
+        let transaction = connection.transaction().map_err(|err| err.to_string())?;
+        for entry in entries {
+            let text = serde_json::to_string(entry).map_err(|err| err.to_string())?;
+            transaction
+                .execute(
+                    "INSERT OR IGNORE INTO ledger (player_uid, entry, entry_id) VALUES (?1, ?2, ?3)",
+                    params![entry.player_uid.to_string(), text, entry.id.to_string()],
+                )
+                .map_err(|err| err.to_string())?;
+        }
+        transaction.commit().map_err(|err| err.to_string())
+    }
+
+    fn load_ledger(&self) -> Result<Vec<LedgerEntry>, String> {
+        let connection = self.connection.lock().map_err(|err| err.to_string())?;
+        let mut statement = connection
+            .prepare("SELECT entry FROM ledger ORDER BY id")
+            .map_err(|err| err.to_string())?;
+        let rows = statement
+            .query_map([], |row| row.get::<_, String>(0))
+            .map_err(|err| err.to_string())?;
+
+        let mut entries = Vec::new();
+        for row in rows {
+            let text = row.map_err(|err| err.to_string())?;
+            entries.push(serde_json::from_str(&text).map_err(|err| err.to_string())?);
+        }
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::ledger::{Counterparty, LedgerReason};
+    use tempfile::tempdir;
+
+    /// Tiny versioned payload, so each generation of a file is told apart by his number
+    fn encode_generation(generation: u32) -> Vec<u8> {
+        encode_with_header(&generation).unwrap()
+    }
+
+    fn decode_generation(bytes: &[u8]) -> Result<u32, String> {
+        if save_version(bytes) != CURRENT_SAVE_VERSION {
+            return Err("Not one of our generations".to_string());
+        }
+        bincode::deserialize(&bytes[8..]).map_err(|err| err.to_string())
+    }
+
+    fn entry(delta: i64) -> LedgerEntry {
+        LedgerEntry::new(
+            Uuid::new_v4(),
+            delta,
+            100,
+            LedgerReason::AdminGrant,
+            Counterparty::Admin,
+            None,
+            None,
+        )
+    }
+
+    #[test]
+    fn write_atomically_rotates_backups() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("save.bar");
+        for generation in 1..=4 {
+            write_atomically(&path, &encode_generation(generation), 2).unwrap();
+        }
+
+        // Path, expected generation in it
+        let expected = [
+            (path.clone(), Some(4)),
+            (backup_path(&path, 1), Some(3)),
+            (backup_path(&path, 2), Some(2)),
+            (backup_path(&path, 3), None),
+            (path.with_extension("bar.tmp"), None),
+        ];
+        for (file, generation) in expected {
+            let found = fs::read(&file)
+                .ok()
+                .map(|bytes| decode_generation(&bytes).unwrap());
+            assert_eq!(found, generation, "{}", file.display());
+        }
+    }
+
+    #[test]
+    fn read_with_recovery_falls_back_to_newest_valid_backup() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("save.bar");
+        assert_eq!(read_with_recovery(&path, 2, decode_generation), Ok(None));
+
+        for generation in 1..=3 {
+            write_atomically(&path, &encode_generation(generation), 2).unwrap();
+        }
+        assert_eq!(read_with_recovery(&path, 2, decode_generation), Ok(Some(3)));
+
+        // Corrupt primary, newest backup wins
+        fs::write(&path, b"half a save").unwrap();
+        assert_eq!(read_with_recovery(&path, 2, decode_generation), Ok(Some(2)));
+
+        // Corrupt newest backup too, older one wins
+        fs::write(backup_path(&path, 1), b"half a save").unwrap();
+        assert_eq!(read_with_recovery(&path, 2, decode_generation), Ok(Some(1)));
+
+        // Nothing valid left
+        fs::write(backup_path(&path, 2), b"half a save").unwrap();
+        assert!(read_with_recovery(&path, 2, decode_generation).is_err());
+
+        // A save from a newer server is never skipped in favour of a backup
+        let mut newer = b"PSDL".to_vec();
+        newer.extend_from_slice(&(CURRENT_SAVE_VERSION + 1).to_le_bytes());
+        fs::write(&path, newer).unwrap();
+        assert!(read_with_recovery(&path, 2, decode_generation).is_err());
+    }
+
+    #[test]
+    fn json_ledger_ignores_retried_and_torn_entries() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("ledger.jsonl");
+        let first = [entry(10), entry(-5)];
+        append_json_lines(&path, &first).unwrap();
+
+        // Crash tore a line in half, then our retry hands the same entries plus a new one
+        let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"{\"id\":\"half").unwrap();
+        let retried = [first[0].clone(), first[1].clone(), entry(3)];
+        append_json_lines(&path, &retried).unwrap();
+
+        assert_eq!(read_json_lines(&path).unwrap(), retried.to_vec());
+    }
+
+    #[test]
+    fn backends_store_retried_ledger_entries_once() {
+        let dir = tempdir().unwrap();
+        let backends: [Box<dyn SaveBackend>; 3] = [
+            Box::new(BincodeFileBackend::new(
+                dir.path().join("player_info.bar").to_str().unwrap(),
+                1,
+            )),
+            Box::new(DirectoryBackend::new(
+                dir.path().join("players").to_str().unwrap(),
+                1,
+            )),
+            Box::new(SqliteBackend::new(
+                dir.path().join("save.sqlite").to_str().unwrap(),
+            )),
+        ];
+
+        for mut backend in backends {
+            let first = [entry(10), entry(-5)];
+            backend.append_ledger(&first).unwrap();
+            let retried = [first[1].clone(), entry(3)];
+            backend.append_ledger(&retried).unwrap();
+
+            let ledger = backend.load_ledger().unwrap();
+            assert_eq!(
+                ledger,
+                vec![first[0].clone(), first[1].clone(), retried[1].clone()]
+            );
+        }
+    }
+}
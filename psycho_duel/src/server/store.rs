@@ -0,0 +1,224 @@
+use crate::server::protocol::*;
+use crate::shared::catalog::CoreItemCatalog;
+use bevy::prelude::*;
+use lightyear::prelude::*;
+use lightyear::server::events::MessageEvent;
+use uuid::Uuid;
+
+use super::account::CoreAccountRegistry;
+use super::autosave::CoreSaveScheduler;
+use super::ledger::{Counterparty, LedgerEntry, LedgerReason};
+use super::player::ServerClientIdPlayerMap;
+use super::save::send_own_core;
+use super::CommonChannel;
+
+/// Plugin responsible for our store, clients only ask to buy or sell an item. Price comes from our catalog, ownership and the actual change are decided here
+pub struct ServerStorePlugin;
+
+impl Plugin for ServerStorePlugin {
+    fn build(&self, app: &mut App) {
+        // Update because they listen to client messages
+        app.add_systems(Update, (handle_buy_requests, handle_sell_requests));
+    }
+}
+
+/// Validates buy requests, if everything is okay takes the money, hands the item and records it in our ledger
+fn handle_buy_requests(
+    mut buy_requests: EventReader<MessageEvent<BuyItemRequest>>,
+    catalog: Res<CoreItemCatalog>,
+    tick_manager: Res<TickManager>,
+    mut core_info_map: ResMut<CoreSaveInfoMap>,
+    mut save_scheduler: ResMut<CoreSaveScheduler>,
+    accounts: Res<CoreAccountRegistry>,
+    player_map: Res<ServerClientIdPlayerMap>,
+    mut players: Query<(&mut Inventory, &mut Currency)>,
+    mut connection_manager: ResMut<ServerConnectionManager>,
+) {
+    for request in buy_requests.read() {
+        let client_id = *request.context();
+        let item_id = &request.message().item_id;
+
+        let Some((player_uid, player_entity)) = find_player(&accounts, &player_map, &client_id)
+        else {
+            continue;
+        };
+        let Some(core) = core_info_map.map.get_mut(&player_uid) else {
+            continue;
+        };
+        let Ok((mut inventory, mut currency)) = players.get_mut(player_entity) else {
+            continue;
+        };
+
+        let balance_before = currency.amount;
+        let result = buy(item_id, &catalog, &mut inventory, &mut currency);
+        if let TransactionResult::Bought { item, .. } = &result {
+            info!("Player {} bought {}", player_uid, item);
+            core.inventory = inventory.clone();
+            core.currency = *currency;
+            save_scheduler.record(LedgerEntry::new(
+                player_uid,
+                currency.amount as i64 - balance_before as i64,
+                currency.amount,
+                LedgerReason::StorePurchase,
+                Counterparty::Store,
+                Some(item.clone()),
+                Some(tick_manager.tick().0),
+            ));
+            send_own_core(&mut connection_manager, client_id, core);
+        }
+        send_result(&mut connection_manager, client_id, result);
+    }
+}
+
+/// Validates sell requests, if everything is okay takes the item, pays the player and records it in our ledger
+fn handle_sell_requests(
+    mut sell_requests: EventReader<MessageEvent<SellItemRequest>>,
+    catalog: Res<CoreItemCatalog>,
+    tick_manager: Res<TickManager>,
+    mut core_info_map: ResMut<CoreSaveInfoMap>,
+    mut save_scheduler: ResMut<CoreSaveScheduler>,
+    accounts: Res<CoreAccountRegistry>,
+    player_map: Res<ServerClientIdPlayerMap>,
+    mut players: Query<(&mut Inventory, &mut Currency, &PlayerVisuals)>,
+    mut connection_manager: ResMut<ServerConnectionManager>,
+) {
+    for request in sell_requests.read() {
+        let client_id = *request.context();
+        let item_id = &request.message().item_id;
+
+        let Some((player_uid, player_entity)) = find_player(&accounts, &player_map, &client_id)
+        else {
+            continue;
+        };
+        let Some(core) = core_info_map.map.get_mut(&player_uid) else {
+            continue;
+        };
+        let Ok((mut inventory, mut currency, visuals)) = players.get_mut(player_entity) else {
+            continue;
+        };
+
+        let balance_before = currency.amount;
+        let result = sell(item_id, &catalog, &mut inventory, &mut currency, visuals);
+        if let TransactionResult::Sold { item, .. } = &result {
+            info!("Player {} sold {}", player_uid, item);
+            core.inventory = inventory.clone();
+            core.currency = *currency;
+            save_scheduler.record(LedgerEntry::new(
+                player_uid,
+                currency.amount as i64 - balance_before as i64,
+                currency.amount,
+                LedgerReason::StoreSale,
+                Counterparty::Store,
+                Some(item.clone()),
+                Some(tick_manager.tick().0),
+            ));
+            send_own_core(&mut connection_manager, client_id, core);
+        }
+        send_result(&mut connection_manager, client_id, result);
+    }
+}
+
+/// Callable function - Buys a catalog item, only mutates inventory and currency if the transaction is valid
+fn buy(
+    item_id: &str,
+    catalog: &CoreItemCatalog,
+    inventory: &mut Inventory,
+    currency: &mut Currency,
+) -> TransactionResult {
+    let Some(definition) = catalog.get(item_id) else {
+        return TransactionResult::Rejected(TransactionError::UnknownItem(item_id.to_string()));
+    };
+    if !definition.purchasable {
+        return TransactionResult::Rejected(TransactionError::NotForSale(item_id.to_string()));
+    }
+    // Charge a copy first, real balance only changes once the item fits
+    let mut new_balance = *currency;
+    if let Err(err) = new_balance.checked_sub(definition.price) {
+        return TransactionResult::Rejected(TransactionError::Currency(err));
+    }
+    let item = Item::new_instance(definition);
+    if let Err(err) = inventory.add(item.clone(), 1, definition) {
+        return TransactionResult::Rejected(TransactionError::Inventory(err));
+    }
+    *currency = new_balance;
+    TransactionResult::Bought {
+        item: item,
+        currency: *currency,
+    }
+}
+
+/// Callable function - Sells one of an owned stack for his catalog sell value, equipped items cant be sold
+fn sell(
+    item_id: &Uuid,
+    catalog: &CoreItemCatalog,
+    inventory: &mut Inventory,
+    currency: &mut Currency,
+    visuals: &PlayerVisuals,
+) -> TransactionResult {
+    // Check catalog before touching the inventory
+    let Some(entry) = inventory.entries.get(item_id) else {
+        return TransactionResult::Rejected(TransactionError::Inventory(InventoryError::NotOwned(
+            *item_id,
+        )));
+    };
+    let Some(definition) = catalog.get(&entry.item.definition_id) else {
+        return TransactionResult::Rejected(TransactionError::UnknownItem(
+            entry.item.definition_id.clone(),
+        ));
+    };
+    // Pay a copy first, real balance only changes once the item leaves
+    let mut new_balance = *currency;
+    if let Err(err) = new_balance.checked_add(definition.sell_value) {
+        return TransactionResult::Rejected(TransactionError::Currency(err));
+    }
+    match inventory.remove_unequipped(item_id, 1, visuals) {
+        Ok(item) => {
+            *currency = new_balance;
+            TransactionResult::Sold {
+                item: item,
+                currency: *currency,
+            }
+        }
+        Err(err) => TransactionResult::Rejected(TransactionError::Inventory(err)),
+    }
+}
+
+/// Callable function - Tell me the player uid and the server entity of that client
+fn find_player(
+    accounts: &CoreAccountRegistry,
+    player_map: &ServerClientIdPlayerMap,
+    client_id: &ClientId,
+) -> Option<(Uuid, Entity)> {
+    let Some(player_uid) = accounts.player_of(client_id) else {
+        warn!(
+            "Client {} sent a store request without a session",
+            client_id
+        );
+        return None;
+    };
+    let Some(player_entity) = player_map.map.get(client_id) else {
+        warn!("Client {} sent a store request without a player", client_id);
+        return None;
+    };
+    Some((player_uid, *player_entity))
+}
+
+/// Callable function - Tells the client how his transaction went
+fn send_result(
+    connection_manager: &mut ServerConnectionManager,
+    client_id: ClientId,
+    mut result: TransactionResult,
+) {
+    if let TransactionResult::Rejected(err) = &result {
+        warn!("Refused transaction of client {}: {}", client_id, err);
+    }
+    if connection_manager
+        .send_message_to_target::<CommonChannel, TransactionResult>(
+            &mut result,
+            NetworkTarget::Single(client_id),
+        )
+        .is_err()
+    {
+        warn!("Couldnt send transaction result to client {}", client_id)
+    }
+}
@@ -0,0 +1,942 @@
+use crate::server::protocol::*;
+use crate::shared::catalog::CoreItemCatalog;
+use bevy::prelude::*;
+use bevy::utils::{HashMap, HashSet};
+use lightyear::prelude::*;
+use lightyear::server::events::MessageEvent;
+use uuid::Uuid;
+
+use super::account::CoreAccountRegistry;
+use super::autosave::CoreSaveScheduler;
+use super::ledger::{Counterparty, LedgerEntry, LedgerReason};
+use super::player::ServerClientIdPlayerMap;
+use super::save::send_own_core;
+use super::CommonChannel;
+
+/// Plugin responsible for player to player trades. Sessions only live in server, clients ask to invite, offer, lock and confirm.
+/// Once both sides confirmed their locked offers we swap everything at once, either all of it changes hands or nothing does
+pub struct ServerTradePlugin;
+
+impl Plugin for ServerTradePlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<TradeSessions>();
+
+        // Update because they listen to client messages
+        app.add_systems(Update, (handle_trade_requests, cancel_trades_on_disconnect));
+    }
+}
+
+/// Every trade going on and every invite waiting for an answer
+#[derive(Resource, Default)]
+pub struct TradeSessions {
+    /// Invitee to who invited him
+    invites: HashMap<ClientId, ClientId>,
+    /// Ongoing sessions
+    sessions: Vec<TradeSession>,
+}
+
+impl TradeSessions {
+    /// Grab the session that client is in
+    fn session_of(&mut self, client_id: &ClientId) -> Option<&mut TradeSession> {
+        self.sessions.iter_mut().find(|session| {
+            session
+                .sides
+                .iter()
+                .any(|side| side.client_id == *client_id)
+        })
+    }
+
+    /// Tell me if that client is in a session
+    fn is_trading(&self, client_id: &ClientId) -> bool {
+        self.sessions.iter().any(|session| {
+            session
+                .sides
+                .iter()
+                .any(|side| side.client_id == *client_id)
+        })
+    }
+
+    /// Removes the session that client is in, returning it
+    fn remove_session_of(&mut self, client_id: &ClientId) -> Option<TradeSession> {
+        let index = self.sessions.iter().position(|session| {
+            session
+                .sides
+                .iter()
+                .any(|side| side.client_id == *client_id)
+        })?;
+        Some(self.sessions.remove(index))
+    }
+
+    /// Stores that invite. Refuses if either side is trading or the partner already has an invite waiting,
+    /// a second one would silently drop the first
+    fn invite(&mut self, inviter: ClientId, partner: ClientId) -> Result<(), TradeError> {
+        if partner == inviter {
+            return Err(TradeError::PartnerUnavailable(partner));
+        }
+        if let Some(busy) = [inviter, partner]
+            .into_iter()
+            .find(|id| self.is_trading(id))
+        {
+            return Err(TradeError::AlreadyTrading(busy));
+        }
+        if self.invites.contains_key(&partner) {
+            return Err(TradeError::InvitePending(partner));
+        }
+        self.invites.insert(partner, inviter);
+        Ok(())
+    }
+
+    /// Takes the invite that client answered, refusing if it isnt the one waiting for him
+    fn take_invite(&mut self, invitee: ClientId, inviter: ClientId) -> Result<(), TradeError> {
+        if self.invites.get(&invitee) != Some(&inviter) {
+            return Err(TradeError::NoInvite(inviter));
+        }
+        self.invites.remove(&invitee);
+        Ok(())
+    }
+
+    /// Starts a session between both, inviter may have started another trade since he invited
+    fn start(&mut self, inviter: ClientId, invitee: ClientId) -> Result<&TradeSession, TradeError> {
+        if self.is_trading(&inviter) {
+            return Err(TradeError::PartnerUnavailable(inviter));
+        }
+        if self.is_trading(&invitee) {
+            return Err(TradeError::AlreadyTrading(invitee));
+        }
+        info!("Trade between {} and {} started", inviter, invitee);
+        self.sessions.push(TradeSession::new(inviter, invitee));
+        Ok(&self.sessions[self.sessions.len() - 1])
+    }
+
+    /// Forgets every invite of that client and removes his session, returning it
+    fn leave(&mut self, client_id: &ClientId) -> Option<TradeSession> {
+        self.invites
+            .retain(|invitee, inviter| invitee != client_id && inviter != client_id);
+        self.remove_session_of(client_id)
+    }
+}
+
+/// A trade between two players
+struct TradeSession {
+    sides: [TradeSide; 2],
+}
+
+/// One of the players in a trade
+struct TradeSide {
+    client_id: ClientId,
+    offer: TradeOffer,
+    phase: TradePhase,
+}
+
+impl TradeSession {
+    fn new(first: ClientId, second: ClientId) -> Self {
+        let side = |client_id| TradeSide {
+            client_id: client_id,
+            offer: TradeOffer::default(),
+            phase: TradePhase::Editing,
+        };
+        Self {
+            sides: [side(first), side(second)],
+        }
+    }
+
+    /// Index of that client in our sides
+    fn side_of(&self, client_id: &ClientId) -> usize {
+        if self.sides[0].client_id == *client_id {
+            0
+        } else {
+            1
+        }
+    }
+
+    /// Session as seen by the given side
+    fn view(&self, side: usize) -> TradeView {
+        let mine = &self.sides[side];
+        let theirs = &self.sides[1 - side];
+        TradeView {
+            partner: theirs.client_id,
+            my_offer: mine.offer.clone(),
+            my_phase: mine.phase,
+            their_offer: theirs.offer.clone(),
+            their_phase: theirs.phase,
+        }
+    }
+
+    /// Any change of offer means both sides must look at it again
+    fn unlock(&mut self) {
+        for side in self.sides.iter_mut() {
+            side.phase = TradePhase::Editing;
+        }
+    }
+
+    /// Replaces that side offer, sending both sides back to editing
+    fn set_offer(&mut self, client_id: &ClientId, offer: TradeOffer) {
+        let side = self.side_of(client_id);
+        self.sides[side].offer = offer;
+        self.unlock();
+    }
+
+    /// Locks that side. Ignored once anybody confirmed, only an offer change may take a confirm back.
+    /// Returns if anything changed
+    fn lock(&mut self, client_id: &ClientId) -> bool {
+        if self
+            .sides
+            .iter()
+            .any(|side| side.phase == TradePhase::Confirmed)
+        {
+            return false;
+        }
+        let side = self.side_of(client_id);
+        self.sides[side].phase = TradePhase::Locked;
+        true
+    }
+
+    /// Confirms that side, both must have locked first. Returns true once both sides confirmed
+    fn confirm(&mut self, client_id: &ClientId) -> Result<bool, TradeError> {
+        if self
+            .sides
+            .iter()
+            .any(|side| side.phase == TradePhase::Editing)
+        {
+            return Err(TradeError::NotLocked);
+        }
+        let side = self.side_of(client_id);
+        self.sides[side].phase = TradePhase::Confirmed;
+        Ok(self
+            .sides
+            .iter()
+            .all(|side| side.phase == TradePhase::Confirmed))
+    }
+}
+
+/// Reads every trade request and moves the sessions along. When both sides confirm we swap
+fn handle_trade_requests(
+    mut trade_requests: EventReader<MessageEvent<TradeRequest>>,
+    mut trades: ResMut<TradeSessions>,
+    catalog: Res<CoreItemCatalog>,
+    tick_manager: Res<TickManager>,
+    mut core_info_map: ResMut<CoreSaveInfoMap>,
+    mut save_scheduler: ResMut<CoreSaveScheduler>,
+    accounts: Res<CoreAccountRegistry>,
+    player_map: Res<ServerClientIdPlayerMap>,
+    mut players: Query<(&mut Inventory, &mut Currency, &PlayerVisuals)>,
+    mut connection_manager: ResMut<ServerConnectionManager>,
+) {
+    for request in trade_requests.read() {
+        let client_id = *request.context();
+
+        match request.message() {
+            TradeRequest::Invite { partner } => {
+                if !player_map.map.contains_key(partner) {
+                    refuse(
+                        &mut connection_manager,
+                        client_id,
+                        TradeError::PartnerUnavailable(*partner),
+                    );
+                    continue;
+                }
+                if let Err(err) = trades.invite(client_id, *partner) {
+                    refuse(&mut connection_manager, client_id, err);
+                    continue;
+                }
+                info!("Client {} invited {} to trade", client_id, partner);
+                send_update(
+                    &mut connection_manager,
+                    *partner,
+                    TradeUpdate::Invited { inviter: client_id },
+                );
+            }
+            TradeRequest::Respond { inviter, accept } => {
+                if let Err(err) = trades.take_invite(client_id, *inviter) {
+                    refuse(&mut connection_manager, client_id, err);
+                    continue;
+                }
+                if !accept {
+                    send_update(
+                        &mut connection_manager,
+                        *inviter,
+                        TradeUpdate::Cancelled {
+                            reason: format!("Player {} declined", client_id),
+                        },
+                    );
+                    continue;
+                }
+                // Inviter may have left meanwhile
+                if !player_map.map.contains_key(inviter) {
+                    refuse(
+                        &mut connection_manager,
+                        client_id,
+                        TradeError::PartnerUnavailable(*inviter),
+                    );
+                    continue;
+                }
+                match trades.start(*inviter, client_id) {
+                    Ok(session) => broadcast_session(&mut connection_manager, session),
+                    Err(err) => refuse(&mut connection_manager, client_id, err),
+                }
+            }
+            TradeRequest::Offer { items, currency } => {
+                let Some(session) = trades.session_of(&client_id) else {
+                    refuse(&mut connection_manager, client_id, TradeError::NoSession);
+                    continue;
+                };
+                let Some(player_entity) = player_map.map.get(&client_id) else {
+                    continue;
+                };
+                let Ok((inventory, balance, visuals)) = players.get(*player_entity) else {
+                    continue;
+                };
+                match build_offer(items, *currency, inventory, balance, visuals) {
+                    Ok(offer) => {
+                        session.set_offer(&client_id, offer);
+                        broadcast_session(&mut connection_manager, session);
+                    }
+                    Err(err) => refuse(&mut connection_manager, client_id, err),
+                }
+            }
+            TradeRequest::Lock => {
+                let Some(session) = trades.session_of(&client_id) else {
+                    refuse(&mut connection_manager, client_id, TradeError::NoSession);
+                    continue;
+                };
+                if session.lock(&client_id) {
+                    broadcast_session(&mut connection_manager, session);
+                }
+            }
+            TradeRequest::Confirm => {
+                let Some(session) = trades.session_of(&client_id) else {
+                    refuse(&mut connection_manager, client_id, TradeError::NoSession);
+                    continue;
+                };
+                match session.confirm(&client_id) {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        broadcast_session(&mut connection_manager, session);
+                        continue;
+                    }
+                    Err(err) => {
+                        refuse(&mut connection_manager, client_id, err);
+                        continue;
+                    }
+                }
+
+                // Both confirmed - Swap. From here on the session is gone, so whatever goes wrong must cancel it on both sides
+                let Some(session) = trades.remove_session_of(&client_id) else {
+                    continue;
+                };
+                let client_ids = [session.sides[0].client_id, session.sides[1].client_id];
+                let (Some(first_uid), Some(second_uid)) = (
+                    accounts.player_of(&client_ids[0]),
+                    accounts.player_of(&client_ids[1]),
+                ) else {
+                    warn!("Trade side without an account session, cancelling");
+                    cancel_both(
+                        &mut connection_manager,
+                        client_ids,
+                        "Player has no account session",
+                    );
+                    continue;
+                };
+                let player_uids = [first_uid, second_uid];
+                let (Some(first_entity), Some(second_entity)) = (
+                    player_map.map.get(&client_ids[0]),
+                    player_map.map.get(&client_ids[1]),
+                ) else {
+                    warn!("Trade side without a player, cancelling");
+                    cancel_both(
+                        &mut connection_manager,
+                        client_ids,
+                        "Player is no longer here",
+                    );
+                    continue;
+                };
+                let Ok([first, second]) = players.get_many_mut([*first_entity, *second_entity])
+                else {
+                    warn!("Trade side without inventory or currency, cancelling");
+                    cancel_both(
+                        &mut connection_manager,
+                        client_ids,
+                        "Player is no longer here",
+                    );
+                    continue;
+                };
+                let (mut first_inventory, mut first_currency, first_visuals) = first;
+                let (mut second_inventory, mut second_currency, second_visuals) = second;
+                let balances_before = [first_currency.amount, second_currency.amount];
+
+                let swapped = swap(
+                    [&session.sides[0].offer, &session.sides[1].offer],
+                    [&mut *first_inventory, &mut *second_inventory],
+                    [&mut *first_currency, &mut *second_currency],
+                    [first_visuals, second_visuals],
+                    &catalog,
+                );
+                let given = match swapped {
+                    Ok(given) => given,
+                    Err(err) => {
+                        // Something changed since they locked, nothing moved. Send them back to editing
+                        warn!(
+                            "Trade between {} and {} failed {}",
+                            client_ids[0], client_ids[1], err
+                        );
+                        let mut session = session;
+                        session.unlock();
+                        broadcast_session(&mut connection_manager, &session);
+                        for client_id in client_ids {
+                            refuse(&mut connection_manager, client_id, err.clone());
+                        }
+                        trades.sessions.push(session);
+                        continue;
+                    }
+                };
+                info!(
+                    "Trade between {} and {} completed",
+                    client_ids[0], client_ids[1]
+                );
+
+                // Ledger - Items first, then currency so balances read in order
+                let tick = Some(tick_manager.tick().0);
+                let inventories = [&*first_inventory, &*second_inventory];
+                let currencies = [*first_currency, *second_currency];
+                for side in 0..2 {
+                    let other = 1 - side;
+                    let sent = session.sides[side].offer.currency.amount;
+                    let received = session.sides[other].offer.currency.amount;
+                    let record = |save_scheduler: &mut CoreSaveScheduler,
+                                  delta: i64,
+                                  balance: u64,
+                                  reason: LedgerReason,
+                                  item: Option<Item>| {
+                        save_scheduler.record(LedgerEntry::new(
+                            player_uids[side],
+                            delta,
+                            balance,
+                            reason,
+                            Counterparty::Player(player_uids[other]),
+                            item,
+                            tick,
+                        ));
+                    };
+                    for entry in given[side].iter() {
+                        record(
+                            &mut save_scheduler,
+                            0,
+                            balances_before[side],
+                            LedgerReason::TradeSent,
+                            Some(entry.item.clone()),
+                        );
+                    }
+                    for entry in given[other].iter() {
+                        record(
+                            &mut save_scheduler,
+                            0,
+                            balances_before[side],
+                            LedgerReason::TradeReceived,
+                            Some(entry.item.clone()),
+                        );
+                    }
+                    if sent > 0 {
+                        record(
+                            &mut save_scheduler,
+                            -(sent as i64),
+                            balances_before[side] - sent,
+                            LedgerReason::TradeSent,
+                            None,
+                        );
+                    }
+                    if received > 0 {
+                        record(
+                            &mut save_scheduler,
+                            received as i64,
+                            currencies[side].amount,
+                            LedgerReason::TradeReceived,
+                            None,
+                        );
+                    }
+
+                    // Save and tell the owner
+                    if let Some(core) = core_info_map.map.get_mut(&player_uids[side]) {
+                        core.inventory = inventories[side].clone();
+                        core.currency = currencies[side];
+                        save_scheduler.mark_dirty(&player_uids[side]);
+                        send_own_core(&mut connection_manager, client_ids[side], core);
+                    }
+                    send_update(
+                        &mut connection_manager,
+                        client_ids[side],
+                        TradeUpdate::Completed,
+                    );
+                }
+            }
+            TradeRequest::Cancel => {
+                let Some(session) = trades.remove_session_of(&client_id) else {
+                    refuse(&mut connection_manager, client_id, TradeError::NoSession);
+                    continue;
+                };
+                info!("Client {} cancelled his trade", client_id);
+                for side in session.sides.iter() {
+                    send_update(
+                        &mut connection_manager,
+                        side.client_id,
+                        TradeUpdate::Cancelled {
+                            reason: format!("Player {} cancelled", client_id),
+                        },
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Whoever leaves takes his trade and invites with him
+fn cancel_trades_on_disconnect(
+    mut disconnections: EventReader<ServerDisconnectEvent>,
+    mut trades: ResMut<TradeSessions>,
+    mut connection_manager: ResMut<ServerConnectionManager>,
+) {
+    for event in disconnections.read() {
+        let client_id = event.client_id;
+        if let Some(session) = trades.leave(&client_id) {
+            info!("Client {} left mid trade, cancelling it", client_id);
+            for side in session.sides.iter() {
+                if side.client_id != client_id {
+                    send_update(
+                        &mut connection_manager,
+                        side.client_id,
+                        TradeUpdate::Cancelled {
+                            reason: format!("Player {} disconnected", client_id),
+                        },
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Callable function - Turns what the client asked into an offer of the exact instances he owns.
+/// Early check only, swap validates everything again as inventories may change until then
+fn build_offer(
+    items: &[(Uuid, u32)],
+    currency: u64,
+    inventory: &Inventory,
+    balance: &Currency,
+    visuals: &PlayerVisuals,
+) -> Result<TradeOffer, TradeError> {
+    let mut seen = HashSet::new();
+    let mut offer = TradeOffer::default();
+    for (item_id, count) in items {
+        if *count == 0 || !seen.insert(*item_id) {
+            return Err(TradeError::InvalidOffer);
+        }
+        // Try it on a copy, so we get the same errors swap would
+        let mut check = inventory.clone();
+        let item = check
+            .remove_unequipped(item_id, *count, visuals)
+            .map_err(TradeError::Inventory)?;
+        offer.items.push(InventoryEntry {
+            item: item,
+            count: *count,
+        });
+    }
+    let mut check_balance = *balance;
+    check_balance
+        .checked_sub(currency)
+        .map_err(TradeError::Currency)?;
+    offer.currency = Currency::new(currency);
+    Ok(offer)
+}
+
+/// Callable function - Swaps both offers atomically. Works on copies and only writes them back if every step passed,
+/// so on error nothing changed. Returns what each side gave
+fn swap(
+    offers: [&TradeOffer; 2],
+    inventories: [&mut Inventory; 2],
+    currencies: [&mut Currency; 2],
+    visuals: [&PlayerVisuals; 2],
+    catalog: &CoreItemCatalog,
+) -> Result<[Vec<InventoryEntry>; 2], TradeError> {
+    let mut new_inventories = [inventories[0].clone(), inventories[1].clone()];
+    let mut new_currencies = [*currencies[0], *currencies[1]];
+    let mut given: [Vec<InventoryEntry>; 2] = [Vec::new(), Vec::new()];
+
+    // First - Take everything out, freeing space before anything comes in
+    for side in 0..2 {
+        for offered in offers[side].items.iter() {
+            let item_id = offered.item.id;
+            let mut item = new_inventories[side]
+                .remove_unequipped(&item_id, offered.count, visuals[side])
+                .map_err(TradeError::Inventory)?;
+            // Part of a stack stayed behind, so what leaves becomes a new instance
+            if new_inventories[side].entries.contains_key(&item_id) {
+                item.id = Uuid::new_v4();
+            }
+            given[side].push(InventoryEntry {
+                item: item,
+                count: offered.count,
+            });
+        }
+        new_currencies[side]
+            .checked_sub(offers[side].currency.amount)
+            .map_err(TradeError::Currency)?;
+    }
+
+    // Second - Hand it to the other side
+    for side in 0..2 {
+        let other = 1 - side;
+        for entry in given[side].iter() {
+            let Some(definition) = catalog.get(&entry.item.definition_id) else {
+                return Err(TradeError::UnknownItem(entry.item.definition_id.clone()));
+            };
+            new_inventories[other]
+                .add(entry.item.clone(), entry.count, definition)
+                .map_err(TradeError::Inventory)?;
+        }
+        new_currencies[other]
+            .checked_add(offers[side].currency.amount)
+            .map_err(TradeError::Currency)?;
+    }
+
+    // Everything passed - Write it back
+    let [first_inventory, second_inventory] = inventories;
+    let [first_currency, second_currency] = currencies;
+    let [new_first_inventory, new_second_inventory] = new_inventories;
+    *first_inventory = new_first_inventory;
+    *second_inventory = new_second_inventory;
+    *first_currency = new_currencies[0];
+    *second_currency = new_currencies[1];
+    Ok(given)
+}
+
+/// Callable function - Sends each side his own view of the session
+fn broadcast_session(connection_manager: &mut ServerConnectionManager, session: &TradeSession) {
+    for side in 0..2 {
+        send_update(
+            connection_manager,
+            session.sides[side].client_id,
+            TradeUpdate::Session(session.view(side)),
+        );
+    }
+}
+
+/// Callable function - Tells both sides their session is gone and why
+fn cancel_both(
+    connection_manager: &mut ServerConnectionManager,
+    client_ids: [ClientId; 2],
+    reason: &str,
+) {
+    for client_id in client_ids {
+        send_update(
+            connection_manager,
+            client_id,
+            TradeUpdate::Cancelled {
+                reason: reason.to_string(),
+            },
+        );
+    }
+}
+
+/// Callable function - Tells the client why we refused
+fn refuse(connection_manager: &mut ServerConnectionManager, client_id: ClientId, err: TradeError) {
+    warn!("Refused trade request of client {}: {}", client_id, err);
+    send_update(connection_manager, client_id, TradeUpdate::Refused(err));
+}
+
+/// Callable function - Sends a trade update to one client
+fn send_update(
+    connection_manager: &mut ServerConnectionManager,
+    client_id: ClientId,
+    mut update: TradeUpdate,
+) {
+    if connection_manager
+        .send_message_to_target::<CommonChannel, TradeUpdate>(
+            &mut update,
+            NetworkTarget::Single(client_id),
+        )
+        .is_err()
+    {
+        warn!("Couldnt send trade update to client {}", client_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::catalog::ItemCatalog;
+    use std::path::Path;
+
+    /// Our shipped catalog, so stacks and unique items follow real definitions
+    fn catalog() -> CoreItemCatalog {
+        let catalog = ItemCatalog::read(Path::new("assets/items.catalog.ron"))
+            .expect("Shipped catalog to be valid");
+        CoreItemCatalog {
+            handle: Handle::default(),
+            items: catalog
+                .items
+                .into_iter()
+                .map(|item| (item.id.clone(), item))
+                .collect(),
+        }
+    }
+
+    fn client(id: u64) -> ClientId {
+        ClientId::Netcode(id)
+    }
+
+    /// Inventory holding count of each catalog item given
+    fn inventory_with(catalog: &CoreItemCatalog, items: &[(&str, u32)]) -> Inventory {
+        let mut inventory = Inventory::empty();
+        for (definition_id, count) in items {
+            let definition = catalog.get(definition_id).unwrap();
+            inventory
+                .add(Item::new_instance(definition), *count, definition)
+                .unwrap();
+        }
+        inventory
+    }
+
+    /// Offer of count of that catalog item straight out of that inventory
+    fn offer_of(inventory: &Inventory, items: &[(&str, u32)], currency: u64) -> TradeOffer {
+        TradeOffer {
+            items: items
+                .iter()
+                .map(|(definition_id, count)| InventoryEntry {
+                    item: inventory.find_definition(definition_id).unwrap().clone(),
+                    count: *count,
+                })
+                .collect(),
+            currency: Currency::new(currency),
+        }
+    }
+
+    /// Total of that catalog item across both inventories
+    fn total_of(inventories: &[Inventory; 2], definition_id: &str) -> u32 {
+        inventories
+            .iter()
+            .map(|inventory| inventory.count_of(definition_id))
+            .sum()
+    }
+
+    #[test]
+    fn invites_refuse_ourselves_busy_players_and_pending_invites() {
+        let mut trades = TradeSessions::default();
+        trades
+            .sessions
+            .push(TradeSession::new(client(3), client(4)));
+
+        // Inviter, partner, expected
+        let steps = [
+            (1, 1, Err(TradeError::PartnerUnavailable(client(1)))),
+            (1, 2, Ok(())),
+            (5, 2, Err(TradeError::InvitePending(client(2)))),
+            (1, 2, Err(TradeError::InvitePending(client(2)))),
+            (1, 3, Err(TradeError::AlreadyTrading(client(3)))),
+            (4, 5, Err(TradeError::AlreadyTrading(client(4)))),
+        ];
+        for (inviter, partner, expected) in steps {
+            assert_eq!(
+                trades.invite(client(inviter), client(partner)),
+                expected,
+                "{} inviting {}",
+                inviter,
+                partner
+            );
+        }
+        // First invite survived the second one
+        assert_eq!(trades.invites.get(&client(2)), Some(&client(1)));
+
+        assert_eq!(
+            trades.take_invite(client(2), client(5)),
+            Err(TradeError::NoInvite(client(5)))
+        );
+        assert_eq!(trades.take_invite(client(2), client(1)), Ok(()));
+        assert_eq!(
+            trades.take_invite(client(2), client(1)),
+            Err(TradeError::NoInvite(client(1)))
+        );
+        // Answered, so he may be invited again
+        assert_eq!(trades.invite(client(5), client(2)), Ok(()));
+    }
+
+    #[test]
+    fn lock_and_confirm_follow_the_phases() {
+        let mut session = TradeSession::new(client(1), client(2));
+        let phases = |session: &TradeSession| [session.sides[0].phase, session.sides[1].phase];
+
+        assert_eq!(session.confirm(&client(1)), Err(TradeError::NotLocked));
+        assert!(session.lock(&client(1)));
+        assert_eq!(session.confirm(&client(1)), Err(TradeError::NotLocked));
+        assert!(session.lock(&client(2)));
+        assert_eq!(session.confirm(&client(1)), Ok(false));
+        assert_eq!(
+            phases(&session),
+            [TradePhase::Confirmed, TradePhase::Locked]
+        );
+
+        // Locking again never takes a confirm back, from either side
+        assert!(!session.lock(&client(1)));
+        assert!(!session.lock(&client(2)));
+        assert_eq!(
+            phases(&session),
+            [TradePhase::Confirmed, TradePhase::Locked]
+        );
+
+        // Offer changes send both back to editing
+        session.set_offer(&client(2), TradeOffer::default());
+        assert_eq!(phases(&session), [TradePhase::Editing, TradePhase::Editing]);
+        assert_eq!(session.confirm(&client(2)), Err(TradeError::NotLocked));
+
+        assert!(session.lock(&client(1)));
+        assert!(session.lock(&client(2)));
+        assert_eq!(session.confirm(&client(2)), Ok(false));
+        assert_eq!(session.confirm(&client(1)), Ok(true));
+    }
+
+    #[test]
+    fn sessions_start_once_and_leaving_ends_them_for_both() {
+        let mut trades = TradeSessions::default();
+        trades.invite(client(1), client(2)).unwrap();
+        trades.invite(client(1), client(3)).unwrap();
+        trades.invite(client(2), client(4)).unwrap();
+        trades.take_invite(client(2), client(1)).unwrap();
+        let session = trades.start(client(1), client(2)).unwrap();
+        assert_eq!(session.view(0).partner, client(2));
+        assert_eq!(session.view(1).partner, client(1));
+        assert!(trades.is_trading(&client(1)) && trades.is_trading(&client(2)));
+
+        // Client 3 answers late, his inviter is busy
+        trades.take_invite(client(3), client(1)).unwrap();
+        assert!(matches!(
+            trades.start(client(1), client(3)),
+            Err(TradeError::PartnerUnavailable(_))
+        ));
+        assert_eq!(
+            trades.invite(client(3), client(2)),
+            Err(TradeError::AlreadyTrading(client(2)))
+        );
+
+        // Cancel from either side removes it for both, along with invites the leaver sent
+        let session = trades.leave(&client(2)).unwrap();
+        assert!(!trades.invites.contains_key(&client(4)));
+        assert_eq!(
+            [session.sides[0].client_id, session.sides[1].client_id],
+            [client(1), client(2)]
+        );
+        assert!(!trades.is_trading(&client(1)) && !trades.is_trading(&client(2)));
+        assert!(trades.remove_session_of(&client(1)).is_none());
+        trades.leave(&client(1));
+        assert!(trades.invites.is_empty());
+    }
+
+    #[test]
+    fn swap_conserves_items_and_currency() {
+        let catalog = catalog();
+        let visuals = PlayerVisuals::starter(&catalog);
+        let mut inventories = [
+            inventory_with(&catalog, &[("def_m_head", 3), ("katana", 1)]),
+            inventory_with(&catalog, &[("katana", 1)]),
+        ];
+        let mut currencies = [Currency::new(500), Currency::new(200)];
+        let offers = [
+            offer_of(&inventories[0], &[("def_m_head", 2)], 100),
+            offer_of(&inventories[1], &[("katana", 1)], 50),
+        ];
+        let kept_head = offers[0].items[0].item.id;
+
+        let [first_inventory, second_inventory] = &mut inventories;
+        let [first_currency, second_currency] = &mut currencies;
+        let given = swap(
+            [&offers[0], &offers[1]],
+            [first_inventory, second_inventory],
+            [first_currency, second_currency],
+            [&visuals, &visuals],
+            &catalog,
+        )
+        .unwrap();
+
+        assert_eq!(given[0].len(), 1);
+        assert_eq!(given[1].len(), 1);
+        // Part of the head stack stayed, so what left is a new instance
+        assert_ne!(given[0][0].item.id, kept_head);
+        assert_eq!(inventories[0].count_of("def_m_head"), 1);
+        assert_eq!(inventories[0].count_of("katana"), 2);
+        assert_eq!(inventories[1].count_of("def_m_head"), 2);
+        assert_eq!(inventories[1].count_of("katana"), 0);
+        assert_eq!(total_of(&inventories, "def_m_head"), 3);
+        assert_eq!(total_of(&inventories, "katana"), 2);
+        assert_eq!(currencies, [Currency::new(450), Currency::new(250)]);
+    }
+
+    #[test]
+    fn failed_swap_changes_nothing() {
+        let catalog = catalog();
+        let owned = [("def_m_head", 2), ("katana", 1), ("def_m_main_skeleton", 1)];
+
+        // Name, first offer, first currency offered, second inventory capacity, first wears his katana, expected
+        let cases: [(&str, &[(&str, u32)], u64, u32, bool, &str); 5] = [
+            ("overdraft", &[], 600, 30, false, "currency"),
+            ("receiver full", &[("katana", 1)], 0, 3, false, "full"),
+            ("worn katana", &[("katana", 1)], 0, 30, true, "equipped"),
+            (
+                "unique owned",
+                &[("def_m_main_skeleton", 1)],
+                0,
+                30,
+                false,
+                "unique",
+            ),
+            (
+                "more than owned",
+                &[("def_m_head", 3)],
+                0,
+                30,
+                false,
+                "not enough",
+            ),
+        ];
+
+        for (name, items, currency, capacity, wearing, reason) in cases {
+            let mut inventories = [
+                inventory_with(&catalog, &owned),
+                inventory_with(&catalog, &owned),
+            ];
+            inventories[1].capacity = capacity;
+            let mut currencies = [Currency::new(500), Currency::new(200)];
+            let mut first_visuals = PlayerVisuals::starter(&catalog);
+            if wearing {
+                first_visuals.main_hand = inventories[0].find_definition("katana").cloned();
+            }
+            let second_visuals = PlayerVisuals::starter(&catalog);
+            let offers = [
+                offer_of(&inventories[0], items, currency),
+                TradeOffer::default(),
+            ];
+            let before = (inventories.clone(), currencies);
+
+            let [first_inventory, second_inventory] = &mut inventories;
+            let [first_currency, second_currency] = &mut currencies;
+            let result = swap(
+                [&offers[0], &offers[1]],
+                [first_inventory, second_inventory],
+                [first_currency, second_currency],
+                [&first_visuals, &second_visuals],
+                &catalog,
+            );
+
+            let matched = match result {
+                Err(TradeError::Currency(CurrencyError::Overdraft { .. })) => "currency",
+                Err(TradeError::Inventory(InventoryError::Full { .. })) => "full",
+                Err(TradeError::Inventory(InventoryError::Equipped(_))) => "equipped",
+                Err(TradeError::Inventory(InventoryError::UniqueOwned(_))) => "unique",
+                Err(TradeError::Inventory(InventoryError::NotEnough { .. })) => "not enough",
+                _ => "something else",
+            };
+            assert_eq!(matched, reason, "{}", name);
+            assert_eq!(
+                (inventories, currencies),
+                before,
+                "{}: swap left changes",
+                name
+            );
+        }
+    }
+}
@@ -0,0 +1,419 @@
+use bevy::asset::io::Reader;
+use bevy::asset::{AssetLoader, LoadContext, LoadState};
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use super::protocol::{AttackKind, ItemType};
+use super::skeleton::{SkeletonClips, MAIN_HAND_BONE, OFF_HAND_BONE, OFF_HAND_GRIP};
+use super::FIXED_TIMESTEP_HZ;
+
+/// Where our catalog lives inside the assets folder
+pub const CATALOG_ASSET_PATH: &str = "items.catalog.ron";
+
+/// Catalog ids every new player starts wearing, they must exist in our catalog
+pub const STARTER_SKELETON: &str = "def_m_main_skeleton";
+pub const STARTER_HEAD: &str = "def_m_head";
+pub const STARTER_TORSO: &str = "def_m_torso";
+pub const STARTER_LEG: &str = "def_m_legs";
+pub const STARTER_ARM: &str = "def_m_arms";
+pub const STARTER_WEAPON: &str = "katana";
+
+/// Plugin responsible for our item catalog, the one source of truth of what items exist, what they cost and where their gltf is.
+/// Shared because server decides prices and ownership with it, and client renders store and customizer with it
+pub struct CatalogPlugin;
+
+impl Plugin for CatalogPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_asset::<ItemCatalog>();
+        app.register_asset_loader(ItemCatalogLoader);
+
+        // Empty until our asset finishes loading
+        app.init_resource::<CoreItemCatalog>();
+
+        // Startup because we want it loading as soon as possible
+        app.add_systems(Startup, load_catalog);
+
+        // Update because catalog may be hot reloaded
+        app.add_systems(Update, (fill_catalog, refuse_broken_catalog));
+
+        // Debug
+        app.register_type::<CoreItemCatalog>();
+    }
+}
+
+/// One entry of our catalog, everything we need to know about an item
+#[derive(Serialize, Deserialize, Reflect, Clone, Debug, PartialEq)]
+pub struct ItemDefinition {
+    /// Stable id of that item, never change it once released as saves and requests point to it
+    pub id: String,
+    /// Pretty name for eguis
+    pub display_name: String,
+    /// Where that item goes on our player
+    pub slot: ItemSlot,
+    /// How rare that item is
+    pub rarity: Rarity,
+    /// How much store charges for it, in currency minor units
+    pub price: u64,
+    /// How much store pays when player sells it, in currency minor units
+    pub sell_value: u64,
+    /// If false store doesnt sell it, it can only be granted
+    pub purchasable: bool,
+    /// How many of him fit in one inventory entry
+    #[serde(default = "default_max_stack")]
+    pub max_stack: u32,
+    /// If true a player may only own one of him
+    #[serde(default)]
+    pub unique: bool,
+    /// Path of his gltf inside assets folder
+    pub gltf_path: String,
+    /// How his blade swings, every weapon must have one
+    #[serde(default)]
+    pub melee: Option<MeleeProfile>,
+}
+
+/// How a weapon swings. Blades are sampled from the hand bone of our skeleton clips once catalog loads, so hits follow the very animation clients play.
+/// Server has no skeleton entities, which is why he reads the clips himself instead of asking a bone where it is
+#[derive(Serialize, Deserialize, Reflect, Clone, Debug, PartialEq)]
+pub struct MeleeProfile {
+    /// Blade thickness, hits are a capsule from hilt to tip
+    pub blade_radius: f32,
+    /// Hilt and tip in the weapon gltf space, which is the main hand bone space as clients parent weapons straight into it
+    pub grip: BladeFrame,
+    /// Skeleton clip of a light swing without his hand suffix, _R swings with the main hand and _L with the off hand
+    pub light_clip: String,
+    /// Same as light clip but for heavy swings
+    pub heavy_clip: String,
+    /// Sampled blades of the main hand, filled by bake swings
+    #[serde(skip)]
+    #[reflect(ignore)]
+    pub main_hand: SwingFrames,
+    /// Sampled blades of the off hand, filled by bake swings
+    #[serde(skip)]
+    #[reflect(ignore)]
+    pub off_hand: SwingFrames,
+}
+
+impl MeleeProfile {
+    /// Skeleton clip that hand plays for that swing
+    pub fn clip(&self, attack: AttackKind, off_hand: bool) -> String {
+        let clip = match attack {
+            AttackKind::Light => &self.light_clip,
+            AttackKind::Heavy => &self.heavy_clip,
+        };
+        format!("{}_{}", clip, if off_hand { "L" } else { "R" })
+    }
+
+    /// Blade positions of that swing
+    pub fn frames(&self, attack: AttackKind, off_hand: bool) -> &[BladeFrame] {
+        let hand = if off_hand {
+            &self.off_hand
+        } else {
+            &self.main_hand
+        };
+        match attack {
+            AttackKind::Light => &hand.light,
+            AttackKind::Heavy => &hand.heavy,
+        }
+    }
+}
+
+/// One blade per active tick of each swing plus his last position, relative to the player feet while he faces +z
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SwingFrames {
+    pub light: Vec<BladeFrame>,
+    pub heavy: Vec<BladeFrame>,
+}
+
+/// Where hilt and tip of a blade are on one tick
+#[derive(Serialize, Deserialize, Reflect, Clone, Copy, Debug, PartialEq)]
+pub struct BladeFrame {
+    pub hilt: Vec3,
+    pub tip: Vec3,
+}
+
+/// Items dont stack unless catalog says so
+fn default_max_stack() -> u32 {
+    1
+}
+
+/// Every place an item may occupy in our player
+#[derive(Serialize, Deserialize, Reflect, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ItemSlot {
+    Head,
+    Torso,
+    Leg,
+    Arm,
+    Weapon,
+    Skeleton,
+}
+
+impl ItemSlot {
+    /// Our save item type for that slot
+    pub fn item_type(&self) -> ItemType {
+        match self {
+            ItemSlot::Head | ItemSlot::Torso | ItemSlot::Leg | ItemSlot::Arm => ItemType::Visual,
+            ItemSlot::Weapon => ItemType::Weapon,
+            ItemSlot::Skeleton => ItemType::Skeleton,
+        }
+    }
+}
+
+/// How rare an item is
+#[derive(Serialize, Deserialize, Reflect, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Rarity {
+    Common,
+    Uncommon,
+    Rare,
+    Epic,
+    Legendary,
+}
+
+/// The catalog file itself, either ron or json
+#[derive(Asset, TypePath, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ItemCatalog {
+    pub items: Vec<ItemDefinition>,
+}
+
+impl ItemCatalog {
+    /// Parses a catalog file, json if path ends in json otherwise ron. Also refuses duplicated ids, empty stacks,
+    /// missing starter items and weapons without a melee profile. Swings still need baking afterwards
+    pub fn parse(bytes: &[u8], path: &Path) -> Result<Self, String> {
+        let is_json = path
+            .extension()
+            .is_some_and(|extension| extension == "json");
+        let catalog: ItemCatalog = if is_json {
+            serde_json::from_slice(bytes).map_err(|err| err.to_string())?
+        } else {
+            ron::de::from_bytes(bytes).map_err(|err| err.to_string())?
+        };
+
+        let mut seen = Vec::new();
+        for item in catalog.items.iter() {
+            if seen.contains(&&item.id) {
+                return Err(format!("Item id {} is duplicated in catalog", item.id));
+            }
+            if item.max_stack == 0 {
+                return Err(format!("Item {} has a max stack of zero", item.id));
+            }
+            if item.slot == ItemSlot::Weapon && item.melee.is_none() {
+                return Err(format!("Weapon {} has no melee profile", item.id));
+            }
+            seen.push(&item.id);
+        }
+        for starter in [
+            STARTER_SKELETON,
+            STARTER_HEAD,
+            STARTER_TORSO,
+            STARTER_LEG,
+            STARTER_ARM,
+            STARTER_WEAPON,
+        ] {
+            if !seen.iter().any(|id| id.as_str() == starter) {
+                return Err(format!("Starter item {} is missing from catalog", starter));
+            }
+        }
+        Ok(catalog)
+    }
+
+    /// Reads a catalog straight from disk, for when we have no bevy app. Example: save cli.
+    /// Skeleton gltf is looked for next to the catalog, same as the asset server would
+    pub fn read(path: &Path) -> Result<Self, String> {
+        let bytes = std::fs::read(path).map_err(|err| err.to_string())?;
+        let mut catalog = Self::parse(&bytes, path)?;
+        let skeleton_path = path
+            .parent()
+            .unwrap_or(Path::new(""))
+            .join(catalog.skeleton_path());
+        let skeleton = std::fs::read(&skeleton_path)
+            .map_err(|err| format!("{}: {}", skeleton_path.display(), err))?;
+        catalog.bake_swings(&skeleton)?;
+        Ok(catalog)
+    }
+
+    /// Gltf path of our starter skeleton, every swing clip lives in him. Parse already made sure he exists
+    pub fn skeleton_path(&self) -> &str {
+        self.items
+            .iter()
+            .find(|item| item.id == STARTER_SKELETON)
+            .map(|item| item.gltf_path.as_str())
+            .unwrap_or_default()
+    }
+
+    /// Samples the hand bone of every weapon swing clip on each active tick. Refuses clips or bones missing from our skeleton
+    /// and clips that end before the blade stops hitting
+    pub fn bake_swings(&mut self, skeleton: &[u8]) -> Result<(), String> {
+        let clips = SkeletonClips::parse(skeleton)?;
+        for item in self.items.iter_mut() {
+            let Some(melee) = item.melee.as_mut() else {
+                continue;
+            };
+            for off_hand in [false, true] {
+                let (bone, grip) = if off_hand {
+                    (OFF_HAND_BONE, OFF_HAND_GRIP)
+                } else {
+                    (MAIN_HAND_BONE, Quat::IDENTITY)
+                };
+                let mut frames = SwingFrames::default();
+                for attack in [AttackKind::Light, AttackKind::Heavy] {
+                    let clip = melee.clip(attack, off_hand);
+                    let last_tick = attack.windup_ticks() + attack.active_ticks();
+                    let tick_time = 1.0 / FIXED_TIMESTEP_HZ as f32;
+                    if clips.duration(&clip)? < last_tick as f32 * tick_time {
+                        return Err(format!(
+                            "Clip {} of weapon {} ends before his blade stops hitting",
+                            clip, item.id
+                        ));
+                    }
+                    let blades = (attack.windup_ticks()..=last_tick)
+                        .map(|tick| {
+                            let hand = clips.bone_at(&clip, bone, tick as f32 * tick_time)?;
+                            Ok(BladeFrame {
+                                hilt: hand.transform_point(grip * melee.grip.hilt),
+                                tip: hand.transform_point(grip * melee.grip.tip),
+                            })
+                        })
+                        .collect::<Result<Vec<BladeFrame>, String>>()?;
+                    match attack {
+                        AttackKind::Light => frames.light = blades,
+                        AttackKind::Heavy => frames.heavy = blades,
+                    }
+                }
+                if off_hand {
+                    melee.off_hand = frames;
+                } else {
+                    melee.main_hand = frames;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Loader that turns our catalog file into an asset
+#[derive(Default)]
+pub struct ItemCatalogLoader;
+
+impl AssetLoader for ItemCatalogLoader {
+    type Asset = ItemCatalog;
+    type Settings = ();
+    type Error = String;
+
+    async fn load(
+        &self,
+        reader: &mut dyn Reader,
+        _settings: &(),
+        load_context: &mut LoadContext<'_>,
+    ) -> Result<Self::Asset, Self::Error> {
+        let mut bytes = Vec::new();
+        reader
+            .read_to_end(&mut bytes)
+            .await
+            .map_err(|err| err.to_string())?;
+        let mut catalog = ItemCatalog::parse(&bytes, load_context.path())?;
+        // Also makes our catalog reload whenever the skeleton clips change
+        let skeleton = load_context
+            .read_asset_bytes(catalog.skeleton_path().to_string())
+            .await
+            .map_err(|err| err.to_string())?;
+        catalog.bake_swings(&skeleton)?;
+        Ok(catalog)
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["catalog.ron", "catalog.json"]
+    }
+}
+
+/// Easy access to our loaded catalog, keyed by item id. Systems should read from here instead of the asset.
+/// Worth noting he is empty until asset finishes loading
+#[derive(Resource, Reflect, Default)]
+#[reflect(Resource)]
+pub struct CoreItemCatalog {
+    /// Keeps our asset alive
+    pub handle: Handle<ItemCatalog>,
+    /// Item id to definition
+    pub items: HashMap<String, ItemDefinition>,
+}
+
+impl CoreItemCatalog {
+    /// Empty until our asset loads, nothing can be priced, owned or worn before that
+    pub fn is_loaded(&self) -> bool {
+        !self.items.is_empty()
+    }
+
+    /// Grab a definition by his id
+    pub fn get(&self, item_id: &str) -> Option<&ItemDefinition> {
+        self.items.get(item_id)
+    }
+
+    /// Every item our store sells, sorted so eguis dont jump around
+    pub fn purchasable(&self) -> Vec<&ItemDefinition> {
+        let mut items: Vec<&ItemDefinition> = self
+            .items
+            .values()
+            .filter(|item| item.purchasable)
+            .collect();
+        items.sort_by(|a, b| a.id.cmp(&b.id));
+        items
+    }
+
+    /// Every item of that slot, sorted so eguis dont jump around
+    pub fn of_slot(&self, slot: ItemSlot) -> Vec<&ItemDefinition> {
+        let mut items: Vec<&ItemDefinition> = self
+            .items
+            .values()
+            .filter(|item| item.slot == slot)
+            .collect();
+        items.sort_by(|a, b| a.id.cmp(&b.id));
+        items
+    }
+}
+
+/// Asks asset server for our catalog
+fn load_catalog(asset_server: Res<AssetServer>, mut core_catalog: ResMut<CoreItemCatalog>) {
+    core_catalog.handle = asset_server.load(CATALOG_ASSET_PATH);
+}
+
+/// Whenever our catalog loads or gets modified we refill our core catalog
+fn fill_catalog(
+    mut asset_events: EventReader<AssetEvent<ItemCatalog>>,
+    catalogs: Res<Assets<ItemCatalog>>,
+    mut core_catalog: ResMut<CoreItemCatalog>,
+) {
+    for event in asset_events.read() {
+        let (AssetEvent::LoadedWithDependencies { id } | AssetEvent::Modified { id }) = event
+        else {
+            continue;
+        };
+        if *id != core_catalog.handle.id() {
+            continue;
+        }
+        if let Some(catalog) = catalogs.get(*id) {
+            core_catalog.items = catalog
+                .items
+                .iter()
+                .map(|item| (item.id.clone(), item.clone()))
+                .collect();
+            info!(
+                "Item catalog loaded with {} items",
+                core_catalog.items.len()
+            );
+        }
+    }
+}
+
+/// A catalog that never loaded is a startup error, nobody could be given starter items.
+/// Hot reloads that fail keep the catalog we already had, so those are only logged by the asset server
+fn refuse_broken_catalog(asset_server: Res<AssetServer>, core_catalog: Res<CoreItemCatalog>) {
+    if core_catalog.is_loaded() {
+        return;
+    }
+    if let Some(LoadState::Failed(err)) = asset_server.get_load_state(core_catalog.handle.id()) {
+        panic!(
+            "Failed to load item catalog {}, fix it before booting again. Error type {}",
+            CATALOG_ASSET_PATH, err
+        );
+    }
+}
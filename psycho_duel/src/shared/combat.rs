@@ -0,0 +1,523 @@
+use crate::shared::catalog::{CoreItemCatalog, MeleeProfile};
+use crate::shared::movement::look_yaw;
+use crate::shared::protocol::*;
+use bevy::ecs::query::QueryFilter;
+use bevy::prelude::*;
+use leafwing_input_manager::prelude::*;
+
+/// Stamina regained per second while neither swinging nor blocking
+pub const STAMINA_REGEN: f32 = 20.0;
+
+/// Stamina a blocked hit costs, per point of damage it would have done
+pub const BLOCK_STAMINA_PER_DAMAGE: f32 = 0.8;
+
+/// Player hurtbox is a vertical capsule, from slightly above his feet up to his head
+pub const BODY_RADIUS: f32 = 0.35;
+pub const BODY_BOTTOM: f32 = 0.2;
+pub const BODY_TOP: f32 = 1.7;
+
+/// How many in between blade positions each tick checks. A heavy swing covers a lot in one tick, without these it would skip a thin body
+pub const SWEEP_SAMPLES: u32 = 4;
+
+/// Anything below this is treated as a zero length segment
+const SEGMENT_EPSILON: f32 = 1e-6;
+
+impl AttackKind {
+    /// Health removed from whoever gets hit
+    pub fn damage(&self) -> f32 {
+        match self {
+            AttackKind::Light => 12.0,
+            AttackKind::Heavy => 30.0,
+        }
+    }
+
+    /// Stamina paid when the swing starts
+    pub fn stamina_cost(&self) -> f32 {
+        match self {
+            AttackKind::Light => 15.0,
+            AttackKind::Heavy => 35.0,
+        }
+    }
+
+    /// Ticks before the blade starts hitting
+    pub fn windup_ticks(&self) -> u16 {
+        match self {
+            AttackKind::Light => 6,
+            AttackKind::Heavy => 18,
+        }
+    }
+
+    /// Ticks the blade is able to hit
+    pub fn active_ticks(&self) -> u16 {
+        match self {
+            AttackKind::Light => 8,
+            AttackKind::Heavy => 10,
+        }
+    }
+
+    /// Ticks after the blade stops hitting until the player can act again
+    pub fn recovery_ticks(&self) -> u16 {
+        match self {
+            AttackKind::Light => 10,
+            AttackKind::Heavy => 20,
+        }
+    }
+
+    /// Whole swing length in ticks
+    pub fn total_ticks(&self) -> u16 {
+        self.windup_ticks() + self.active_ticks() + self.recovery_ticks()
+    }
+
+    /// Is the blade able to hit on that tick
+    pub fn is_active(&self, tick: u16) -> bool {
+        tick >= self.windup_ticks() && tick <= self.windup_ticks() + self.active_ticks()
+    }
+}
+
+/// The weapon we swing with, main hand first
+pub struct SwingWeapon<'a> {
+    /// His melee profile from our catalog
+    pub profile: &'a MeleeProfile,
+    /// Held in the off hand, he swings with the off hand clips
+    pub off_hand: bool,
+}
+
+/// Callable function - Grabs the weapon we swing with and his melee profile. None means no weapon, no attacking nor blocking
+pub fn swing_weapon<'a>(
+    visuals: &PlayerVisuals,
+    catalog: &'a CoreItemCatalog,
+) -> Option<SwingWeapon<'a>> {
+    let profile_of = |item: &Option<Item>| {
+        item.as_ref()
+            .and_then(|item| catalog.get(&item.definition_id))
+            .and_then(|definition| definition.melee.as_ref())
+    };
+    if let Some(profile) = profile_of(&visuals.main_hand) {
+        Some(SwingWeapon {
+            profile: profile,
+            off_hand: false,
+        })
+    } else {
+        profile_of(&visuals.off_hand).map(|profile| SwingWeapon {
+            profile: profile,
+            off_hand: true,
+        })
+    }
+}
+
+/// Callable function - Steps the weapon of a player one tick. Client prediction and server simulation must both go through here,
+/// same idea as movement so rollback replays swings and stamina exactly
+pub fn step_melee(
+    action_state: &ActionState<PlayerActions>,
+    has_weapon: bool,
+    melee: &mut MeleeState,
+    stamina: &mut Stamina,
+    timestep: f32,
+) {
+    // Mid swing we are committed, nothing else happens until it ends
+    if let Some(attack) = melee.attack {
+        melee.tick += 1;
+        if melee.tick >= attack.total_ticks() {
+            melee.attack = None;
+            melee.tick = 0;
+        }
+        return;
+    }
+
+    let wanted = if action_state.just_pressed(&PlayerActions::HeavyAttack) {
+        Some(AttackKind::Heavy)
+    } else if action_state.just_pressed(&PlayerActions::LightAttack) {
+        Some(AttackKind::Light)
+    } else {
+        None
+    };
+    if let Some(attack) = wanted {
+        if has_weapon && stamina.current >= attack.stamina_cost() {
+            stamina.current -= attack.stamina_cost();
+            melee.attack = Some(attack);
+            melee.tick = 0;
+            melee.blocking = false;
+            return;
+        }
+    }
+
+    melee.blocking =
+        has_weapon && action_state.pressed(&PlayerActions::Block) && stamina.current > 0.0;
+    if !melee.blocking {
+        stamina.current = (stamina.current + STAMINA_REGEN * timestep).min(stamina.max);
+    }
+}
+
+/// Shared system - Steps the weapon of every player that matches the filter, client registers it for his predicted players and server for all of them.
+/// IMPORTANT - Only register it in FixedUpdate, swings are counted in ticks
+pub fn update_melee<F: QueryFilter + 'static>(
+    time: Res<Time<Fixed>>,
+    catalog: Res<CoreItemCatalog>,
+    mut players: Query<
+        (
+            &ActionState<PlayerActions>,
+            &PlayerVisuals,
+            &mut MeleeState,
+            &mut Stamina,
+        ),
+        F,
+    >,
+) {
+    let timestep = time.timestep().as_secs_f32();
+    for (action_state, visuals, mut melee, mut stamina) in players.iter_mut() {
+        let has_weapon = swing_weapon(visuals, &catalog).is_some();
+        step_melee(action_state, has_weapon, &mut melee, &mut stamina, timestep);
+    }
+}
+
+/// Callable function - Hilt and tip of the blade in world space at that point of the swing. Tick may be fractional for sweep samples,
+/// in between ticks we blend the two closest frames sampled from his clip
+pub fn blade_at(
+    transform: &Transform,
+    weapon: &SwingWeapon,
+    attack: AttackKind,
+    tick: f32,
+) -> (Vec3, Vec3) {
+    let frames = weapon.profile.frames(attack, weapon.off_hand);
+    let last = frames.len().saturating_sub(1);
+    let progress = (tick - attack.windup_ticks() as f32).clamp(0.0, last as f32);
+    let index = (progress.floor() as usize).min(last);
+    let next = (index + 1).min(last);
+    let blend = progress - index as f32;
+    let hilt = frames[index].hilt.lerp(frames[next].hilt, blend);
+    let tip = frames[index].tip.lerp(frames[next].tip, blend);
+    (
+        transform.transform_point(hilt),
+        transform.transform_point(tip),
+    )
+}
+
+/// Callable function - Every blade position the swing went through during this tick, from the previous tick up to this one.
+/// Empty if the blade isnt active
+pub fn swept_blade(
+    transform: &Transform,
+    weapon: &SwingWeapon,
+    attack: AttackKind,
+    tick: u16,
+) -> Vec<(Vec3, Vec3)> {
+    if !attack.is_active(tick) {
+        return Vec::new();
+    }
+    let current = tick as f32;
+    let previous = tick.saturating_sub(1).max(attack.windup_ticks()) as f32;
+    (0..=SWEEP_SAMPLES)
+        .map(|sample| {
+            let tick_at = previous + (current - previous) * sample as f32 / SWEEP_SAMPLES as f32;
+            blade_at(transform, weapon, attack, tick_at)
+        })
+        .collect()
+}
+
+/// Callable function - Does that blade position touch the body of a player standing at that translation
+pub fn blade_hits_body(blade: (Vec3, Vec3), blade_radius: f32, body_translation: Vec3) -> bool {
+    let body_bottom = body_translation + Vec3::Y * BODY_BOTTOM;
+    let body_top = body_translation + Vec3::Y * BODY_TOP;
+    segment_distance(blade.0, blade.1, body_bottom, body_top) <= BODY_RADIUS + blade_radius
+}
+
+/// Callable function - Blocks only work for a blocking player looking at whoever swung at him.
+/// Facing is the camera yaw of his input, not his rotation, as standing still he keeps his rotation while turning the camera
+pub fn blocks_from(
+    defender_translation: Vec3,
+    action_state: &ActionState<PlayerActions>,
+    melee: &MeleeState,
+    attacker_translation: Vec3,
+) -> bool {
+    if !melee.blocking {
+        return false;
+    }
+    let facing = Quat::from_rotation_y(look_yaw(action_state)) * Vec3::Z;
+    let to_attacker = (attacker_translation - defender_translation)
+        .with_y(0.0)
+        .normalize_or_zero();
+    facing.dot(to_attacker) > 0.0
+}
+
+/// Callable function - Shortest distance between two segments, closest points are clamped to both of them
+pub fn segment_distance(a_start: Vec3, a_end: Vec3, b_start: Vec3, b_end: Vec3) -> f32 {
+    let a_dir = a_end - a_start;
+    let b_dir = b_end - b_start;
+    let between = a_start - b_start;
+    let a_len_sq = a_dir.length_squared();
+    let b_len_sq = b_dir.length_squared();
+    let b_dot_between = b_dir.dot(between);
+
+    let (a_t, b_t) = if a_len_sq <= SEGMENT_EPSILON && b_len_sq <= SEGMENT_EPSILON {
+        (0.0, 0.0)
+    } else if a_len_sq <= SEGMENT_EPSILON {
+        (0.0, (b_dot_between / b_len_sq).clamp(0.0, 1.0))
+    } else {
+        let a_dot_between = a_dir.dot(between);
+        if b_len_sq <= SEGMENT_EPSILON {
+            ((-a_dot_between / a_len_sq).clamp(0.0, 1.0), 0.0)
+        } else {
+            let a_dot_b = a_dir.dot(b_dir);
+            let denominator = a_len_sq * b_len_sq - a_dot_b * a_dot_b;
+            // Parallel segments have no single closest point, any start works
+            let a_t = if denominator > SEGMENT_EPSILON {
+                ((a_dot_b * b_dot_between - a_dot_between * b_len_sq) / denominator).clamp(0.0, 1.0)
+            } else {
+                0.0
+            };
+            let b_t = (a_dot_b * a_t + b_dot_between) / b_len_sq;
+            if b_t < 0.0 {
+                ((-a_dot_between / a_len_sq).clamp(0.0, 1.0), 0.0)
+            } else if b_t > 1.0 {
+                (((a_dot_b - a_dot_between) / a_len_sq).clamp(0.0, 1.0), 1.0)
+            } else {
+                (a_t, b_t)
+            }
+        }
+    };
+
+    ((a_start + a_dir * a_t) - (b_start + b_dir * b_t)).length()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::catalog::{ItemCatalog, STARTER_SKELETON, STARTER_WEAPON};
+    use crate::shared::movement::step_movement;
+    use crate::shared::skeleton::{SkeletonClips, MAIN_HAND_BONE, OFF_HAND_BONE, OFF_HAND_GRIP};
+    use crate::shared::FIXED_TIMESTEP_HZ;
+    use std::f32::consts::PI;
+    use std::path::Path;
+
+    #[test]
+    fn segment_distance_of_crossing_and_apart_segments() {
+        let crossing = segment_distance(
+            Vec3::new(-1.0, 0.0, 0.0),
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(0.0, -1.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+        );
+        assert!(crossing.abs() < 1e-6);
+
+        let apart = segment_distance(
+            Vec3::new(-1.0, 0.0, 0.0),
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(3.0, -1.0, 2.0),
+            Vec3::new(3.0, 1.0, 2.0),
+        );
+        assert!((apart - Vec3::new(2.0, 0.0, 2.0).length()).abs() < 1e-5);
+    }
+
+    /// Our shipped catalog, so tests swing the real katana clips
+    fn catalog() -> CoreItemCatalog {
+        let catalog = ItemCatalog::read(Path::new("assets/items.catalog.ron"))
+            .expect("Shipped catalog to be valid");
+        CoreItemCatalog {
+            handle: Handle::default(),
+            items: catalog
+                .items
+                .into_iter()
+                .map(|item| (item.id.clone(), item))
+                .collect(),
+        }
+    }
+
+    /// Player wearing a katana in one of his hands
+    fn wielding(catalog: &CoreItemCatalog, off_hand: bool) -> PlayerVisuals {
+        let mut visuals = PlayerVisuals::starter(catalog);
+        if off_hand {
+            visuals.off_hand = visuals.main_hand.take();
+        }
+        visuals
+    }
+
+    /// Does that swing hit a body standing there on any tick
+    fn swing_hits(weapon: &SwingWeapon, attack: AttackKind, body: Vec3) -> bool {
+        (0..attack.total_ticks()).any(|tick| {
+            swept_blade(&Transform::default(), weapon, attack, tick)
+                .into_iter()
+                .any(|blade| blade_hits_body(blade, weapon.profile.blade_radius, body))
+        })
+    }
+
+    #[test]
+    fn swing_hits_in_front_never_behind() {
+        let catalog = catalog();
+        let visuals = wielding(&catalog, false);
+        let weapon = swing_weapon(&visuals, &catalog).expect("Katana to have a melee profile");
+        for attack in [AttackKind::Light, AttackKind::Heavy] {
+            // Straight in front of the attacker, the middle of the arc
+            assert!(swing_hits(&weapon, attack, Vec3::new(0.0, 0.0, 1.3)));
+            // Behind the attacker the blade never goes
+            assert!(!swing_hits(&weapon, attack, Vec3::new(0.0, 0.0, -1.3)));
+        }
+    }
+
+    #[test]
+    fn blade_follows_the_sampled_frames() {
+        let catalog = catalog();
+        let visuals = wielding(&catalog, false);
+        let weapon = swing_weapon(&visuals, &catalog).unwrap();
+        let attack = AttackKind::Light;
+        let frames = weapon.profile.frames(attack, false);
+        let attacker = Transform::from_xyz(2.0, 0.0, -1.0);
+
+        // On a tick we are exactly on his frame, moved along with the player
+        let (hilt, tip) = blade_at(&attacker, &weapon, attack, attack.windup_ticks() as f32);
+        assert!(hilt.abs_diff_eq(frames[0].hilt + attacker.translation, 1e-5));
+        assert!(tip.abs_diff_eq(frames[0].tip + attacker.translation, 1e-5));
+
+        // Half a tick later we are half way to the next frame
+        let (_, tip) = blade_at(
+            &attacker,
+            &weapon,
+            attack,
+            attack.windup_ticks() as f32 + 0.5,
+        );
+        let halfway = frames[0].tip.lerp(frames[1].tip, 0.5) + attacker.translation;
+        assert!(tip.abs_diff_eq(halfway, 1e-5));
+    }
+
+    #[test]
+    fn blade_rides_the_hand_bone_of_his_clip() {
+        let catalog = catalog();
+        let weapon = catalog.get(STARTER_WEAPON).unwrap();
+        let melee = weapon.melee.as_ref().unwrap();
+        let skeleton_path =
+            Path::new("assets").join(&catalog.get(STARTER_SKELETON).unwrap().gltf_path);
+        let skeleton = std::fs::read(skeleton_path).expect("Shipped skeleton to exist");
+        let clips = SkeletonClips::parse(&skeleton).unwrap();
+        for attack in [AttackKind::Light, AttackKind::Heavy] {
+            for (off_hand, bone, grip) in [
+                (false, MAIN_HAND_BONE, Quat::IDENTITY),
+                (true, OFF_HAND_BONE, OFF_HAND_GRIP),
+            ] {
+                let frames = melee.frames(attack, off_hand);
+                assert_eq!(frames.len(), attack.active_ticks() as usize + 1);
+                for (index, frame) in frames.iter().enumerate() {
+                    let tick = attack.windup_ticks() as usize + index;
+                    let hand = clips
+                        .bone_at(
+                            &melee.clip(attack, off_hand),
+                            bone,
+                            tick as f32 / FIXED_TIMESTEP_HZ as f32,
+                        )
+                        .unwrap();
+                    assert!(frame
+                        .hilt
+                        .abs_diff_eq(hand.transform_point(grip * melee.grip.hilt), 1e-5));
+                    assert!(frame
+                        .tip
+                        .abs_diff_eq(hand.transform_point(grip * melee.grip.tip), 1e-5));
+                    // Hilt sits in his fist and the blade keeps his length all swing long
+                    assert!(frame.hilt.distance(hand.translation) < 0.15);
+                    let blade_length = melee.grip.hilt.distance(melee.grip.tip);
+                    assert!((frame.hilt.distance(frame.tip) - blade_length).abs() < 1e-4);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn off_hand_swings_mirror_the_main_hand() {
+        let catalog = catalog();
+        let main_visuals = wielding(&catalog, false);
+        let off_visuals = wielding(&catalog, true);
+        let main = swing_weapon(&main_visuals, &catalog).unwrap();
+        let off = swing_weapon(&off_visuals, &catalog).unwrap();
+        assert!(off.off_hand);
+        let tick = AttackKind::Heavy.windup_ticks() as f32 + 3.0;
+        let (main_hilt, main_tip) = blade_at(&Transform::default(), &main, AttackKind::Heavy, tick);
+        let (off_hilt, off_tip) = blade_at(&Transform::default(), &off, AttackKind::Heavy, tick);
+        assert!(off_hilt.abs_diff_eq(main_hilt * Vec3::new(-1.0, 1.0, 1.0), 1e-3));
+        assert!(off_tip.abs_diff_eq(main_tip * Vec3::new(-1.0, 1.0, 1.0), 1e-3));
+    }
+
+    #[test]
+    fn swing_costs_stamina_and_ends() {
+        let mut action_state = ActionState::<PlayerActions>::default();
+        let mut melee = MeleeState::default();
+        let mut stamina = Stamina::default();
+        action_state.press(&PlayerActions::LightAttack);
+        step_melee(&action_state, true, &mut melee, &mut stamina, 0.0);
+        assert_eq!(melee.attack, Some(AttackKind::Light));
+        assert_eq!(
+            stamina.current,
+            MAX_STAMINA - AttackKind::Light.stamina_cost()
+        );
+
+        for _ in 0..AttackKind::Light.total_ticks() {
+            step_melee(&action_state, true, &mut melee, &mut stamina, 0.0);
+        }
+        assert_eq!(melee.attack, None);
+    }
+
+    #[test]
+    fn no_weapon_no_swing() {
+        let mut action_state = ActionState::<PlayerActions>::default();
+        let mut melee = MeleeState::default();
+        let mut stamina = Stamina::default();
+        action_state.press(&PlayerActions::HeavyAttack);
+        action_state.press(&PlayerActions::Block);
+        step_melee(&action_state, false, &mut melee, &mut stamina, 0.0);
+        assert_eq!(melee, MeleeState::default());
+        assert_eq!(stamina.current, MAX_STAMINA);
+    }
+
+    #[test]
+    fn blocks_only_from_the_front() {
+        let action_state = ActionState::<PlayerActions>::default();
+        let melee = MeleeState {
+            attack: None,
+            tick: 0,
+            blocking: true,
+        };
+        assert!(blocks_from(
+            Vec3::ZERO,
+            &action_state,
+            &melee,
+            Vec3::new(0.0, 0.0, 2.0)
+        ));
+        assert!(!blocks_from(
+            Vec3::ZERO,
+            &action_state,
+            &melee,
+            Vec3::new(0.0, 0.0, -2.0)
+        ));
+    }
+
+    #[test]
+    fn blocks_where_he_looks_after_turning_in_place() {
+        let mut action_state = ActionState::<PlayerActions>::default();
+        let mut transform = Transform::default();
+        let mut melee = MeleeState::default();
+        let mut stamina = Stamina::default();
+
+        // Turns around without moving, his rotation stays facing +z
+        action_state.set_axis_pair(&PlayerActions::CameraLook, Vec2::new(PI, 0.0));
+        step_movement(
+            &action_state,
+            &mut transform,
+            1.0 / FIXED_TIMESTEP_HZ as f32,
+        );
+        assert_eq!(transform, Transform::default());
+
+        action_state.press(&PlayerActions::Block);
+        step_melee(&action_state, true, &mut melee, &mut stamina, 0.0);
+        assert!(melee.blocking);
+
+        let behind_his_rotation = Vec3::new(0.0, 0.0, -2.0);
+        let in_front_of_his_rotation = Vec3::new(0.0, 0.0, 2.0);
+        assert!(blocks_from(
+            transform.translation,
+            &action_state,
+            &melee,
+            behind_his_rotation
+        ));
+        assert!(!blocks_from(
+            transform.translation,
+            &action_state,
+            &melee,
+            in_front_of_his_rotation
+        ));
+    }
+}
@@ -0,0 +1,222 @@
+use bevy::prelude::*;
+use clap::Args;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+
+/// Default path of our config file, if it doesnt exist we simply run with default values
+const CONFIG_FILE_PATH: &str = "./psycho_duel/config.toml";
+
+/// Centralization struct - Every configurable aspect of our game that can be set via toml file shall be stored here.
+/// Order of importance is: cli flags > config file > defaults
+#[derive(Resource, Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct CoreConfig {
+    /// Where server binds and where client connects to, also our protocol id
+    pub network: NetworkConfig,
+    /// Where our token service lives and where the server private key is
+    pub auth: AuthConfig,
+    /// How and where the server persists our core information
+    pub save: SaveConfig,
+    /// Where the client keeps the settings of each user, like keybinds
+    pub settings: SettingsConfig,
+}
+
+/// Network related configuration, both server and client read from here
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct NetworkConfig {
+    /// In server this is the ip we bind to, in client the ip we connect to
+    pub server_ip: IpAddr,
+    /// In server this is the port we bind to, in client the port we connect to
+    pub server_port: u16,
+    /// Ip the client opens his udp socket on
+    pub client_ip: IpAddr,
+    /// Port the client opens his udp socket on, 0 means the OS picks a free one for us
+    pub client_port: u16,
+    /// Netcode protocol id, server and client must have the same one or the connection is refused
+    pub protocol_id: u64,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            server_ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            server_port: 5000,
+            client_ip: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            client_port: 0,
+            protocol_id: 0,
+        }
+    }
+}
+
+impl NetworkConfig {
+    /// Full socket address of our server
+    pub fn server_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.server_ip, self.server_port)
+    }
+    /// Full socket address of our client
+    pub fn client_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.client_ip, self.client_port)
+    }
+}
+
+/// Token service related configuration
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct AuthConfig {
+    /// In server this is the ip our token service binds to, in client the ip we ask tokens from
+    pub ip: IpAddr,
+    /// In server this is the port our token service binds to, in client the port we ask tokens from
+    pub port: u16,
+    /// If true our game server also runs the token service, if false run the auth-server subcommand separately
+    pub in_process: bool,
+    /// Path to the key that signs our connect tokens. Only server side reads it
+    pub private_key_path: String,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: 5001,
+            in_process: true,
+            private_key_path: "./psycho_duel/src/server/save_files/private.key".to_string(),
+        }
+    }
+}
+
+impl AuthConfig {
+    /// Full socket address of our token service
+    pub fn auth_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.ip, self.port)
+    }
+}
+
+/// Save related configuration, only server reads from here
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct SaveConfig {
+    /// Which storage our core save info map uses
+    pub backend: SaveBackendKind,
+    /// File path for bincode file and sqlite, folder path for directory
+    pub path: String,
+    /// How many previous generations of each save file we keep around for recovery, sqlite ignores it as it has his own journal
+    pub backups: usize,
+    /// Seconds between the first unsaved change and the write of the whole batch, the max time a change lives only in memory
+    pub autosave_interval: f32,
+}
+
+impl Default for SaveConfig {
+    fn default() -> Self {
+        Self {
+            backend: SaveBackendKind::BincodeFile,
+            path: "./psycho_duel/src/server/save_files/player_info.bar".to_string(),
+            backups: 3,
+            autosave_interval: 5.0,
+        }
+    }
+}
+
+/// Client settings related configuration, only client reads from here
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct SettingsConfig {
+    /// Folder with one settings file per user that logged in this machine
+    pub dir: String,
+}
+
+impl Default for SettingsConfig {
+    fn default() -> Self {
+        Self {
+            dir: "./psycho_duel/src/client/settings_files".to_string(),
+        }
+    }
+}
+
+/// Every available save backend
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SaveBackendKind {
+    /// Whole save map in one sole bincode file
+    BincodeFile,
+    /// One bincode file per player inside a folder
+    Directory,
+    /// Embedded sqlite database
+    Sqlite,
+}
+
+/// Cli flags shared by both server and client, all of them are optional if passed they override the config file
+#[derive(Args, PartialEq, Debug, Clone, Default)]
+pub struct ConfigArgs {
+    /// Path to a toml config file
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+    /// Server ip, bind address in server connect address in client
+    #[arg(long)]
+    pub server_ip: Option<IpAddr>,
+    /// Server port, bind port in server connect port in client
+    #[arg(long)]
+    pub server_port: Option<u16>,
+    /// Ip that client binds his socket to
+    #[arg(long)]
+    pub client_ip: Option<IpAddr>,
+    /// Port that client binds his socket to
+    #[arg(long)]
+    pub client_port: Option<u16>,
+    /// Netcode protocol id
+    #[arg(long)]
+    pub protocol_id: Option<u64>,
+    /// Token service port, bind port in server connect port in client
+    #[arg(long)]
+    pub auth_port: Option<u16>,
+}
+
+impl CoreConfig {
+    /// Reads the config file pointed by args (or the default one) and then applies the cli flags on top of it
+    pub fn load(args: &ConfigArgs) -> Self {
+        let path = args
+            .config
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(CONFIG_FILE_PATH));
+
+        let mut config = match fs::read_to_string(&path) {
+            Ok(text) => toml::from_str(&text).unwrap_or_else(|err| {
+                panic!("Config file {} is malformed: {}", path.display(), err)
+            }),
+            // Only complain if user explicitly asked for a file
+            Err(err) if err.kind() == ErrorKind::NotFound && args.config.is_none() => {
+                CoreConfig::default()
+            }
+            Err(err) => panic!("Failed to read config file {}: {}", path.display(), err),
+        };
+
+        config.apply_args(args);
+        config
+    }
+
+    /// Overrides config values with the ones passed via cli
+    fn apply_args(&mut self, args: &ConfigArgs) {
+        let network = &mut self.network;
+        if let Some(server_ip) = args.server_ip {
+            network.server_ip = server_ip;
+        }
+        if let Some(server_port) = args.server_port {
+            network.server_port = server_port;
+        }
+        if let Some(client_ip) = args.client_ip {
+            network.client_ip = client_ip;
+        }
+        if let Some(client_port) = args.client_port {
+            network.client_port = client_port;
+        }
+        if let Some(protocol_id) = args.protocol_id {
+            network.protocol_id = protocol_id;
+        }
+        if let Some(auth_port) = args.auth_port {
+            self.auth.port = auth_port;
+        }
+    }
+}
@@ -19,8 +19,8 @@ impl Plugin for SharedEgui {
         app.add_systems(Update, (inspector_ui, shared_diagnostics_ui));
 
         // Bevy native diagnostics plugin - Those carry precious information
-        app.add_plugins(FrameTimeDiagnosticsPlugin::default());
-        app.add_plugins(EntityCountDiagnosticsPlugin::default());
+        app.add_plugins(FrameTimeDiagnosticsPlugin);
+        app.add_plugins(EntityCountDiagnosticsPlugin);
     }
 }
 
@@ -60,9 +60,7 @@ fn inspector_ui(world: &mut World) {
                     ui.allocate_space(ui.available_size());
                 })
             });
-    } else {
-        return;
-    };
+    }
 }
 
 /// A shared diagnostics ui that diplays key perfomance informations, this works via acessing diagnostic store,
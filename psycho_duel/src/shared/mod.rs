@@ -1,11 +1,10 @@
 use bevy::prelude::*;
 use bevy::utils::Duration;
-use egui::SharedEgui;
 use lightyear::prelude::*;
 use lightyear::shared::config::Mode;
 // use player::SharedPlayerPlugin;
+use catalog::CatalogPlugin;
 use protocol::ProtocolPlugin;
-use renderer::SharedRendererPlugin;
 
 pub const FIXED_TIMESTEP_HZ: f64 = 64.0;
 
@@ -13,25 +12,30 @@ pub const SERVER_REPLICATION_INTERVAL: Duration = Duration::from_millis(100);
 
 /// Systems and protocols, that need to be shared between server and client will be stationed here.
 /// Warning - Whenever adjusting shared plugins, also reset server. I tend to make that mistake
+/// IMPORTANT - Nothing here should require a window or a renderer, as the server may run headless. Egui and renderer plugins are added by whoever needs them
 pub struct CoreSharedPlugin;
 
 /// Reliable ordered channel utilized for our own self made messages
 #[derive(Channel)]
 pub struct CommonChannel;
 
+pub mod catalog;
+pub mod combat;
+pub mod config;
 pub mod egui;
+pub mod movement;
 pub mod protocol;
 pub mod renderer;
+pub mod skeleton;
 
 impl Plugin for CoreSharedPlugin {
     fn build(&self, app: &mut App) {
-        // Self made plugins
-        app.add_plugins(SharedEgui);
-        app.add_plugins(SharedRendererPlugin);
-
         // Protocol plugin- SUPER DUPER IMPORTANT
         app.add_plugins(ProtocolPlugin);
 
+        // Item catalog - Both sides must agree on what items exist and what they cost
+        app.add_plugins(CatalogPlugin);
+
         //Self made channels
         app.add_channel::<CommonChannel>(ChannelSettings {
             mode: ChannelMode::OrderedReliable(ReliableSettings::default()),
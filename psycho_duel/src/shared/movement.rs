@@ -0,0 +1,197 @@
+use crate::shared::protocol::PlayerActions;
+use bevy::ecs::query::QueryFilter;
+use bevy::prelude::*;
+use leafwing_input_manager::prelude::*;
+
+/// How fast our player walks, in units per second
+pub const PLAYER_SPEED: f32 = 6.4;
+
+/// Callable function - Camera yaw the client sent along with this tick input, in radians. Zero means looking down +z
+pub fn look_yaw(action_state: &ActionState<PlayerActions>) -> f32 {
+    action_state.axis_pair(&PlayerActions::CameraLook).x
+}
+
+/// Callable function - Tells me where the player wants to go on world axes, relative to where his camera was looking.
+/// Length is how far the stick is pushed, clamped to one so keyboard diagonals arent faster than going straight
+pub fn movement_direction(action_state: &ActionState<PlayerActions>) -> Vec3 {
+    let movement = action_state
+        .axis_pair(&PlayerActions::Move)
+        .clamp_length_max(1.0);
+    // Stick up is forward (+z), stick right is cam.right which is -x when looking down +z
+    let direction = Vec3::new(-movement.x, 0.0, movement.y);
+    Quat::from_rotation_y(look_yaw(action_state)) * direction
+}
+
+/// Callable function - Moves that transform for one tick. Client prediction and server simulation must both go through here,
+/// otherwise we would be rolling back every single tick. While moving the player turns to face the camera yaw, so strafe animations still make sense,
+/// when standing still he keeps his rotation so you can orbit around him. Everything comes from the action state of that tick so rollback replays it exactly
+pub fn step_movement(
+    action_state: &ActionState<PlayerActions>,
+    transform: &mut Transform,
+    timestep: f32,
+) {
+    let direction = movement_direction(action_state);
+    if direction == Vec3::ZERO {
+        return;
+    }
+    transform.translation += direction * PLAYER_SPEED * timestep;
+    transform.rotation = Quat::from_rotation_y(look_yaw(action_state));
+}
+
+/// Shared system - Moves every player that matches the filter, client registers it for his predicted players and server for all of them.
+/// IMPORTANT - Only register it in FixedUpdate, we scale by the fixed timestep not by frame time
+pub fn move_players<F: QueryFilter + 'static>(
+    time: Res<Time<Fixed>>,
+    mut players: Query<(&ActionState<PlayerActions>, &mut Transform), F>,
+) {
+    let timestep = time.timestep().as_secs_f32();
+    for (action_state, mut transform) in players.iter_mut() {
+        step_movement(action_state, &mut transform, timestep);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::protocol::PlayerMarker;
+    use crate::shared::FIXED_TIMESTEP_HZ;
+    use lightyear::client::prediction::Predicted;
+    use std::f32::consts::FRAC_PI_2;
+
+    /// Each tick is the move axis held during it and the camera yaw
+    fn input_sequence() -> Vec<(Vec2, f32)> {
+        vec![
+            (Vec2::Y, 0.0),
+            (Vec2::new(-1.0, 1.0), 0.3),
+            (Vec2::ZERO, 1.2),
+            (Vec2::new(0.35, -0.6), -2.1),
+            (Vec2::new(0.05, 0.0), 0.7),
+            (Vec2::new(-0.8, 0.9), 3.0),
+            (Vec2::X, FRAC_PI_2),
+        ]
+    }
+
+    /// Minimal app with only our movement registered the way each side does it
+    fn app_with<F: QueryFilter + 'static>(marker: impl Bundle) -> (App, Entity) {
+        let mut app = App::new();
+        app.insert_resource(Time::<Fixed>::from_hz(FIXED_TIMESTEP_HZ));
+        app.add_systems(FixedUpdate, move_players::<F>);
+        let player = app
+            .world_mut()
+            .spawn((
+                marker,
+                Transform::default(),
+                ActionState::<PlayerActions>::default(),
+            ))
+            .id();
+        (app, player)
+    }
+
+    /// Holds the move axis, looks at that yaw and runs one fixed tick
+    fn tick(app: &mut App, player: Entity, movement: Vec2, yaw: f32) {
+        let mut action_state = app
+            .world_mut()
+            .get_mut::<ActionState<PlayerActions>>(player)
+            .unwrap();
+        action_state.set_axis_pair(&PlayerActions::Move, movement);
+        action_state.set_axis_pair(&PlayerActions::CameraLook, Vec2::new(yaw, 0.0));
+        app.world_mut().run_schedule(FixedUpdate);
+    }
+
+    #[test]
+    fn client_prediction_matches_server_simulation() {
+        let (mut client, client_player) = app_with::<With<Predicted>>(Predicted {
+            confirmed_entity: None,
+        });
+        let (mut server, server_player) = app_with::<With<PlayerMarker>>(PlayerMarker);
+
+        for (movement, yaw) in input_sequence() {
+            tick(&mut client, client_player, movement, yaw);
+            tick(&mut server, server_player, movement, yaw);
+            assert_eq!(
+                client.world().get::<Transform>(client_player),
+                server.world().get::<Transform>(server_player)
+            );
+        }
+        assert_ne!(
+            client.world().get::<Transform>(client_player),
+            Some(&Transform::default())
+        );
+    }
+
+    #[test]
+    fn moves_speed_units_per_second() {
+        let (mut app, player) = app_with::<With<PlayerMarker>>(PlayerMarker);
+        for _ in 0..FIXED_TIMESTEP_HZ as u32 {
+            tick(&mut app, player, Vec2::Y, 0.0);
+        }
+        let translation = app.world().get::<Transform>(player).unwrap().translation;
+        assert!((translation.z - PLAYER_SPEED).abs() < 1e-3);
+        assert_eq!(translation.x, 0.0);
+    }
+
+    #[test]
+    fn diagonals_are_not_faster() {
+        let mut action_state = ActionState::<PlayerActions>::default();
+        // Virtual dpad gives us both axes fully pushed for W + A
+        action_state.set_axis_pair(&PlayerActions::Move, Vec2::new(-1.0, 1.0));
+        let direction = movement_direction(&action_state);
+        assert!((direction.length() - 1.0).abs() < 1e-6);
+        assert!(direction.x > 0.0 && direction.z > 0.0);
+    }
+
+    #[test]
+    fn half_pushed_stick_walks_half_speed() {
+        let (mut app, player) = app_with::<With<PlayerMarker>>(PlayerMarker);
+        for _ in 0..FIXED_TIMESTEP_HZ as u32 {
+            tick(&mut app, player, Vec2::new(0.0, 0.5), 0.0);
+        }
+        let translation = app.world().get::<Transform>(player).unwrap().translation;
+        assert!((translation.z - PLAYER_SPEED * 0.5).abs() < 1e-3);
+    }
+
+    #[test]
+    fn forward_follows_camera_yaw() {
+        let (mut app, player) = app_with::<With<PlayerMarker>>(PlayerMarker);
+        tick(&mut app, player, Vec2::Y, FRAC_PI_2);
+        let transform = app.world().get::<Transform>(player).unwrap();
+        assert!(transform.translation.x > 0.0);
+        assert!(transform.translation.z.abs() < 1e-6);
+        assert!(transform
+            .rotation
+            .abs_diff_eq(Quat::from_rotation_y(FRAC_PI_2), 1e-6));
+    }
+
+    #[test]
+    fn standing_still_keeps_rotation() {
+        let (mut app, player) = app_with::<With<PlayerMarker>>(PlayerMarker);
+        tick(&mut app, player, Vec2::NEG_X, 0.5);
+        let facing = app.world().get::<Transform>(player).unwrap().rotation;
+        tick(&mut app, player, Vec2::ZERO, 2.0);
+        assert_eq!(
+            app.world().get::<Transform>(player).unwrap().rotation,
+            facing
+        );
+        assert!(facing.abs_diff_eq(Quat::from_rotation_y(0.5), 1e-6));
+    }
+
+    #[test]
+    fn rollback_replays_the_same_result() {
+        let (mut app, player) = app_with::<With<PlayerMarker>>(PlayerMarker);
+        let sequence = input_sequence();
+        let (first_movement, first_yaw) = sequence[0];
+        tick(&mut app, player, first_movement, first_yaw);
+        let checkpoint = *app.world().get::<Transform>(player).unwrap();
+        for (movement, yaw) in &sequence[1..] {
+            tick(&mut app, player, *movement, *yaw);
+        }
+        let predicted = *app.world().get::<Transform>(player).unwrap();
+
+        // Same as a rollback, restore the confirmed state and resimulate the buffered inputs
+        *app.world_mut().get_mut::<Transform>(player).unwrap() = checkpoint;
+        for (movement, yaw) in &sequence[1..] {
+            tick(&mut app, player, *movement, *yaw);
+        }
+        assert_eq!(*app.world().get::<Transform>(player).unwrap(), predicted);
+    }
+}
@@ -1,8 +1,10 @@
 use crate::client::egui::ChangeCharEvent;
 use crate::client::egui::Parts;
+use crate::shared::catalog::*;
 use crate::shared::ClientId;
 use bevy::prelude::*;
 use bevy::utils::HashMap;
+use bincode::Options;
 use leafwing_input_manager::prelude::*;
 use lightyear::client::components::LerpFn;
 use lightyear::prelude::client::ComponentSyncMode;
@@ -12,35 +14,206 @@ use std::fmt;
 use std::time::Duration;
 use uuid::Uuid;
 
-/// Component that tell me exactly what items that player has available to him we can easily query it via his UUid
-/// Is worth noting this guy is just a wrapped hashmap with renamed functions but who know perhaps later he will have more to it
-#[derive(Component, Reflect, Serialize, Deserialize, PartialEq, Debug, Clone, Default)]
+/// How many entries an inventory holds by default, each stack takes one entry no matter his count
+pub const DEFAULT_INVENTORY_CAPACITY: u32 = 30;
+
+/// Component that tell me exactly what items that player has available to him we can easily query it via the instance id of each entry
+/// Rules come from our catalog, stack limits, unique items and capacity. Every mutation goes through the functions below so they are never broken
+#[derive(Component, Reflect, Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct Inventory {
-    pub items: HashMap<Uuid, Item>,
+    /// Instance id of the stack to that stack
+    pub entries: HashMap<Uuid, InventoryEntry>,
+    /// Max amount of entries
+    pub capacity: u32,
 }
+
+/// One stack of our inventory, all items in it are the same catalog item
+#[derive(Reflect, Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct InventoryEntry {
+    /// Instance that represents the whole stack
+    pub item: Item,
+    /// How many of him we have, never zero
+    pub count: u32,
+}
+
+impl Default for Inventory {
+    fn default() -> Self {
+        Self::empty()
+    }
+}
+
 impl Inventory {
     /// Creates an empty inventory
     pub fn empty() -> Self {
         Self {
-            items: HashMap::new(), // Assuming `items` is a `HashMap`
+            entries: HashMap::new(),
+            capacity: DEFAULT_INVENTORY_CAPACITY,
         }
     }
-    /// Insert one sole item in our inventory
-    pub fn insert_item(&mut self, item: Item) -> &Self {
-        self.items.insert(item.id, item);
-        self
+    /// Adds count of that item, filling stacks we already have before opening new ones.
+    /// Nothing changes if it doesnt fit. Returns the instance id of the stack that received the item
+    pub fn add(
+        &mut self,
+        item: Item,
+        count: u32,
+        definition: &ItemDefinition,
+    ) -> Result<Uuid, InventoryError> {
+        let max_stack = definition.max_stack.max(1);
+        if definition.unique && (count > 1 || self.find_definition(&definition.id).is_some()) {
+            return Err(InventoryError::UniqueOwned(definition.id.clone()));
+        }
+
+        // Room left on stacks we already have
+        let stack_room: u32 = self
+            .entries
+            .values()
+            .filter(|entry| entry.item.same_definition(&item))
+            .map(|entry| max_stack.saturating_sub(entry.count))
+            .sum();
+        let leftover = count.saturating_sub(stack_room);
+        let new_stacks = leftover.div_ceil(max_stack);
+        if self.entries.len() as u32 + new_stacks > self.capacity {
+            return Err(InventoryError::Full {
+                capacity: self.capacity,
+            });
+        }
+
+        // Fits - First fill old stacks
+        let mut remaining = count;
+        let mut received = item.id;
+        for entry in self.entries.values_mut() {
+            if remaining == 0 {
+                break;
+            }
+            if entry.item.same_definition(&item) && entry.count < max_stack {
+                let added = remaining.min(max_stack - entry.count);
+                entry.count += added;
+                remaining -= added;
+                received = entry.item.id;
+            }
+        }
+        // Second open new ones, first one keeps the given instance
+        let mut instance = item;
+        while remaining > 0 {
+            let added = remaining.min(max_stack);
+            received = instance.id;
+            self.entries.insert(
+                instance.id,
+                InventoryEntry {
+                    item: instance.clone(),
+                    count: added,
+                },
+            );
+            remaining -= added;
+            instance.id = Uuid::new_v4();
+        }
+        Ok(received)
+    }
+    /// Removes count from that stack, dropping the entry once it reaches zero. Returns the item removed
+    pub fn remove(&mut self, item_id: &Uuid, count: u32) -> Result<Item, InventoryError> {
+        let Some(entry) = self.entries.get_mut(item_id) else {
+            return Err(InventoryError::NotOwned(*item_id));
+        };
+        if entry.count < count {
+            return Err(InventoryError::NotEnough {
+                item_id: *item_id,
+                owned: entry.count,
+            });
+        }
+        entry.count -= count;
+        let item = entry.item.clone();
+        if entry.count == 0 {
+            self.entries.remove(item_id);
+        }
+        Ok(item)
     }
-    /// Insert multiple items at once in our inventory and return self
-    pub fn insert_mult_items(&mut self, items: Vec<Item>) -> &mut Self {
-        for item in items {
-            self.items.insert(item.id, item);
+    /// Same as remove, but refuses if that would take away an item player is wearing
+    pub fn remove_unequipped(
+        &mut self,
+        item_id: &Uuid,
+        count: u32,
+        visuals: &PlayerVisuals,
+    ) -> Result<Item, InventoryError> {
+        if let Some(entry) = self.entries.get(item_id) {
+            // Whatever stays must still cover every slot wearing it
+            if entry.count.saturating_sub(count) < visuals.equipped_count(item_id) {
+                return Err(InventoryError::Equipped(*item_id));
+            }
         }
-        self
+        self.remove(item_id, count)
+    }
+    /// Grab an owned instance of that catalog item that isnt fully worn, ignoring what is in the part we are about to replace.
+    /// Lets both hands hold the same weapon only when we own two of it
+    pub fn find_spare(
+        &self,
+        definition_id: &str,
+        visuals: &PlayerVisuals,
+        replacing: &Parts,
+    ) -> Option<&Item> {
+        self.entries
+            .values()
+            .filter(|entry| entry.item.definition_id == definition_id)
+            .find(|entry| {
+                let mut worn = visuals.equipped_count(&entry.item.id);
+                if visuals
+                    .get_visual(replacing)
+                    .is_some_and(|item| item.id == entry.item.id)
+                {
+                    worn -= 1;
+                }
+                entry.count > worn
+            })
+            .map(|entry| &entry.item)
+    }
+    /// Grab an owned instance of that catalog item
+    pub fn find_definition(&self, definition_id: &str) -> Option<&Item> {
+        self.entries
+            .values()
+            .find(|entry| entry.item.definition_id == definition_id)
+            .map(|entry| &entry.item)
     }
-    /// In takes a item and removes it from invetory
-    pub fn remove_item(&mut self, item: &Item) -> &Self {
-        self.items.remove(&item.id);
-        self
+    /// Tell me how many of that catalog item we have across all stacks
+    pub fn count_of(&self, definition_id: &str) -> u32 {
+        self.entries
+            .values()
+            .filter(|entry| entry.item.definition_id == definition_id)
+            .map(|entry| entry.count)
+            .sum()
+    }
+}
+
+/// Every reason our inventory may refuse a change
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub enum InventoryError {
+    /// Overflow - Doesnt fit, not enough free entries
+    Full { capacity: u32 },
+    /// Item can only be owned once and player already has it
+    UniqueOwned(String),
+    /// Player doesnt have that item in his inventory
+    NotOwned(Uuid),
+    /// Player has that item but not that many
+    NotEnough { item_id: Uuid, owned: u32 },
+    /// Player is currently wearing that item, unequip it first
+    Equipped(Uuid),
+}
+
+impl fmt::Display for InventoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InventoryError::Full { capacity } => {
+                write!(f, "Inventory is full, it holds {} entries", capacity)
+            }
+            InventoryError::UniqueOwned(definition_id) => {
+                write!(f, "{} is unique and you already own it", definition_id)
+            }
+            InventoryError::NotOwned(item_id) => write!(f, "You dont own item {}", item_id),
+            InventoryError::NotEnough { item_id, owned } => {
+                write!(f, "You only have {} of item {}", owned, item_id)
+            }
+            InventoryError::Equipped(item_id) => {
+                write!(f, "Item {} is equipped, unequip it first", item_id)
+            }
+        }
     }
 }
 
@@ -48,36 +221,45 @@ impl Inventory {
 /// Things like, guns, visuals, should be items
 #[derive(Reflect, Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct Item {
-    /// A unique identifier for our items necessary to make exclusive mechanics, if one day I want to make this display pretty
+    /// Instance id, only server hands those. Two katanas bought are two different instances.
+    /// Items the client builds himself, like customizer previews, have a nil id. If one day I want to make this display pretty
     /// Use InspectorEguiImpl
     pub id: Uuid,
+    /// Catalog id of that item, what that item actually is. Ownership checks compare this guy
+    pub definition_id: String,
     /// Name of that item - Mostly used for pretty egui
     pub name: Name,
     /// File path - File path to that grab that item via AssetCollections, must outlive it is referebce. Also serialize dislikes lifetimes
     pub file_path: String,
-    /// Item type shall give me additional information like value and so on
+    /// Item type shall give me additional information, prices live in our catalog
     pub item_type: ItemType,
 }
 
 impl Item {
-    /// Creates an item from it is given file path, grabing the last part from slice.
-    pub fn new_from_filepath(file_path: &str) -> Self {
-        let name_str = file_path
-            .split("/")
-            .last()
-            .unwrap_or(&file_path)
-            .to_string();
-
-        let item_type = ItemType::type_from_filepath(file_path);
-
+    /// Creates an item out of his catalog definition without an instance id. Good for previews and requests
+    pub fn from_definition(definition: &ItemDefinition) -> Self {
+        Self {
+            id: Uuid::nil(),
+            definition_id: definition.id.clone(),
+            name: Name::new(definition.display_name.clone()),
+            file_path: definition.gltf_path.clone(),
+            item_type: definition.slot.item_type(),
+        }
+    }
+    /// Creates an owned instance of that catalog definition with a fresh instance id.
+    /// IMPORTANT - Server only, clients never mint instances
+    pub fn new_instance(definition: &ItemDefinition) -> Self {
         Self {
             id: Uuid::new_v4(),
-            name: Name::new(name_str),
-            file_path: file_path.to_string(),
-            item_type: item_type,
+            ..Self::from_definition(definition)
         }
     }
+    /// Tell me if both items are the same catalog item, regardless of instance
+    pub fn same_definition(&self, other: &Item) -> bool {
+        self.definition_id == other.definition_id
+    }
 }
+
 /// Display trait for item, shows us his name made for pretty :)
 impl fmt::Display for Item {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -93,49 +275,86 @@ pub enum ItemType {
     Skeleton,
 }
 
-impl ItemType {
-    /// That item type value
-    pub fn value(&self) -> f32 {
-        match self {
-            ItemType::Visual => 1.0,
-            ItemType::Skeleton => 100.0,
-            ItemType::Weapon => 10.0,
-        }
-    }
-    /// Return the item type according to the given father folder
-    pub fn type_from_filepath(file_path: &str) -> Self {
-        if file_path.contains("visual_parts") {
-            ItemType::Visual
-        } else if file_path.contains("weapon") {
-            ItemType::Weapon
-        } else if file_path.contains("anim_skeletons") {
-            ItemType::Skeleton
-        } else {
-            // SPOOKY SPOOKY SKELETONS running down your spine
-            ItemType::Skeleton
-        }
-    }
-}
+/// How many minor units make one coin, eguis show currency as coins with two decimals
+pub const MINOR_UNITS_PER_COIN: u64 = 100;
 
 /// Component responsible to tell me how much money a specific client id has
-/// It has mathematical function to ease our usage
-#[derive(Component, Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Reflect)]
+/// Stored as integer minor units so we never create or lose money to float rounding. Arithmetic is checked, a balance never goes below zero
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Reflect)]
 pub struct Currency {
-    pub amount: f32,
+    /// Minor units, 100 = 1 coin
+    pub amount: u64,
 }
 
 impl Default for Currency {
     fn default() -> Self {
-        Self { amount: 1.0 }
+        Self {
+            amount: MINOR_UNITS_PER_COIN,
+        }
     }
 }
 
 impl Currency {
-    pub fn add(&mut self, value: f32) {
-        self.amount += value;
+    /// Creates a currency out of minor units
+    pub fn new(amount: u64) -> Self {
+        Self { amount: amount }
+    }
+    /// Adds value, refusing if we would overflow. Returns the new balance
+    pub fn checked_add(&mut self, value: u64) -> Result<u64, CurrencyError> {
+        self.amount = self
+            .amount
+            .checked_add(value)
+            .ok_or(CurrencyError::Overflow)?;
+        Ok(self.amount)
+    }
+    /// Subtracts value, refusing overdrafts. Returns the new balance
+    pub fn checked_sub(&mut self, value: u64) -> Result<u64, CurrencyError> {
+        self.amount = self
+            .amount
+            .checked_sub(value)
+            .ok_or(CurrencyError::Overdraft {
+                requested: value,
+                available: self.amount,
+            })?;
+        Ok(self.amount)
+    }
+}
+
+/// Shows coins with two decimals, example 1050 minor units shows as 10.50
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}.{:02}",
+            self.amount / MINOR_UNITS_PER_COIN,
+            self.amount % MINOR_UNITS_PER_COIN
+        )
     }
-    pub fn sub(&mut self, value: f32) {
-        self.amount -= value;
+}
+
+/// Every reason a balance change may be refused
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum CurrencyError {
+    /// Not enough money, both in minor units
+    Overdraft { requested: u64, available: u64 },
+    /// Balance would go past what we can store
+    Overflow,
+}
+
+impl fmt::Display for CurrencyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CurrencyError::Overdraft {
+                requested,
+                available,
+            } => write!(
+                f,
+                "Costs {} but you only have {}",
+                Currency::new(*requested),
+                Currency::new(*available)
+            ),
+            CurrencyError::Overflow => write!(f, "Balance would overflow"),
+        }
     }
 }
 
@@ -166,25 +385,34 @@ pub struct PlayerVisuals {
     pub leg: Item,
     // Character available arm
     pub arm: Item,
-    /// Character available weapon
-    pub weapon_1: Item,
+    /// Weapon in the right hand, parented to his hand bone
+    pub main_hand: Option<Item>,
+    /// Weapon in the left hand, empty for new players
+    pub off_hand: Option<Item>,
 }
 
-impl Default for PlayerVisuals {
-    fn default() -> Self {
+impl PlayerVisuals {
+    /// Every new player starts wearing those, each one is a fresh instance
+    /// IMPORTANT - Server only, if the catalog lacks one of our starter items we cant create players so we panic
+    pub fn starter(catalog: &CoreItemCatalog) -> Self {
+        let starter = |definition_id: &str| {
+            let definition = catalog
+                .get(definition_id)
+                .unwrap_or_else(|| panic!("To have starter item {} in catalog", definition_id));
+            Item::new_instance(definition)
+        };
         Self {
-            head: Item::new_from_filepath("characters/visual_parts/def_m_head.glb"),
-            torso: Item::new_from_filepath("characters/visual_parts/def_m_torso.glb"),
-            leg: Item::new_from_filepath("characters/visual_parts/def_m_legs.glb"),
-            arm: Item::new_from_filepath("characters/visual_parts/def_m_arms.glb"),
-            weapon_1: Item::new_from_filepath("weapons/katana.glb"),
-            skeleton: Item::new_from_filepath("characters/anim_skeletons/def_m_main_skeleton.glb"),
+            skeleton: starter(STARTER_SKELETON),
+            head: starter(STARTER_HEAD),
+            torso: starter(STARTER_TORSO),
+            leg: starter(STARTER_LEG),
+            arm: starter(STARTER_ARM),
+            main_hand: Some(starter(STARTER_WEAPON)),
+            off_hand: None,
         }
     }
-}
-
-impl PlayerVisuals {
     /// Returns an iterator over the visual components. Good iterator for when spawning first the entity
+    /// Worth noting weapons arent here, they go in hands not in the player root. See iter_weapons
     pub fn iter_visuals(&self) -> impl Iterator<Item = &Item> {
         vec![
             &self.head,
@@ -195,24 +423,44 @@ impl PlayerVisuals {
         ]
         .into_iter()
     }
-    /// Returns a reference to the visual component corresponding to the given`Parts` enum
+    /// Returns an iterator over the filled weapon slots and what is in them
+    pub fn iter_weapons(&self) -> impl Iterator<Item = (Parts, &Item)> {
+        vec![
+            (Parts::MainHand, self.main_hand.as_ref()),
+            (Parts::OffHand, self.off_hand.as_ref()),
+        ]
+        .into_iter()
+        .filter_map(|(part, item)| item.map(|item| (part, item)))
+    }
+    /// Tell me in how many slots that item instance is, a stack of two katanas may be in both hands
+    pub fn equipped_count(&self, item_id: &Uuid) -> u32 {
+        self.iter_visuals()
+            .chain(self.iter_weapons().map(|(_, item)| item))
+            .filter(|item| item.id == *item_id)
+            .count() as u32
+    }
+    /// Returns a reference to the item in the given `Parts`, weapon slots may be empty
     /// Avoids the usage of uncessary match statements
-    pub fn get_visual(&self, part: &Parts) -> &Item {
+    pub fn get_visual(&self, part: &Parts) -> Option<&Item> {
         match part {
-            Parts::Head => &self.head,
-            Parts::Torso => &self.torso,
-            Parts::Leg => &self.leg,
-            Parts::Arm => &self.arm,
+            Parts::Head => Some(&self.head),
+            Parts::Torso => Some(&self.torso),
+            Parts::Leg => Some(&self.leg),
+            Parts::Arm => Some(&self.arm),
+            Parts::MainHand => self.main_hand.as_ref(),
+            Parts::OffHand => self.off_hand.as_ref(),
         }
     }
-    /// Returns a mutable reference to the visual component corresponding to the given`Parts` enum
+    /// Puts that item in the given `Parts`, replacing whatever was there
     /// Avoids the usage of uncessary match statements
-    pub fn get_visual_mut(&mut self, part: &Parts) -> &mut Item {
+    pub fn set_visual(&mut self, part: &Parts, item: Item) {
         match part {
-            Parts::Head => &mut self.head,
-            Parts::Torso => &mut self.torso,
-            Parts::Leg => &mut self.leg,
-            Parts::Arm => &mut self.arm,
+            Parts::Head => self.head = item,
+            Parts::Torso => self.torso = item,
+            Parts::Leg => self.leg = item,
+            Parts::Arm => self.arm = item,
+            Parts::MainHand => self.main_hand = Some(item),
+            Parts::OffHand => self.off_hand = Some(item),
         }
     }
 }
@@ -221,92 +469,420 @@ impl PlayerVisuals {
 /// I think it has something to do with action state which is the actual interesting component.
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Reflect, Clone, Copy, Hash)]
 pub enum PlayerActions {
-    /// Forward direction
-    Forward,
-    /// Backward direction
-    Backward,
-    /// Left direction
-    Left,
-    /// Goes in the cam.right direction
-    Right,
+    /// Where the player wants to walk, y is cam.forward and x is cam.right. Analog, a half pushed stick walks at half speed
+    Move,
+    /// Right stick, client camera orbits with it. Only input, movement never reads it
+    Look,
+    /// Where the camera is looking, x is the camera yaw and y his pitch in radians. Never bound to anything, client camera writes it every frame
+    /// so server and rollback see the exact same yaw for that tick
+    CameraLook,
+    /// Quick cheap swing with the equipped weapon
+    LightAttack,
+    /// Slow swing that hits harder and costs more stamina
+    HeavyAttack,
+    /// Hold to block hits coming from the front, each blocked hit costs stamina
+    Block,
 }
 
 impl Actionlike for PlayerActions {
     fn input_control_kind(&self) -> InputControlKind {
         match self {
-            Self::Forward => InputControlKind::Button,
-            Self::Backward => InputControlKind::Button,
-            Self::Left => InputControlKind::Button,
-            Self::Right => InputControlKind::Button,
+            Self::Move => InputControlKind::DualAxis,
+            Self::Look => InputControlKind::DualAxis,
+            Self::CameraLook => InputControlKind::DualAxis,
+            Self::LightAttack => InputControlKind::Button,
+            Self::HeavyAttack => InputControlKind::Button,
+            Self::Block => InputControlKind::Button,
         }
     }
 }
 
+/// Small sticks drift, anything under this is treated as not touched
+pub const STICK_DEADZONE: f32 = 0.1;
+
 impl PlayerActions {
-    /// Return the default input map for that player actions. A usefull way of aligning both client and server with the same default input map
+    /// Return the default input map for that player actions, server uses it. Client builds his own from his keybinds, which start equal to this one
     pub fn default_input_map() -> InputMap<Self> {
-        let input_map = InputMap::default()
-            .with(Self::Forward, KeyCode::KeyW)
-            .with(Self::Forward, KeyCode::ArrowUp)
-            .with(Self::Backward, KeyCode::KeyS)
-            .with(Self::Backward, KeyCode::ArrowDown)
-            .with(Self::Left, KeyCode::KeyA)
-            .with(Self::Left, KeyCode::ArrowLeft)
-            .with(Self::Right, KeyCode::ArrowRight)
-            .with(Self::Right, KeyCode::KeyD);
-        return input_map;
+        InputMap::default()
+            .with_dual_axis(Self::Move, VirtualDPad::wasd())
+            .with_dual_axis(Self::Move, VirtualDPad::arrow_keys())
+            .with_dual_axis(
+                Self::Move,
+                GamepadStick::LEFT.with_circle_deadzone(STICK_DEADZONE),
+            )
+            .with_dual_axis(
+                Self::Look,
+                GamepadStick::RIGHT.with_circle_deadzone(STICK_DEADZONE),
+            )
+            .with(Self::LightAttack, KeyCode::KeyJ)
+            .with(Self::LightAttack, GamepadButton::West)
+            .with(Self::HeavyAttack, KeyCode::KeyK)
+            .with(Self::HeavyAttack, GamepadButton::North)
+            .with(Self::Block, KeyCode::KeyL)
+            .with(Self::Block, GamepadButton::LeftTrigger2)
     }
 }
 
 /// Our save resource map, it is gonna store all types of core information really important for our mechanics.
 /// Initially this is monolithic, meaning we only have one save info map that stores basically all of user info later we can make subdivisions and subfiles
+/// IMPORTANT - Keyed by the account player uid, not by client id. Client ids change every session
 #[derive(Resource, Serialize, Deserialize, Clone, Debug, PartialEq, Reflect, Default)]
 #[reflect(Resource)]
 pub struct CoreSaveInfoMap {
-    pub map: HashMap<ClientId, CoreInformation>,
+    pub map: HashMap<Uuid, CoreInformation>,
 }
 
 /// A centralization struct - That shall store everything that NEEDs to be saved about that specific client
-/// It also stores a client_id pointer
+/// It also stores a client_id pointer, which is updated every time that player logs in
 #[derive(Bundle, Serialize, Deserialize, Reflect, Clone, Debug, PartialEq)]
 pub struct CoreInformation {
     pub player_id: PlayerId,
     pub player_visuals: PlayerVisuals,
     pub inventory: Inventory,
     pub currency: Currency,
+    pub loadouts: Loadouts,
 }
 
 impl CoreInformation {
-    /// Pass a client id - Get the default core for new players
-    pub fn new(client_id: ClientId) -> Self {
+    /// Pass a client id - Get the default core for new players. Server only as it mints item instances
+    pub fn new(client_id: ClientId, catalog: &CoreItemCatalog) -> Self {
         // All default player visuals
-        let player_visual_items = PlayerVisuals::default();
-        // Clone here to avoid utilizing same memory space as visuals
-        let default_items: Vec<Item> = player_visual_items.iter_visuals().cloned().collect();
+        let player_visual_items = PlayerVisuals::starter(catalog);
+        // Clone here so inventory holds the exact same instances player is wearing
+        let default_items: Vec<Item> = player_visual_items
+            .iter_visuals()
+            .chain(player_visual_items.iter_weapons().map(|(_, item)| item))
+            .cloned()
+            .collect();
         // Fill empty inventory with default items
         let mut empty_inventory = Inventory::empty();
-        empty_inventory.insert_mult_items(default_items);
+        for item in default_items {
+            let definition = catalog
+                .get(&item.definition_id)
+                .expect("To have starter items in catalog");
+            empty_inventory
+                .add(item, 1, definition)
+                .expect("To fit starter items in an empty inventory");
+        }
 
         Self {
             player_id: PlayerId { id: client_id },
-            player_visuals: PlayerVisuals::default(),
+            player_visuals: player_visual_items,
             currency: Currency::default(),
             inventory: empty_inventory,
+            loadouts: Loadouts::default(),
         }
     }
 }
 
+/// How many loadouts a player may save
+pub const MAX_LOADOUTS: usize = 8;
+/// Longest loadout name we accept, in characters
+pub const MAX_LOADOUT_NAME_LEN: usize = 24;
+
+/// Component responsible for storing the named outfits of a player. Not replicated, owner reads them via his core information
+#[derive(Component, Serialize, Deserialize, Reflect, Clone, Debug, PartialEq, Default)]
+pub struct Loadouts {
+    pub saved: Vec<Loadout>,
+}
+
+impl Loadouts {
+    /// Grab the loadout with that name
+    pub fn get(&self, name: &str) -> Option<&Loadout> {
+        self.saved.iter().find(|loadout| loadout.name == name)
+    }
+}
+
+/// A complete outfit, every body part and both hands. Instances are the ones worn when it was saved,
+/// server falls back to any owned instance of the same catalog item when applying it
+#[derive(Serialize, Deserialize, Reflect, Clone, Debug, PartialEq)]
+pub struct Loadout {
+    pub name: String,
+    pub visuals: PlayerVisuals,
+}
+
 /// A bidirectional message utilized, to save things on server.
 /// If one of the optional fields are passed we should validate the information.
+/// Worth noting currency and inventory are never sent here, they only change via store requests
 #[derive(Event, Serialize, Deserialize, Clone, PartialEq)]
 pub struct SaveMessage {
     pub id: ClientId,
     /// Should be filled if there was an action in client that changed that character visual
     pub change_char: Option<ChangeCharEvent>,
-    /// Should be filled if there was an action that changed it is currency
-    pub change_currency: Option<Currency>,
-    /// Should occur whenever our inventory changes via buy or sell actions
-    pub change_inventory: Option<Inventory>,
+}
+
+/// Server to client message - Server refused a visual change, client should remove his preview and go back to the confirmed part
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct VisualChangeRejected {
+    /// Which part was refused
+    pub body_part: Parts,
+    /// Item the client was previewing
+    pub rejected: Item,
+    /// Item server has for that part, what we should go back to. Weapon slots may be empty
+    pub confirmed: Option<Item>,
+    /// Why it was refused
+    pub reason: String,
+}
+
+/// Client to server message - Asks to buy one of our store items. Only carries the id, price is up to server
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct BuyItemRequest {
+    /// Catalog item id
+    pub item_id: String,
+}
+
+/// Client to server message - Asks to sell one of his owned items
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct SellItemRequest {
+    /// Id of the item in his inventory
+    pub item_id: Uuid,
+}
+
+/// Server to client message - Answer to a buy or sell request, currency is the new amount after the transaction
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub enum TransactionResult {
+    Bought { item: Item, currency: Currency },
+    Sold { item: Item, currency: Currency },
+    Rejected(TransactionError),
+}
+
+/// Every reason server may refuse a transaction
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub enum TransactionError {
+    /// Catalog doesnt have that
+    UnknownItem(String),
+    /// Item exists but store doesnt sell it
+    NotForSale(String),
+    /// Not enough money or balance would overflow
+    Currency(CurrencyError),
+    /// Inventory refused it, full, not owned, equipped and so on
+    Inventory(InventoryError),
+}
+
+impl fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransactionError::UnknownItem(item_id) => write!(f, "Catalog doesnt have {}", item_id),
+            TransactionError::NotForSale(item_id) => write!(f, "Store doesnt sell {}", item_id),
+            TransactionError::Currency(err) => write!(f, "{}", err),
+            TransactionError::Inventory(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+/// Client to server message - Everything a player may do in a trade. Server owns the session, clients only ask
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub enum TradeRequest {
+    /// Invite another connected player to trade
+    Invite { partner: ClientId },
+    /// Answer an invite we received
+    Respond { inviter: ClientId, accept: bool },
+    /// Replace our whole offer, item instance ids with counts plus currency in minor units. Unlocks both sides
+    Offer {
+        items: Vec<(Uuid, u32)>,
+        currency: u64,
+    },
+    /// First phase - Freeze our offer
+    Lock,
+    /// Second phase - Only possible once both offers are locked, when both confirm server swaps
+    Confirm,
+    /// Leave the trade, nothing changes hands
+    Cancel,
+}
+
+/// Server to client message - How a trade is going
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub enum TradeUpdate {
+    /// Someone wants to trade with us
+    Invited { inviter: ClientId },
+    /// Current state of our session, sent whenever something changes in it
+    Session(TradeView),
+    /// Swap happened, our new inventory and currency come via core information
+    Completed,
+    /// Session is over and nothing changed hands
+    Cancelled { reason: String },
+    /// Server refused our last request, session if any goes on
+    Refused(TradeError),
+}
+
+/// A trade session as seen by one of his sides
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct TradeView {
+    /// Who we are trading with
+    pub partner: ClientId,
+    pub my_offer: TradeOffer,
+    pub my_phase: TradePhase,
+    pub their_offer: TradeOffer,
+    pub their_phase: TradePhase,
+}
+
+/// What one side puts on the table, items are the exact instances server validated
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, Default)]
+pub struct TradeOffer {
+    pub items: Vec<InventoryEntry>,
+    pub currency: Currency,
+}
+
+/// Two phase confirm, editing -> locked -> confirmed. Any offer change sends both sides back to editing
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum TradePhase {
+    #[default]
+    Editing,
+    Locked,
+    Confirmed,
+}
+
+/// Every reason server may refuse a trade request
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub enum TradeError {
+    /// That player isnt connected or is ourselves
+    PartnerUnavailable(ClientId),
+    /// One of the sides already has a trade going on
+    AlreadyTrading(ClientId),
+    /// Answered an invite that doesnt exist
+    NoInvite(ClientId),
+    /// That player already has an invite waiting for his answer
+    InvitePending(ClientId),
+    /// Asked something of a session we arent in
+    NoSession,
+    /// Tried to confirm before both sides locked
+    NotLocked,
+    /// Offered the same item twice or zero of it
+    InvalidOffer,
+    /// Catalog doesnt have that
+    UnknownItem(String),
+    /// One of the inventories refused it
+    Inventory(InventoryError),
+    /// One of the balances refused it
+    Currency(CurrencyError),
+}
+
+impl fmt::Display for TradeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TradeError::PartnerUnavailable(client_id) => {
+                write!(f, "Player {} cant trade right now", client_id)
+            }
+            TradeError::AlreadyTrading(client_id) => {
+                write!(f, "Player {} is already trading", client_id)
+            }
+            TradeError::NoInvite(client_id) => write!(f, "No invite from player {}", client_id),
+            TradeError::InvitePending(client_id) => {
+                write!(f, "Player {} already has an invite to answer", client_id)
+            }
+            TradeError::NoSession => write!(f, "You arent trading with anyone"),
+            TradeError::NotLocked => write!(f, "Both sides must lock before confirming"),
+            TradeError::InvalidOffer => write!(f, "Offer repeats an item or has zero of it"),
+            TradeError::UnknownItem(item_id) => write!(f, "Catalog doesnt have {}", item_id),
+            TradeError::Inventory(err) => write!(f, "{}", err),
+            TradeError::Currency(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+/// Client to server message - Everything a player may do with his loadouts
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub enum LoadoutRequest {
+    /// Saves what server says we are wearing under that name, replacing any loadout with the same name
+    Save {
+        name: String,
+    },
+    /// Wears that whole loadout at once
+    Apply {
+        name: String,
+    },
+    Delete {
+        name: String,
+    },
+}
+
+/// Server to client message - Answer to a loadout request, sent only to who asked
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub enum LoadoutResult {
+    Saved(String),
+    Applied(String),
+    Deleted(String),
+    Rejected(LoadoutError),
+}
+
+/// Every reason server may refuse a loadout request
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub enum LoadoutError {
+    /// Name is empty or too long
+    InvalidName,
+    /// Already at the loadout limit
+    Full { max: usize },
+    /// No loadout with that name
+    Unknown(String),
+    /// Doesnt own enough of that catalog item to fill every slot using it
+    NotOwned(String),
+    /// That catalog item cant go in that slot
+    WrongSlot(String),
+}
+
+impl fmt::Display for LoadoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadoutError::InvalidName => write!(
+                f,
+                "Loadout names must have between 1 and {} characters",
+                MAX_LOADOUT_NAME_LEN
+            ),
+            LoadoutError::Full { max } => write!(f, "You can only save {} loadouts", max),
+            LoadoutError::Unknown(name) => write!(f, "You have no loadout named {}", name),
+            LoadoutError::NotOwned(item_id) => write!(f, "You dont own enough {}", item_id),
+            LoadoutError::WrongSlot(item_id) => write!(f, "{} doesnt go in that slot", item_id),
+        }
+    }
+}
+
+/// Server to all clients message - A player wore a whole loadout, carries both outfits so clients only respawn the scenes that changed
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct LoadoutApplied {
+    pub id: ClientId,
+    pub previous: PlayerVisuals,
+    pub current: PlayerVisuals,
+}
+
+/// Server to client message - Carries the core information of the receiving client only, sent whenever his core changes.
+/// Worth noting we never send the whole save map, other players inventories and currencies stay in server
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct CoreInformationMessage {
+    pub core: CoreInformation,
+}
+
+/// Sent by client to our token service via tcp. Accounts are only created when explicitly asked via register,
+/// a login of an unknown username is refused like a wrong password.
+/// Worth noting this is not a lightyear message, as we still dont have a connection when sending it.
+/// IMPORTANT - Password goes in plain text, our token service must only be reachable on localhost or behind tls
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct LoginRequest {
+    pub username: String,
+    pub password: String,
+    /// If true creates that account and logs him in, refused if the username is already taken
+    pub register: bool,
+}
+
+/// Answer from our token service to a login request
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub enum LoginResponse {
+    /// Serialized netcode connect token, client should connect with it
+    Token(Vec<u8>),
+    /// Why we refused that login
+    Refused(String),
+}
+
+/// Biggest login request or answer we read, a connect token is 2 KB and usernames and passwords are way shorter.
+/// Bincode trusts length prefixes, without a limit one forged packet would make us allocate whatever size it claims
+pub const MAX_LOGIN_MESSAGE_BYTES: u64 = 4 * 1024;
+
+/// Callable function - Bincode options every login message goes through, on both ends. Same encoding as plain bincode,
+/// but anything bigger than our limit is refused before we allocate it
+pub fn login_codec() -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(MAX_LOGIN_MESSAGE_BYTES)
 }
 
 /// For prediction, we want every entity that is predicted to be part of the same replication group This will make sure that they will be replicated
@@ -333,6 +909,90 @@ impl Default for CycleTimer {
     }
 }
 
+/// How much damage a player can take before dying. Only server damages it, client predicts it so rollback restores it with the rest
+#[derive(Component, Reflect, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Health {
+    pub current: f32,
+    pub max: f32,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self {
+            current: MAX_HEALTH,
+            max: MAX_HEALTH,
+        }
+    }
+}
+
+/// Spent by attacking and by blocking hits, regenerates while idle
+#[derive(Component, Reflect, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Stamina {
+    pub current: f32,
+    pub max: f32,
+}
+
+impl Default for Stamina {
+    fn default() -> Self {
+        Self {
+            current: MAX_STAMINA,
+            max: MAX_STAMINA,
+        }
+    }
+}
+
+/// Every player starts and respawns with this much health
+pub const MAX_HEALTH: f32 = 100.0;
+
+/// Every player starts and respawns with this much stamina
+pub const MAX_STAMINA: f32 = 100.0;
+
+/// Both kinds of swing our weapons have, their numbers live in shared combat
+#[derive(Reflect, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AttackKind {
+    Light,
+    Heavy,
+}
+
+/// What our player is doing with his weapon, stepped tick by tick from his action state. Predicted so rollback can replay a swing
+#[derive(Component, Reflect, Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct MeleeState {
+    /// Swing in progress, None when idle
+    pub attack: Option<AttackKind>,
+    /// Ticks since that swing started
+    pub tick: u16,
+    /// Holding block with stamina left and not swinging
+    pub blocking: bool,
+}
+
+/// Server to all clients message - A swing landed on a player
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct MeleeHit {
+    pub attacker: ClientId,
+    pub defender: ClientId,
+    pub attack: AttackKind,
+    pub damage: f32,
+    /// Health of the defender after the hit
+    pub health_left: f32,
+}
+
+/// Server to all clients message - A swing landed on a player that was blocking facing it
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct MeleeBlocked {
+    pub attacker: ClientId,
+    pub defender: ClientId,
+    pub attack: AttackKind,
+    /// Stamina of the defender after paying for the block
+    pub stamina_left: f32,
+}
+
+/// Server to all clients message - A player ran out of health, server already respawned him
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct PlayerDied {
+    pub id: ClientId,
+    pub killer: ClientId,
+}
+
 /// Centralization plugin - Defines how our component will be synced (from server to client or client to server or bidirectional)
 /// Defines what essential components need to be replicated among the two.
 pub struct ProtocolPlugin;
@@ -355,10 +1015,8 @@ impl Plugin for ProtocolPlugin {
             .add_prediction(ComponentSyncMode::Once);
         app.register_component::<PlayerVisuals>(ChannelDirection::ServerToClient)
             .add_prediction(ComponentSyncMode::Simple);
-        app.register_component::<Inventory>(ChannelDirection::ServerToClient)
-            .add_prediction(ComponentSyncMode::Simple);
-        app.register_component::<Currency>(ChannelDirection::ServerToClient)
-            .add_prediction(ComponentSyncMode::Simple);
+        // Inventory and currency are not registered on purpose, our player replicates to everyone.
+        // Each client only gets his own via core information message
         app.register_component::<Name>(ChannelDirection::ServerToClient)
             .add_prediction(ComponentSyncMode::Once)
             .add_interpolation(ComponentSyncMode::Simple);
@@ -369,17 +1027,38 @@ impl Plugin for ProtocolPlugin {
             .add_interpolation_fn(TransformLinearInterpolation::lerp)
             .add_correction_fn(TransformLinearInterpolation::lerp);
 
-        // Replicated resources -  The workflow for replicated resources is as follows
-        //-> First - Register in shared, as he is supposed to exist both in client and server
-        //-> Second - Initialize him in server and client
-        //-> Third - Do commands.replicate to start replicating him when necessary
-        app.register_resource::<CoreSaveInfoMap>(ChannelDirection::ServerToClient);
+        // Combat components, predicted in full like transform. Client steps swings and stamina itself and server corrects him
+        app.register_component::<Health>(ChannelDirection::ServerToClient)
+            .add_prediction(ComponentSyncMode::Full);
+        app.register_component::<Stamina>(ChannelDirection::ServerToClient)
+            .add_prediction(ComponentSyncMode::Full);
+        app.register_component::<MeleeState>(ChannelDirection::ServerToClient)
+            .add_prediction(ComponentSyncMode::Full);
 
         // Self-made messages - The workflow for messages is as follows:
         // -> First register message
         // -> Send her via clientconnectionmessager using send_message function with all of it is shenanigans
         // -> Read it via EventReader<MessageEvent<>>
         app.register_message::<SaveMessage>(ChannelDirection::Bidirectional);
+        // Tells a client his cosmetic preview was refused
+        app.register_message::<VisualChangeRejected>(ChannelDirection::ServerToClient);
+        // Each client only receives his own core information, save map is server only
+        app.register_message::<CoreInformationMessage>(ChannelDirection::ServerToClient);
+        // Store transactions, client only asks server decides
+        app.register_message::<BuyItemRequest>(ChannelDirection::ClientToServer);
+        app.register_message::<SellItemRequest>(ChannelDirection::ClientToServer);
+        app.register_message::<TransactionResult>(ChannelDirection::ServerToClient);
+        // Player to player trades, same idea as the store
+        app.register_message::<TradeRequest>(ChannelDirection::ClientToServer);
+        app.register_message::<TradeUpdate>(ChannelDirection::ServerToClient);
+        // Loadouts, server validates every slot before anyone sees the change
+        app.register_message::<LoadoutRequest>(ChannelDirection::ClientToServer);
+        app.register_message::<LoadoutResult>(ChannelDirection::ServerToClient);
+        app.register_message::<LoadoutApplied>(ChannelDirection::ServerToClient);
+        // Combat outcomes, only server resolves hits
+        app.register_message::<MeleeHit>(ChannelDirection::ServerToClient);
+        app.register_message::<MeleeBlocked>(ChannelDirection::ServerToClient);
+        app.register_message::<PlayerDied>(ChannelDirection::ServerToClient);
 
         // Our sun
         app.register_component::<SunMarker>(ChannelDirection::ServerToClient);
@@ -390,6 +1069,9 @@ impl Plugin for ProtocolPlugin {
         app.register_type::<PlayerVisuals>();
         app.register_type::<CoreSaveInfoMap>();
         app.register_type::<CycleTimer>();
+        app.register_type::<Health>();
+        app.register_type::<Stamina>();
+        app.register_type::<MeleeState>();
     }
 }
 
@@ -415,3 +1097,265 @@ impl LerpFn<Transform> for TransformLinearInterpolation {
         res
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Catalog definition with the stacking rules we want to test
+    fn definition(id: &str, max_stack: u32, unique: bool) -> ItemDefinition {
+        ItemDefinition {
+            id: id.to_string(),
+            display_name: id.to_string(),
+            slot: ItemSlot::Weapon,
+            rarity: Rarity::Common,
+            price: 100,
+            sell_value: 50,
+            purchasable: true,
+            max_stack: max_stack,
+            unique: unique,
+            gltf_path: format!("weapons/{}.glb", id),
+            melee: None,
+        }
+    }
+
+    /// Player wearing placeholder body parts and whatever we put in his hands
+    fn visuals(main_hand: Option<Item>, off_hand: Option<Item>) -> PlayerVisuals {
+        let part = Item::from_definition(&definition("part", 1, false));
+        PlayerVisuals {
+            skeleton: part.clone(),
+            head: part.clone(),
+            torso: part.clone(),
+            leg: part.clone(),
+            arm: part,
+            main_hand: main_hand,
+            off_hand: off_hand,
+        }
+    }
+
+    #[test]
+    fn add_follows_stacks_unique_items_and_capacity() {
+        // Name, max stack, unique, capacity, already owned, adding, expected error, owned after, entries after
+        let cases: [(
+            &str,
+            u32,
+            bool,
+            u32,
+            u32,
+            u32,
+            Option<InventoryError>,
+            u32,
+            usize,
+        ); 9] = [
+            ("first stack", 5, false, 3, 0, 3, None, 3, 1),
+            ("fills old stack first", 5, false, 3, 3, 2, None, 5, 1),
+            ("spills into new stack", 5, false, 3, 4, 3, None, 7, 2),
+            ("opens many stacks", 5, false, 3, 0, 15, None, 15, 3),
+            (
+                "one past capacity",
+                5,
+                false,
+                3,
+                0,
+                16,
+                Some(InventoryError::Full { capacity: 3 }),
+                0,
+                0,
+            ),
+            (
+                "full stacks dont fit more",
+                1,
+                false,
+                2,
+                2,
+                1,
+                Some(InventoryError::Full { capacity: 2 }),
+                2,
+                2,
+            ),
+            ("unique first one", 1, true, 3, 0, 1, None, 1, 1),
+            (
+                "unique second one",
+                1,
+                true,
+                3,
+                1,
+                1,
+                Some(InventoryError::UniqueOwned("sword".to_string())),
+                1,
+                1,
+            ),
+            (
+                "unique two at once",
+                1,
+                true,
+                3,
+                0,
+                2,
+                Some(InventoryError::UniqueOwned("sword".to_string())),
+                0,
+                0,
+            ),
+        ];
+
+        for (name, max_stack, unique, capacity, owned, adding, error, owned_after, entries_after) in
+            cases
+        {
+            let sword = definition("sword", max_stack, unique);
+            let mut inventory = Inventory {
+                entries: HashMap::new(),
+                capacity: capacity,
+            };
+            if owned > 0 {
+                inventory
+                    .add(Item::new_instance(&sword), owned, &sword)
+                    .unwrap_or_else(|err| panic!("{}: setup failed {}", name, err));
+            }
+            let before = inventory.clone();
+
+            let result = inventory.add(Item::new_instance(&sword), adding, &sword);
+            assert_eq!(result.clone().err(), error, "{}", name);
+            assert_eq!(inventory.count_of("sword"), owned_after, "{}", name);
+            assert_eq!(inventory.entries.len(), entries_after, "{}", name);
+            if error.is_some() {
+                assert_eq!(inventory, before, "{}: refused add changed inventory", name);
+            } else {
+                let received = result.unwrap();
+                assert!(inventory.entries.contains_key(&received), "{}", name);
+            }
+            assert!(
+                inventory
+                    .entries
+                    .values()
+                    .all(|entry| entry.count > 0 && entry.count <= max_stack),
+                "{}: stack out of bounds",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn remove_unequipped_never_takes_worn_instances() {
+        // Name, stack count, hands holding that stack, removing, expected error, owned after
+        let cases: [(&str, u32, u32, u32, bool, u32); 6] = [
+            ("nothing worn", 2, 0, 2, false, 0),
+            ("spare one", 2, 1, 1, false, 1),
+            ("worn one", 1, 1, 1, true, 1),
+            ("both hands", 2, 2, 1, true, 2),
+            ("too many", 3, 1, 3, true, 3),
+            ("partial stack", 3, 1, 2, false, 1),
+        ];
+
+        for (name, stack, worn, removing, refused, owned_after) in cases {
+            let sword = definition("sword", 5, false);
+            let mut inventory = Inventory::empty();
+            let item_id = inventory
+                .add(Item::new_instance(&sword), stack, &sword)
+                .unwrap();
+            let item = inventory.entries[&item_id].item.clone();
+            let visuals = visuals(
+                (worn >= 1).then(|| item.clone()),
+                (worn >= 2).then(|| item.clone()),
+            );
+
+            let result = inventory.remove_unequipped(&item_id, removing, &visuals);
+            assert_eq!(result.is_err(), refused, "{}", name);
+            if refused {
+                assert_eq!(
+                    result.err(),
+                    Some(InventoryError::Equipped(item_id)),
+                    "{}",
+                    name
+                );
+            }
+            assert_eq!(inventory.count_of("sword"), owned_after, "{}", name);
+        }
+
+        let mut inventory = Inventory::empty();
+        let unknown = Uuid::new_v4();
+        assert_eq!(
+            inventory.remove_unequipped(&unknown, 1, &visuals(None, None)),
+            Err(InventoryError::NotOwned(unknown))
+        );
+    }
+
+    #[test]
+    fn find_spare_and_count_of_across_stacks() {
+        // Name, owned, main hand holds it, off hand holds it, replacing, expect spare
+        let cases: [(&str, u32, bool, bool, Parts, bool); 6] = [
+            ("not worn", 1, false, false, Parts::OffHand, true),
+            ("worn in other hand", 1, true, false, Parts::OffHand, false),
+            ("own two, one worn", 2, true, false, Parts::OffHand, true),
+            ("replacing itself", 1, true, false, Parts::MainHand, true),
+            ("both hands worn", 2, true, true, Parts::OffHand, true),
+            ("both hands, none left", 2, true, true, Parts::Head, false),
+        ];
+
+        for (name, owned, main_hand, off_hand, replacing, spare) in cases {
+            let sword = definition("sword", 5, false);
+            let mut inventory = Inventory::empty();
+            let item_id = inventory
+                .add(Item::new_instance(&sword), owned, &sword)
+                .unwrap();
+            let item = inventory.entries[&item_id].item.clone();
+            let visuals = visuals(
+                main_hand.then(|| item.clone()),
+                off_hand.then(|| item.clone()),
+            );
+
+            let found = inventory.find_spare("sword", &visuals, &replacing);
+            assert_eq!(found.is_some(), spare, "{}", name);
+            assert!(inventory.find_spare("axe", &visuals, &replacing).is_none());
+        }
+
+        // Count sums every stack of that definition and nothing else
+        let sword = definition("sword", 2, false);
+        let axe = definition("axe", 10, false);
+        let mut inventory = Inventory::empty();
+        inventory
+            .add(Item::new_instance(&sword), 5, &sword)
+            .unwrap();
+        inventory.add(Item::new_instance(&axe), 4, &axe).unwrap();
+        assert_eq!(inventory.entries.len(), 4);
+        assert_eq!(inventory.count_of("sword"), 5);
+        assert_eq!(inventory.count_of("axe"), 4);
+        assert_eq!(inventory.count_of("bow"), 0);
+    }
+
+    #[test]
+    fn currency_refuses_overflow_and_overdraft() {
+        // Name, balance, adding, expected result
+        let additions: [(&str, u64, u64, Result<u64, CurrencyError>); 3] = [
+            ("plain", 100, 50, Ok(150)),
+            ("up to max", u64::MAX - 1, 1, Ok(u64::MAX)),
+            ("past max", u64::MAX, 1, Err(CurrencyError::Overflow)),
+        ];
+        for (name, balance, adding, expected) in additions {
+            let mut currency = Currency::new(balance);
+            assert_eq!(currency.checked_add(adding), expected, "{}", name);
+            let after = expected.unwrap_or(balance);
+            assert_eq!(currency.amount, after, "{}", name);
+        }
+
+        // Name, balance, subtracting, expected result
+        let subtractions: [(&str, u64, u64, Result<u64, CurrencyError>); 3] = [
+            ("plain", 100, 40, Ok(60)),
+            ("down to zero", 100, 100, Ok(0)),
+            (
+                "overdraft",
+                100,
+                101,
+                Err(CurrencyError::Overdraft {
+                    requested: 101,
+                    available: 100,
+                }),
+            ),
+        ];
+        for (name, balance, subtracting, expected) in subtractions {
+            let mut currency = Currency::new(balance);
+            assert_eq!(currency.checked_sub(subtracting), expected, "{}", name);
+            let after = expected.unwrap_or(balance);
+            assert_eq!(currency.amount, after, "{}", name);
+        }
+    }
+}
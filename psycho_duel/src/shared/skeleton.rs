@@ -0,0 +1,161 @@
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use gltf::animation::util::ReadOutputs;
+use gltf::animation::{Interpolation, Property};
+use gltf::buffer::Source;
+use gltf::Gltf;
+
+/// Bone that holds the weapon of each hand in our main skeleton
+pub const MAIN_HAND_BONE: &str = "Hand.R";
+pub const OFF_HAND_BONE: &str = "Hand.L";
+
+/// Hand bones are mirrored, so off hand weapons are turned half a turn around his bone. Otherwise his blade would point behind his fist
+pub const OFF_HAND_GRIP: Quat = Quat::from_xyzw(0.0, 1.0, 0.0, 0.0);
+
+/// Our skeleton gltf read without an asset server nor renderer, so server knows where bones go during a clip.
+/// Only bone transforms matter here, meshes and skins are never touched
+pub struct SkeletonClips {
+    gltf: Gltf,
+    /// Node index to his parent node index
+    parents: HashMap<usize, usize>,
+}
+
+impl SkeletonClips {
+    /// Parses a binary gltf, the same bytes clients load their skeleton scene from
+    pub fn parse(bytes: &[u8]) -> Result<Self, String> {
+        let gltf = Gltf::from_slice(bytes).map_err(|err| err.to_string())?;
+        let mut parents = HashMap::new();
+        for node in gltf.document.nodes() {
+            for child in node.children() {
+                parents.insert(child.index(), node.index());
+            }
+        }
+        Ok(SkeletonClips {
+            gltf: gltf,
+            parents: parents,
+        })
+    }
+
+    /// Seconds from our first key to our last key of that clip
+    pub fn duration(&self, clip: &str) -> Result<f32, String> {
+        let animation = self.animation(clip)?;
+        let mut duration: f32 = 0.0;
+        for channel in animation.channels() {
+            let reader = channel.reader(|buffer| self.buffer_data(buffer));
+            if let Some(inputs) = reader.read_inputs() {
+                duration = inputs.fold(duration, f32::max);
+            }
+        }
+        Ok(duration)
+    }
+
+    /// Callable function - Where that bone is at that time of the clip, relative to the skeleton scene. Which is where our player stands,
+    /// as his skeleton scene is spawned as a child of him without any offset. Bones the clip doesnt key keep their rest transform
+    pub fn bone_at(&self, clip: &str, bone: &str, time: f32) -> Result<Transform, String> {
+        let animation = self.animation(clip)?;
+        let mut node = self
+            .gltf
+            .document
+            .nodes()
+            .find(|node| node.name() == Some(bone))
+            .ok_or(format!("Skeleton has no bone named {}", bone))?
+            .index();
+
+        let mut transform = self.local_at(&animation, node, time)?;
+        while let Some(parent) = self.parents.get(&node) {
+            node = *parent;
+            transform = self
+                .local_at(&animation, node, time)?
+                .mul_transform(transform);
+        }
+        Ok(transform)
+    }
+
+    /// Grabs a clip by his name
+    fn animation(&self, clip: &str) -> Result<gltf::Animation<'_>, String> {
+        self.gltf
+            .document
+            .animations()
+            .find(|animation| animation.name() == Some(clip))
+            .ok_or(format!("Skeleton has no clip named {}", clip))
+    }
+
+    /// Only the binary chunk of our glb, skeletons dont point to outside buffers
+    fn buffer_data(&self, buffer: gltf::Buffer<'_>) -> Option<&[u8]> {
+        match buffer.source() {
+            Source::Bin => self.gltf.blob.as_deref(),
+            Source::Uri(_) => None,
+        }
+    }
+
+    /// Transform of that node relative to his parent at that time of the clip
+    fn local_at(
+        &self,
+        animation: &gltf::Animation<'_>,
+        node: usize,
+        time: f32,
+    ) -> Result<Transform, String> {
+        let rest = self
+            .gltf
+            .document
+            .nodes()
+            .nth(node)
+            .ok_or(format!("Skeleton has no node {}", node))?;
+        let (translation, rotation, scale) = rest.transform().decomposed();
+        let mut transform = Transform {
+            translation: Vec3::from(translation),
+            rotation: Quat::from_array(rotation),
+            scale: Vec3::from(scale),
+        };
+
+        for channel in animation.channels() {
+            if channel.target().node().index() != node {
+                continue;
+            }
+            let interpolation = channel.sampler().interpolation();
+            if interpolation == Interpolation::CubicSpline {
+                return Err(format!(
+                    "Clip {} uses cubic spline keys, export it as linear",
+                    animation.name().unwrap_or_default()
+                ));
+            }
+            let reader = channel.reader(|buffer| self.buffer_data(buffer));
+            let times: Vec<f32> = reader
+                .read_inputs()
+                .map(|inputs| inputs.collect())
+                .unwrap_or_default();
+            if times.is_empty() {
+                return Err(format!(
+                    "Clip {} has a channel without keys",
+                    animation.name().unwrap_or_default()
+                ));
+            }
+            // Key we are on and how far we are into the next one
+            let next = times.partition_point(|key_time| *key_time <= time);
+            let current = next.saturating_sub(1);
+            let next = next.min(times.len() - 1);
+            let blend = if interpolation == Interpolation::Step || next == current {
+                0.0
+            } else {
+                ((time - times[current]) / (times[next] - times[current])).clamp(0.0, 1.0)
+            };
+
+            match (channel.target().property(), reader.read_outputs()) {
+                (Property::Translation, Some(ReadOutputs::Translations(keys))) => {
+                    let keys: Vec<Vec3> = keys.map(Vec3::from).collect();
+                    transform.translation = keys[current].lerp(keys[next], blend);
+                }
+                (Property::Rotation, Some(ReadOutputs::Rotations(keys))) => {
+                    let keys: Vec<Quat> = keys.into_f32().map(Quat::from_array).collect();
+                    transform.rotation = keys[current].slerp(keys[next], blend);
+                }
+                (Property::Scale, Some(ReadOutputs::Scales(keys))) => {
+                    let keys: Vec<Vec3> = keys.map(Vec3::from).collect();
+                    transform.scale = keys[current].lerp(keys[next], blend);
+                }
+                _ => {}
+            }
+        }
+        Ok(transform)
+    }
+}